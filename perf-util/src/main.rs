@@ -15,93 +15,552 @@ use anyhow;
 use docopt;
 
 use winapi_perf_wrapper::constants;
+use winapi_perf_wrapper::counter_path::parse_counter_path_pdh_as_written;
+use winapi_perf_wrapper::machine;
 use winapi_perf_wrapper::*;
 
+use std::io::{BufWriter, ErrorKind, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 const USAGE: &'static str = "
 Performance Counter Utility
 
-Usage: perf-util [options]
+Usage: perf-util [options] [--stream=<p>]...
 
 Options:
     -h --help       Show this help text
-    --machine<m>    The MachineName to use
+    --machine=<m>   The MachineName to use
     --expand=<p>    Expand a counter path to its variants
-    --stream=<p>    Stream the values for a performance counter
+    --stream=<p>    Stream the values for a performance counter. May be repeated.
     --list          List available counters
+    --info          With --list also print each counter's explain text.
+    --complete=<p>  List the objects, instances or counters that complete a partial path.
+    --selftest      Validate the performance counter integration on this machine
+                    and the counter paths the library knows about.
+    --graphite      Output streamed values in the graphite plaintext format.
+    --graphitePrefix=<p>  Prefix to namespace the graphite metric names with.
+    --csv           Output streamed values as CSV with a column per counter. Every row
+                    is read from a single collection so the values share a timestamp.
+    --compare-machines=<hosts>  Stream the values from two machines side by side. Takes
+                    the comma separated hosts, e.g. HOST1,HOST2.
+    --monitor=<p>   Watch a performance counter and alert when it crosses a threshold.
+    --above=<n>     Alert when the monitored value is above n.
+    --below=<n>     Alert when the monitored value is below n.
+    --for=<secs>    How long the threshold must be crossed before alerting. [default: 0]
+    --exec=<cmd>    Command to run when alerting. The path and value are passed in
+                    the PERF_UTIL_PATH and PERF_UTIL_VALUE environment variables.
+    --exitOnAlert   Exit with a non-zero status after the first alert.
 ";
 
-pub fn print_counters(pdh: &mut PDH) -> anyhow::Result<()> {
-    let mut counter_paths = pdh
+pub fn print_counters(pdh: &mut PDH, info: bool) -> anyhow::Result<()> {
+    let counter_paths = pdh
         .enumerate_counters()
         .map_err(|e| constants::pdh_status_friendly_name(e))
         .unwrap();
-    counter_paths.sort();
-    for obj in counter_paths {
-        println!("{}", obj);
+    if !info {
+        for obj in counter_paths {
+            println!("{}", obj);
+        }
+        return Ok(());
+    }
+    let paths: Vec<&str> = counter_paths.iter().map(|p| p.as_str()).collect();
+    for (path, explain) in pdh.counter_help_bulk(&paths) {
+        match explain {
+            Ok(text) => println!("{}\t{}", path, text.replace(&['\r', '\n'][..], " ")),
+            Err(s) => println!("{}\t<{}>", path, constants::pdh_status_friendly_name(s)),
+        }
     }
     Ok(())
 }
 
 pub fn print_object_counters(pdh: &mut PDH, obj: &str) -> anyhow::Result<()> {
     println!("Counters for {}:", obj);
-    let (counters, instances) = pdh
-        .enumerate_items_string(obj)
+    let items = pdh
+        .enumerate_object_items(obj)
         .map_err(|s| constants::pdh_status_friendly_name(s))
         .unwrap();
-    for i in &instances {
-        let i = if i.is_empty() {
-            String::new()
-        } else {
-            format!("({})", i)
-        };
-        for c in &counters {
-            // TODO
-            println!("\t\\{}{}\\{}", obj, i, c);
-        }
+    for path in items.paths() {
+        println!("\t{}", path);
     }
     Ok(())
 }
 
 pub fn print_performance_objects(pdh: &mut PDH) -> anyhow::Result<()> {
     println!("Performance Counter objects:");
-    let mut sorted_counters = pdh
-        .enumerate_objects_string()
+    let objects = pdh
+        .enumerate_active_objects()
         .map_err(|s| constants::pdh_status_friendly_name(s))
         .unwrap();
-    sorted_counters.sort();
-    for obj in sorted_counters {
+    for obj in objects {
         println!("\t{}", obj);
     }
     Ok(())
 }
 
 pub fn print_counter_value(pdh: &mut PDH, path: &str) {
-    let query = pdh
-        .open_query()
-        .map_err(|e| constants::pdh_status_friendly_name(e))
-        .unwrap();
-    let counter_handle = query
-        .add_counter_string(path)
+    let value = pdh
+        .sample_large(path, None)
         .map_err(|e| constants::pdh_status_friendly_name(e))
         .unwrap();
-    let value = query
-        .collect_large_data(&counter_handle)
+    println!("{}: {}", path, value);
+}
+
+/// Checks that each of the library's counter paths can be added to a query.
+/// Paths for hardware the machine doesn't have are skipped. Returns false if
+/// any path failed.
+pub fn self_test_paths(pdh: &PDH) -> bool {
+    let query = match pdh.open_query() {
+        Ok(query) => query,
+        Err(s) => {
+            println!(
+                "FAIL\tvalidate library paths: {}",
+                constants::pdh_status_friendly_name(s)
+            );
+            return false;
+        }
+    };
+    let mut ok = true;
+    for path in machine::COUNTER_PATHS {
+        match query.try_add_counter(path) {
+            Ok(Some(_)) => println!("PASS\tvalidate {}", path),
+            Ok(None) => println!("SKIP\tvalidate {}: not present on this machine", path),
+            Err(s) => {
+                ok = false;
+                println!(
+                    "FAIL\tvalidate {}: {}",
+                    path,
+                    constants::pdh_status_friendly_name(s)
+                );
+            }
+        }
+    }
+    ok
+}
+
+/// The output format for streamed counter values.
+pub enum StreamFormat<'a> {
+    /// Tab separated path and value.
+    Plain,
+    /// Graphite plaintext protocol with an optional metric prefix.
+    Graphite(&'a str),
+}
+
+fn graphite_segment(segment: &str) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for c in segment.chars() {
+        if c.is_alphanumeric() || c == '-' {
+            out.push(c);
+        } else if !out.ends_with('_') {
+            out.push('_');
+        }
+    }
+    out.trim_matches('_').to_owned()
+}
+
+/// Transforms a counter path into dotted graphite notation. Machine, object,
+/// instance and counter each become a segment of the metric name. The path
+/// is parsed by PDH so call this once per path rather than per sample.
+pub fn graphite_metric_name(prefix: &str, path: &str) -> String {
+    let mut segments = Vec::new();
+    if !prefix.is_empty() {
+        segments.push(prefix.trim_matches('.').to_owned());
+    }
+    match parse_counter_path_pdh_as_written(path) {
+        Ok(elements) => {
+            if let Some(ref machine) = elements.machine_name {
+                segments.push(graphite_segment(machine));
+            }
+            segments.push(graphite_segment(&elements.object_name));
+            if let Some(ref parent) = elements.parent_instance {
+                segments.push(graphite_segment(parent));
+            }
+            if let Some(ref instance) = elements.instance_name {
+                segments.push(graphite_segment(instance));
+            }
+            if let Some(idx) = elements.instance_index {
+                segments.push(format!("{}", idx));
+            }
+            segments.push(graphite_segment(&elements.counter_name));
+        }
+        Err(_) => {
+            for segment in path.split('\\') {
+                segments.push(graphite_segment(segment));
+            }
+        }
+    }
+    segments.retain(|s| !s.is_empty());
+    segments.join(".")
+}
+
+/// Writes a cycle's worth of lines and flushes them. Exits quietly if
+/// whatever we are piped into has gone away like `head` does.
+fn write_cycle<W: Write>(out: &mut W, lines: &Vec<String>) {
+    let result = lines
+        .iter()
+        .try_for_each(|line| writeln!(out, "{}", line))
+        .and_then(|_| out.flush());
+    match result {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => std::process::exit(0),
+        Err(e) => eprintln!("Err: {}", e),
+    }
+}
+
+pub fn stream_counters(pdh: &mut PDH, paths: &Vec<&str>, format: StreamFormat) {
+    // We use one long lived query for all of the paths so that every counter
+    // is collected at the same instant each cycle.
+    let query = pdh
+        .open_query()
         .map_err(|e| constants::pdh_status_friendly_name(e))
         .unwrap();
-    println!("{}: {}", path, value);
+    let mut counters = Vec::with_capacity(paths.len());
+    for path in paths {
+        let counter_handle = query
+            .add_counter_string(*path)
+            .map_err(|s| constants::pdh_status_friendly_name(s))
+            .unwrap();
+        let suffix = match query.is_percentage(&counter_handle) {
+            Ok(true) => "%",
+            _ => "",
+        };
+        let name = match format {
+            StreamFormat::Graphite(ref prefix) => graphite_metric_name(prefix, path),
+            StreamFormat::Plain => String::new(),
+        };
+        counters.push((path, name, counter_handle, suffix));
+    }
+    // The first collection will always be garbage.
+    let counter_refs: Vec<&PdhCounter> = counters.iter().map(|(_, _, c, _)| c).collect();
+    if let Err(s) = query.prime(&counter_refs, 1, Duration::from_secs(1)) {
+        eprintln!("Err: {}", constants::pdh_status_friendly_name(s));
+    }
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut lines = Vec::with_capacity(counters.len());
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(1000));
+        if let Err(s) = query.collect() {
+            eprintln!("Err: {}", constants::pdh_status_friendly_name(s));
+            continue;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        lines.clear();
+        for (path, name, counter_handle, suffix) in counters.iter() {
+            match (query.format_long_data(counter_handle), &format) {
+                (Ok(v), StreamFormat::Plain) => lines.push(format!("{}\t{}{}", path, v, suffix)),
+                (Ok(v), StreamFormat::Graphite(_)) => {
+                    lines.push(format!("{} {} {}", name, v, timestamp))
+                }
+                (Err(s), _) => eprintln!("Err: {}", constants::pdh_status_friendly_name(s)),
+            }
+        }
+        write_cycle(&mut out, &lines);
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') {
+        return format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    field.to_owned()
+}
+
+/// Streams the counters as CSV rows of a timestamp followed by a column per
+/// counter. All of the counters are read from one collection of a single
+/// query each cycle so a row's values are sampled at the same instant.
+/// Counters that couldn't be read that cycle are left empty.
+pub fn stream_counters_csv(pdh: &mut PDH, paths: &Vec<&str>) -> anyhow::Result<()> {
+    let query = pdh
+        .open_query()
+        .map_err(|e| anyhow::Error::msg(constants::pdh_status_friendly_name(e)))?;
+    let mut counters = Vec::with_capacity(paths.len());
+    for path in paths {
+        counters.push(query.add_counter_string(*path).map_err(|s| {
+            anyhow::anyhow!("{}: {}", path, constants::pdh_status_friendly_name(s))
+        })?);
+    }
+    let counter_refs: Vec<&PdhCounter> = counters.iter().collect();
+    let mut values = Vec::with_capacity(counter_refs.len());
+    // The first collection will always be garbage.
+    if let Err(s) = query.prime(&counter_refs, 1, Duration::from_secs(1)) {
+        eprintln!("Err: {}", constants::pdh_status_friendly_name(s));
+    }
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut header = vec!["timestamp".to_owned()];
+    header.extend(paths.iter().map(|p| csv_field(p)));
+    let mut lines = vec![header.join(",")];
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(1000));
+        if let Err(s) = query.collect_all_double_into(&counter_refs, &mut values) {
+            eprintln!("Err: {}", constants::pdh_status_friendly_name(s));
+            continue;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut row = vec![timestamp.to_string()];
+        for value in values.iter() {
+            row.push(match value {
+                Ok(v) => v.to_string(),
+                Err(_) => String::new(),
+            });
+        }
+        lines.push(row.join(","));
+        write_cycle(&mut out, &lines);
+        lines.clear();
+    }
+}
+
+/// Rows whose values differ by at least this percentage are marked when
+/// comparing machines.
+const COMPARE_HIGHLIGHT_PCT: f64 = 10.0;
+
+/// Opens a query for the paths on a remote host and primes it.
+fn open_compare_query(
+    host: &str,
+    paths: &Vec<String>,
+) -> Result<(PdhQuery, Vec<PdhCounter>), PdhError> {
+    let pdh = PDH::new().try_with_machine_name_str(host)?;
+    pdh.connect()?;
+    let query = pdh.open_query()?;
+    let mut counters = Vec::with_capacity(paths.len());
+    for path in paths {
+        counters.push(query.add_counter_string(path.as_str())?);
+    }
+    // The first collection will always be garbage.
+    query.collect()?;
+    Ok((query, counters))
+}
+
+/// One machine being compared. A machine that can't be reached is tried
+/// again each cycle.
+struct ComparedMachine<'a> {
+    host: &'a str,
+    paths: Vec<String>,
+    query: Result<(PdhQuery, Vec<PdhCounter>), PdhError>,
+}
+
+impl<'a> ComparedMachine<'a> {
+    fn new(host: &'a str, paths: &Vec<&str>) -> Self {
+        let machine = format!("\\\\{}", host.trim_start_matches('\\'));
+        let paths: Vec<String> = paths.iter().map(|p| format!("{}{}", machine, p)).collect();
+        let query = open_compare_query(host, &paths);
+        Self { host, paths, query }
+    }
+
+    /// Collects the machine and reads each path. Every path reports the
+    /// machine's error if it couldn't be collected.
+    fn read(&mut self) -> Vec<Result<f64, PdhError>> {
+        if self.query.is_err() {
+            self.query = open_compare_query(self.host, &self.paths);
+            // A freshly primed query has nothing to read until the next cycle.
+            if self.query.is_ok() {
+                return vec![Err(constants::PdhError::NoData); self.paths.len()];
+            }
+        }
+        let collected = match self.query {
+            Ok((ref query, ref counters)) => query.collect().map(|_| {
+                counters
+                    .iter()
+                    .map(|c| query.format_double_data(c))
+                    .collect()
+            }),
+            Err(s) => Err(s),
+        };
+        match collected {
+            Ok(values) => values,
+            Err(s) => {
+                self.query = Err(s);
+                vec![Err(s); self.paths.len()]
+            }
+        }
+    }
+}
+
+fn compare_cell(value: &Result<f64, PdhError>) -> String {
+    match value {
+        Ok(v) => format!("{:.2}", v),
+        Err(s) => format!("<{}>", constants::pdh_status_friendly_name(*s)),
+    }
+}
+
+/// Streams the paths from two machines side by side with the difference
+/// between them. Rows that differ by at least `COMPARE_HIGHLIGHT_PCT`
+/// percent are marked with a `*`. A machine that can't be reached shows its
+/// error in its column while the other keeps streaming.
+pub fn compare_machines(hosts: &str, paths: &Vec<&str>) -> anyhow::Result<()> {
+    let hosts: Vec<&str> = hosts.split(',').map(|h| h.trim()).collect();
+    if hosts.len() != 2 || hosts.iter().any(|h| h.is_empty()) {
+        return Err(anyhow::anyhow!(
+            "--compare-machines expects two hosts like HOST1,HOST2"
+        ));
+    }
+    if paths.is_empty() {
+        return Err(anyhow::anyhow!("--compare-machines requires --stream"));
+    }
+    let mut first = ComparedMachine::new(hosts[0], paths);
+    let mut second = ComparedMachine::new(hosts[1], paths);
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut lines = vec![format!(" \tpath\t{}\t{}\tdiff", hosts[0], hosts[1])];
+    loop {
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let (a_values, b_values) = (first.read(), second.read());
+        for ((path, a), b) in paths.iter().zip(a_values.iter()).zip(b_values.iter()) {
+            let (marker, diff) = match (a, b) {
+                (Ok(a), Ok(b)) => {
+                    let pct = if *a != 0.0 {
+                        (b - a) / a.abs() * 100.0
+                    } else if *b != 0.0 {
+                        100.0
+                    } else {
+                        0.0
+                    };
+                    let marker = if pct.abs() >= COMPARE_HIGHLIGHT_PCT {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    (marker, format!("{:+.2} ({:+.1}%)", b - a, pct))
+                }
+                _ => (" ", String::new()),
+            };
+            lines.push(format!(
+                "{}\t{}\t{}\t{}\t{}",
+                marker,
+                path,
+                compare_cell(a),
+                compare_cell(b),
+                diff
+            ));
+        }
+        write_cycle(&mut out, &lines);
+        lines.clear();
+    }
+}
+
+/// A threshold for a monitored counter.
+pub enum Threshold {
+    Above(f64),
+    Below(f64),
+}
+
+impl Threshold {
+    fn is_breached(&self, value: f64) -> bool {
+        match self {
+            Threshold::Above(n) => value > *n,
+            Threshold::Below(n) => value < *n,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Threshold::Above(n) => format!("above {}", n),
+            Threshold::Below(n) => format!("below {}", n),
+        }
+    }
+}
+
+fn run_alert_command(cmd: &str, path: &str, value: f64) {
+    let result = std::process::Command::new("cmd")
+        .args(&["/C", cmd])
+        .env("PERF_UTIL_PATH", path)
+        .env("PERF_UTIL_VALUE", format!("{}", value))
+        .status();
+    match result {
+        Ok(status) if !status.success() => eprintln!("Alert command exited with {}", status),
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to run alert command: {}", e),
+    }
+}
+
+/// Streams a counter and prints an alert line when it stays past the threshold
+/// for at least the given duration. Only alerts once per breach and alerts
+/// again only after the value has recovered. Returns once an alert fires if
+/// exit_on_alert is set.
+pub fn monitor_counter(
+    pdh: &mut PDH,
+    path: &str,
+    threshold: Threshold,
+    duration: Duration,
+    exec: Option<&str>,
+    exit_on_alert: bool,
+) -> anyhow::Result<()> {
+    let query = pdh
+        .open_query()
+        .map_err(|e| anyhow::Error::msg(constants::pdh_status_friendly_name(e)))?;
+    let stream: CounterStream<f64> = query
+        .get_value_stream_from_path(path)
+        .map_err(|e| anyhow::Error::msg(constants::pdh_status_friendly_name(e)))?
+        .with_delay(Duration::from_secs(1));
+    let watcher =
+        ThresholdWatcher::new(stream, |v| threshold.is_breached(v)).with_debounce(duration);
+    loop {
+        let value = match watcher.next() {
+            Ok(ThresholdEvent::Crossed(v)) => v,
+            Ok(ThresholdEvent::Cleared(_)) => continue,
+            Err(s) => {
+                eprintln!("Err: {}", constants::pdh_status_friendly_name(s));
+                continue;
+            }
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        println!(
+            "ALERT\t{}\t{}\t{} is {}",
+            timestamp,
+            path,
+            value,
+            threshold.describe()
+        );
+        if let Some(cmd) = exec {
+            run_alert_command(cmd, path, value);
+        }
+        if exit_on_alert {
+            return Err(anyhow::anyhow!("{} is {}", path, threshold.describe()));
+        }
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     let parser = docopt::Docopt::new(USAGE)?;
     let argv = parser.parse()?;
-    let mut pdh = if let Some(machine) = argv.find("--machine") {
-        PDH::new().with_machine_name(machine.as_str().encode_utf16().collect())
-    } else {
-        PDH::new()
-    };
+    let machine = argv.get_str("--machine");
+    let mut pdh = PDH::new().try_with_machine_name_str(machine)?;
 
-    if argv.get_bool("--list") {
-        print_counters(&mut pdh)?;
+    if argv.get_bool("--selftest") {
+        let mut failed = false;
+        for (step, result) in PDH::self_test() {
+            match result {
+                Ok(()) => println!("PASS\t{}", step),
+                Err(s) => {
+                    failed = true;
+                    println!("FAIL\t{}: {}", step, constants::pdh_status_friendly_name(s));
+                }
+            }
+        }
+        if !self_test_paths(&pdh) {
+            failed = true;
+        }
+        if failed {
+            std::process::exit(1);
+        }
+    } else if argv.get_bool("--list") {
+        print_counters(&mut pdh, argv.get_bool("--info"))?;
+    } else if argv.get_str("--complete") != "" {
+        let paths = pdh
+            .complete_path(argv.get_str("--complete"))
+            .map_err(|e| anyhow::Error::msg(constants::pdh_status_friendly_name(e)))?;
+        for p in paths {
+            println!("{}", p);
+        }
     } else if argv.get_str("--expand") != "" {
         let path = argv.get_str("--expand");
         let paths = pdh
@@ -111,25 +570,45 @@ fn main() -> anyhow::Result<()> {
         for p in paths {
             println!("{}", p);
         }
-    } else if argv.get_str("--stream") != "" {
-        let path = argv.get_str("--stream");
-        let query = pdh
-            .open_query()
-            .map_err(|e| constants::pdh_status_friendly_name(e))
-            .unwrap();
-        let iterator: CounterStream<i32> = query
-            .get_value_stream_from_path(path)
-            .map_err(|s| constants::pdh_status_friendly_name(s))
-            .unwrap()
-            .with_delay(std::time::Duration::from_millis(1000));
-        // Throw away the first value. It will always be garbage.
-        let _ = iterator.next();
-        loop {
-            match iterator.next() {
-                Ok(v) => println!("{}\t{}", path, v),
-                Err(s) => eprintln!("Err: {}", constants::pdh_status_friendly_name(s)),
+    } else if argv.get_str("--monitor") != "" {
+        let threshold = match (argv.get_str("--above"), argv.get_str("--below")) {
+            (above, "") if above != "" => Threshold::Above(above.parse()?),
+            ("", below) if below != "" => Threshold::Below(below.parse()?),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "--monitor requires one of --above or --below"
+                ))
             }
+        };
+        let duration = Duration::from_secs(argv.get_str("--for").parse()?);
+        let exec = match argv.get_str("--exec") {
+            "" => None,
+            cmd => Some(cmd),
+        };
+        monitor_counter(
+            &mut pdh,
+            argv.get_str("--monitor"),
+            threshold,
+            duration,
+            exec,
+            argv.get_bool("--exitOnAlert"),
+        )?;
+    } else if argv.get_str("--compare-machines") != "" {
+        compare_machines(
+            argv.get_str("--compare-machines"),
+            &argv.get_vec("--stream"),
+        )?;
+    } else if !argv.get_vec("--stream").is_empty() {
+        let paths = argv.get_vec("--stream");
+        if argv.get_bool("--csv") {
+            return stream_counters_csv(&mut pdh, &paths);
         }
+        let format = if argv.get_bool("--graphite") {
+            StreamFormat::Graphite(argv.get_str("--graphitePrefix"))
+        } else {
+            StreamFormat::Plain
+        };
+        stream_counters(&mut pdh, &paths, format);
     }
     Ok(())
 }
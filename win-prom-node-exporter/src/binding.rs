@@ -14,111 +14,1047 @@
 
 // Tool that owns a query and borrows a registry and sets up the bindings between
 // performance counters and prometheus guages.
-use lazy_static;
-use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use prometheus::{GaugeVec, Registry};
-use winapi_perf_wrapper::constants::pdh_status_friendly_name;
-use winapi_perf_wrapper::{CounterStream, PDHStatus, PdhQuery, PDH};
+use log::{error, info, warn};
+use prometheus::{GaugeVec, IntGaugeVec, Registry};
+use winapi_perf_wrapper::capabilities::{
+    disabled_provider_for_object, object_registered, COUNTER_CACHE_REMEDIATION,
+    PROVIDER_DISABLED_REMEDIATION,
+};
+use winapi_perf_wrapper::constants::*;
+use winapi_perf_wrapper::counter_path::{number_instances, parse_counter_path_pdh};
+use winapi_perf_wrapper::counter_type::*;
+use winapi_perf_wrapper::{ArrayItem, CounterType, PdhCounter, PdhError, PdhQuery, PDH};
 
-lazy_static::lazy_static! {
-    static ref INSTANCE_REGEX: Regex = Regex::new(r".*\((.*)\)").unwrap();
+use crate::config::MetricGroup;
+
+/// Labels the items of a counter array with their instance names. PDH does
+/// not disambiguate instances that share a name in an array so we number
+/// repeats `name#1`, `name#2` and so on the same way expanded counter paths
+/// do.
+fn instance_labels(items: &Vec<ArrayItem>) -> Vec<String> {
+    number_instances(items.iter().map(|i| i.instance.as_str()))
 }
 
-fn parse_instance(path: &str) -> String {
-    match INSTANCE_REGEX.captures(path) {
-        Some(caps) => caps.get(1).unwrap().as_str().to_owned(),
-        None => String::new(),
+/// Labels the items of a counter array and splits them into the values of
+/// the instances with valid data and the statuses of those without.
+fn partition_items(items: &Vec<ArrayItem>) -> (Vec<(String, f64)>, Vec<(String, u32)>) {
+    let mut valid = Vec::with_capacity(items.len());
+    let mut invalid = Vec::new();
+    for (item, instance) in items.iter().zip(instance_labels(items)) {
+        if item.is_valid() {
+            valid.push((instance, item.value));
+        } else {
+            invalid.push((instance, item.status));
+        }
     }
+    (valid, invalid)
+}
+
+/// How the instance names of an object are turned into labels.
+struct InstanceLabelRule {
+    object: &'static str,
+    /// The separator between the parts of the instance name.
+    separator: char,
+    /// The labels the parts are reported as.
+    labels: &'static [&'static str],
+    /// Whether the object usually has enough instances to be a cardinality
+    /// problem.
+    high_cardinality: bool,
 }
 
-fn get_value_stream<'query_life, NumType>(
-    query: &'query_life PdhQuery,
-    path: &str,
-) -> Result<CounterStream<'query_life, NumType>, PDHStatus> {
-    query.get_value_stream_from_path::<_, NumType>(path)
+/// Objects whose instance names encode more than one label. Wildcard paths on
+/// any other object get a single `instance` label.
+const INSTANCE_LABEL_RULES: &'static [InstanceLabelRule] = &[InstanceLabelRule {
+    object: "Thread",
+    separator: '/',
+    labels: &["process", "thread"],
+    high_cardinality: true,
+}];
+
+const DEFAULT_INSTANCE_LABELS: &'static [&'static str] = &["instance"];
+
+/// Finds the instance label rule for a counter path if there is one.
+fn instance_label_rule(path: &str) -> Option<&'static InstanceLabelRule> {
+    let object = parse_counter_path_pdh(path).ok()?.object_name;
+    INSTANCE_LABEL_RULES
+        .iter()
+        .find(|rule| rule.object.eq_ignore_ascii_case(&object))
 }
 
-fn build_metric_pair<'query_life>(
-    name: &'static str,
-    path: &str,
+/// Splits an instance label into the values for a rule's labels. Missing
+/// parts are reported as empty strings.
+fn split_instance(instance: &str, sep: char, labels: &[&str]) -> Vec<String> {
+    let mut parts = instance
+        .splitn(labels.len(), sep)
+        .map(|p| p.to_owned())
+        .collect::<Vec<String>>();
+    parts.resize(labels.len(), String::new());
+    parts
+}
+
+fn as_strs(values: &Vec<String>) -> Vec<&str> {
+    values.iter().map(|v| v.as_str()).collect()
+}
+
+/// Classifies a collection failure into a reason label for the
+/// collection error metrics.
+pub fn collection_error_reason(status: PdhError) -> &'static str {
+    match status.raw_code() {
+        PDH_NO_DATA => "no_data",
+        PDH_INVALID_HANDLE => "invalid_handle",
+        PDH_CSTATUS_INVALID_DATA | PDH_INVALID_DATA => "invalid_data",
+        PDH_CALC_NEGATIVE_DENOMINATOR | PDH_CALC_NEGATIVE_TIMEBASE | PDH_CALC_NEGATIVE_VALUE => {
+            "negative_value"
+        }
+        PDH_CSTATUS_NO_OBJECT | PDH_CSTATUS_NO_COUNTER | PDH_CSTATUS_NO_INSTANCE => "missing",
+        PDH_CSTATUS_NO_MACHINE => "no_machine",
+        _ => "other",
+    }
+}
+
+fn register_gauge(
+    name: &str,
+    help: &str,
+    labels: &[&str],
     registry: &prometheus::Registry,
-    query: &'query_life PdhQuery,
-) -> anyhow::Result<(&'static str, GaugeVec, CounterStream<'query_life, f64>)> {
-    let gauge = GaugeVec::new(prometheus::Opts::new(name, path), &[])?;
+) -> anyhow::Result<GaugeVec> {
+    let gauge = GaugeVec::new(prometheus::Opts::new(name, help), labels)?;
     registry.register(Box::new(gauge.clone()))?;
-    Ok((
-        name,
-        gauge,
-        get_value_stream::<f64>(query, path)
-            .map_err(|s| anyhow::Error::msg(pdh_status_friendly_name(s)))?,
-    ))
+    Ok(gauge)
 }
 
-pub struct CounterToPrometheus<'myself, 'registry> {
-    pdh: PDH,
-    query: PdhQuery,
+/// Informal unit suffixes that conventional names replace.
+const INFORMAL_SUFFIXES: &'static [&'static str] = &["_pct", "_per_sec", "_sec", "_gauge"];
+
+/// Rewrites a metric name to follow the prometheus conventions for the unit
+/// of its counter and returns the factor to scale its values by.
+///
+/// * Percentages become `_ratio` and are scaled to 0-1.
+/// * Per second counters become `_per_second`, or `_bytes_per_second` if the
+///   counter is of bytes.
+/// * Counters displayed in seconds become `_seconds`.
+///
+/// Anything else keeps its name and value.
+fn conventional_name(name: &str, path: &str, counter_type: CounterType) -> (String, f64) {
+    let (suffix, scale) = match counter_type.display() {
+        PERF_DISPLAY_PERCENT => ("_ratio", 0.01),
+        PERF_DISPLAY_PER_SEC if path.to_lowercase().contains("bytes") => ("_bytes_per_second", 1.0),
+        PERF_DISPLAY_PER_SEC => ("_per_second", 1.0),
+        PERF_DISPLAY_SECONDS => ("_seconds", 1.0),
+        _ => return (name.to_owned(), 1.0),
+    };
+    let mut base = name;
+    for informal in INFORMAL_SUFFIXES {
+        if base.ends_with(informal) {
+            base = &base[0..base.len() - informal.len()];
+            break;
+        }
+    }
+    // Avoid doubling up e.g. disk_read_bytes_bytes_per_second.
+    if suffix == "_bytes_per_second" && base.ends_with("_bytes") {
+        base = &base[0..base.len() - "_bytes".len()];
+    }
+    if base.ends_with(suffix) {
+        return (base.to_owned(), scale);
+    }
+    (format!("{}{}", base, suffix), scale)
+}
+
+/// The largest integer magnitude an f64 can represent exactly.
+const F64_EXACT_MAX: i64 = 1 << 53;
+
+/// Whether an integer value would be rounded when converted to an f64.
+fn loses_precision(v: i64) -> bool {
+    v > F64_EXACT_MAX || v < -F64_EXACT_MAX
+}
+
+/// Converts an array of integer values to the f64s our gauges hold. Returns
+/// the first valid value that had to be rounded if any.
+fn large_items_to_f64(
+    large_items: &Vec<ArrayItem<i64>>,
+    items: &mut Vec<ArrayItem>,
+) -> Option<i64> {
+    items.clear();
+    let mut rounded = None;
+    for item in large_items {
+        if rounded.is_none() && item.is_valid() && loses_precision(item.value) {
+            rounded = Some(item.value);
+        }
+        items.push(ArrayItem {
+            instance: item.instance.clone(),
+            status: item.status,
+            value: item.value as f64,
+        });
+    }
+    rounded
+}
+
+/// Collects with collect and if the whole query's handle is no longer valid
+/// reconnects and collects once more. A counter whose own handle is invalid
+/// shows up in the formatted values instead and doesn't cause a reconnect.
+fn collect_or_reconnect<T, C, R>(target: &mut T, collect: C, reconnect: R) -> Result<(), PdhError>
+where
+    C: Fn(&mut T) -> Result<(), PdhError>,
+    R: FnOnce(&mut T) -> Result<(), PdhError>,
+{
+    match collect(target) {
+        Err(PdhError::InvalidHandle) => {
+            warn!("The collection query is no longer valid. Adding the counters to a new one.");
+            reconnect(target)?;
+            collect(target)
+        }
+        result => result,
+    }
+}
+
+/// A cap on the total number of series reported across every metric. Shared
+/// by all of the collection workers so a runaway wildcard in one can't push
+/// the total past the cap.
+pub struct SeriesBudget {
+    /// The most series allowed or 0 for no limit.
+    max: usize,
+    used: AtomicUsize,
+    /// Whether we've logged hitting the cap since the last series was freed.
+    tripped: AtomicBool,
+}
+
+impl SeriesBudget {
+    /// Constructs a SeriesBudget allowing max series. 0 means no limit.
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: max,
+            used: AtomicUsize::new(0),
+            tripped: AtomicBool::new(false),
+        }
+    }
+
+    /// Takes a series from the budget for a counter path. Returns false if
+    /// the cap has been reached. The first counter refused after the cap is
+    /// reached is logged.
+    fn try_take(&self, name: &str, path: &str) -> bool {
+        let max = self.max;
+        let taken = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                if max == 0 || used < max {
+                    Some(used + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok();
+        if !taken && !self.tripped.swap(true, Ordering::SeqCst) {
+            error!(
+                "Reached --maxTotalSeries of {} registering {} = {}. Skipping it and any further series.",
+                max, name, path
+            );
+        }
+        taken
+    }
+
+    /// Returns series to the budget.
+    fn release(&self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.used.fetch_sub(count, Ordering::SeqCst);
+        self.tripped.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Registers the gauge for a scalar metric whose series was already taken
+/// from budget. The series is returned if registration fails, e.g. on a
+/// duplicate metric name, so a bad config can't leak it on every reload.
+fn register_budgeted_gauge(
+    budget: Option<&SeriesBudget>,
+    name: &str,
+    help: &str,
+    registry: &prometheus::Registry,
+) -> anyhow::Result<GaugeVec> {
+    let result = register_gauge(name, help, &[], registry);
+    if let (Err(_), Some(budget)) = (&result, budget) {
+        budget.release(1);
+    }
+    result
+}
+
+/// A single counter bound to a gauge.
+struct ScalarBinding {
+    name: String,
+    path: String,
+    gauge: GaugeVec,
+    counter: PdhCounter,
+    /// Whether the counter is an integer type we read as an i64 rather than
+    /// an f64.
+    integer: bool,
+    /// Whether we've warned about this counter losing precision already.
+    precision_warned: bool,
+    /// The factor values are multiplied by before they are reported.
+    scale: f64,
+}
+
+/// A wildcard counter bound to a gauge with a series per instance.
+struct WildcardBinding {
+    name: String,
+    path: String,
+    gauge: GaugeVec,
+    counter: PdhCounter,
+    /// The factor values are multiplied by before they are reported.
+    scale: f64,
+    /// Whether the counter is an integer type we read as i64s rather than
+    /// f64s.
+    integer: bool,
+    /// Whether we've warned about this counter losing precision already.
+    precision_warned: bool,
+    /// How to split instance names into labels if not a single `instance`.
+    rule: Option<&'static InstanceLabelRule>,
+    /// The instances we set a series for on the last collection.
+    instances: BTreeSet<String>,
+}
+
+impl WildcardBinding {
+    /// The label values for an instance of this binding's counter.
+    fn label_values(&self, instance: &str) -> Vec<String> {
+        match self.rule {
+            Some(rule) => split_instance(instance, rule.separator, rule.labels),
+            None => vec![instance.to_owned()],
+        }
+    }
+}
+
+/// Binds performance counters in a single query to prometheus gauges.
+/// PDH handles are not safe to share across threads so each collection
+/// thread should own its own CounterToPrometheus.
+pub struct CounterToPrometheus<'registry> {
     registry: &'registry Registry,
-    _marker: std::marker::PhantomData<&'myself PdhQuery>,
+    gauges: Vec<GaugeVec>,
+    pairs: Vec<ScalarBinding>,
+    wildcard_pairs: Vec<WildcardBinding>,
+    instance_counts: IntGaugeVec,
+    // Reused across collections of the f64 scalar counters.
+    scalar_values: Vec<Result<f64, PdhError>>,
+    // Reused across collections of the wildcard counter arrays.
+    array_buffer: Vec<u64>,
+    array_items: Vec<ArrayItem>,
+    large_items: Vec<ArrayItem<i64>>,
+    /// Whether metric names and values follow the prometheus unit
+    /// conventions instead of the names in the config.
+    conventional_units: bool,
+    /// The cap on the total number of series we share with the other
+    /// bindings if any.
+    series_budget: Option<&'registry SeriesBudget>,
+    /// The remote machine our counters are read from or None for this one.
+    host: Option<String>,
+    /// The query all of our counters are added to.
+    query: PdhQuery,
 }
 
-impl<'myself, 'registry> CounterToPrometheus<'myself, 'registry> {
-    pub fn try_new(registry: &'registry Registry) -> anyhow::Result<Self> {
-        let pdh = PDH::new();
-        let query = pdh
+impl<'registry> CounterToPrometheus<'registry> {
+    /// Constructs a new CounterToPrometheus. The number of instances each
+    /// wildcard counter matches is reported in the instance_counts gauge
+    /// labeled by the wildcard counter path.
+    pub fn try_new(
+        registry: &'registry Registry,
+        instance_counts: IntGaugeVec,
+    ) -> anyhow::Result<Self> {
+        let query = PDH::new()
             .open_query()
             .map_err(|s| anyhow::Error::msg(pdh_status_friendly_name(s)))?;
         Ok(Self {
-            pdh: pdh,
             query: query,
             registry: registry,
-            _marker: std::marker::PhantomData,
+            gauges: Vec::new(),
+            pairs: Vec::new(),
+            wildcard_pairs: Vec::new(),
+            instance_counts: instance_counts,
+            scalar_values: Vec::new(),
+            array_buffer: Vec::new(),
+            array_items: Vec::new(),
+            large_items: Vec::new(),
+            conventional_units: false,
+            series_budget: None,
+            host: None,
         })
     }
 
+    /// Reads every counter from a remote machine instead of this one. Must
+    /// be called before any metrics are registered.
+    pub fn with_remote_host(mut self, host: &str) -> anyhow::Result<Self> {
+        let pdh = PDH::new()
+            .try_with_machine_name_str(host)
+            .map_err(|s| anyhow::anyhow!("{:?}: {}", host, pdh_status_friendly_name(s)))?;
+        let host = host.trim_start_matches('\\');
+        // An empty host is this machine.
+        if !host.is_empty() {
+            self.host = Some(host.to_owned());
+        }
+        self.query = pdh
+            .open_query()
+            .map_err(|s| anyhow::anyhow!("{}: {}", host, pdh_status_friendly_name(s)))?;
+        Ok(self)
+    }
+
+    /// A PDH for the machine our counters are read from.
+    fn pdh(&self) -> PDH {
+        match self.host {
+            Some(ref host) => PDH::new().with_machine_name_str(host.as_str()),
+            None => PDH::new(),
+        }
+    }
+
+    /// The full path of a counter on the machine our counters are read from.
+    fn machine_path(&self, path: &str) -> String {
+        match self.host {
+            Some(ref host) => format!("\\\\{}{}", host, path),
+            None => path.to_owned(),
+        }
+    }
+
+    /// Renames metrics and scales their values to follow the prometheus
+    /// unit conventions based on the type of their counter. Only affects
+    /// metrics registered afterwards.
+    pub fn with_conventional_units(mut self, conventional_units: bool) -> Self {
+        self.conventional_units = conventional_units;
+        self
+    }
+
+    /// Counts every series we report against a budget shared with other
+    /// bindings. Counters and instances that would take the total past the
+    /// budget's cap are skipped.
+    pub fn with_series_budget(mut self, series_budget: &'registry SeriesBudget) -> Self {
+        self.series_budget = Some(series_budget);
+        self
+    }
+
+    /// Takes a series for a counter from our budget if we have one.
+    fn take_series(&self, name: &str, path: &str) -> bool {
+        self.series_budget
+            .map(|budget| budget.try_take(name, path))
+            .unwrap_or(true)
+    }
+
+    /// Adds a counter to our query. If the counter provider for the
+    /// counter's object is disabled on this machine the counter is skipped
+    /// with a warning and None is returned rather than failing. If the object
+    /// is missing for any other reason PDH's object list is refreshed and the
+    /// add retried once in case the provider showed up after we started.
+    fn add_counter_or_skip(
+        &mut self,
+        name: &str,
+        path: &str,
+    ) -> anyhow::Result<Option<PdhCounter>> {
+        match self.query.add_counter_string(self.machine_path(path)) {
+            Ok(counter) => Ok(Some(counter)),
+            Err(PdhError::ProviderDisabled) => {
+                let service = parse_counter_path_pdh(path)
+                    .ok()
+                    .and_then(|elements| disabled_provider_for_object(&elements.object_name).ok())
+                    .flatten()
+                    .unwrap_or_else(|| "its object".to_owned());
+                warn!(
+                    "Skipping {} = {}. The counter provider for {} is disabled. {}",
+                    name, path, service, PROVIDER_DISABLED_REMEDIATION
+                );
+                Ok(None)
+            }
+            Err(PdhError::NoObject) => self.add_counter_after_refresh(path).map(Some),
+            Err(s) => Err(anyhow::anyhow!("{}: {}", path, pdh_status_friendly_name(s))),
+        }
+    }
+
+    /// Refreshes PDH's object list for the machine our counters are read
+    /// from and adds the counter again. If the object is still missing but
+    /// registered on this machine the error explains how to repair the
+    /// counter settings.
+    fn add_counter_after_refresh(&self, path: &str) -> anyhow::Result<PdhCounter> {
+        if let Err(s) = self.pdh().refresh() {
+            warn!(
+                "Failed to refresh performance objects: {}",
+                pdh_status_friendly_name(s)
+            );
+        }
+        match self.query.add_counter_string(self.machine_path(path)) {
+            Ok(counter) => {
+                info!("Found {} after refreshing the performance objects", path);
+                Ok(counter)
+            }
+            Err(PdhError::NoObject) => {
+                // We can only read the counter registry of this machine.
+                let registered = self.host.is_none()
+                    && parse_counter_path_pdh(path)
+                        .ok()
+                        .and_then(|elements| object_registered(&elements.object_name).ok())
+                        .unwrap_or(false);
+                let message = format!("{}: {}", path, PdhError::NoObject);
+                if registered {
+                    return Err(anyhow::anyhow!(
+                        "{}. {}",
+                        message,
+                        COUNTER_CACHE_REMEDIATION
+                    ));
+                }
+                Err(anyhow::Error::msg(message))
+            }
+            Err(s) => Err(anyhow::anyhow!("{}: {}", path, pdh_status_friendly_name(s))),
+        }
+    }
+
+    /// Whether a counter should be read as an i64 rather than an f64.
+    /// Integer counters such as byte counts are read exactly and only
+    /// converted to the gauge's f64 at the end. An f64 holds integers up to
+    /// 2^53 exactly so beyond that the reported values are rounded but we
+    /// can tell when that happens. Rates and percentages are read as f64s.
+    fn is_integer(&self, counter: &PdhCounter) -> bool {
+        counter.counter_type().map_or(false, |t| t.is_integer())
+    }
+
+    /// The name and value scale to register a metric with.
+    fn metric_name(&self, name: &str, path: &str, counter: &PdhCounter) -> (String, f64) {
+        if !self.conventional_units {
+            return (name.to_owned(), 1.0);
+        }
+        match counter.counter_type() {
+            Some(counter_type) => conventional_name(name, path, counter_type),
+            None => (name.to_owned(), 1.0),
+        }
+    }
+
+    /// The explicit scale factor configured for a metric or 1. A metric with
+    /// an explicit scale is read without PDH's default scaling so the
+    /// explicit scale alone determines its unit.
+    fn configured_scale(
+        &self,
+        name: &str,
+        counter: &PdhCounter,
+        scales: &BTreeMap<String, f64>,
+    ) -> f64 {
+        match scales.get(name) {
+            Some(factor) => {
+                self.query
+                    .set_counter_format_flags(counter, PDH_FMT_NOSCALE);
+                *factor
+            }
+            None => 1.0,
+        }
+    }
+
+    /// Registers all of the metrics in a MetricGroup.
+    pub fn register_group(&mut self, group: &MetricGroup) -> anyhow::Result<()> {
+        self.register_pairs(&group.pairs, &group.scales)?;
+        self.register_wildcard_pairs(&group.wildcard_pairs, &group.scales)?;
+        Ok(())
+    }
+
     pub fn register_pairs(
-        &'myself self,
-        name_path_pairs: Vec<(&'static str, &str)>,
-    ) -> anyhow::Result<Vec<(&str, GaugeVec, CounterStream<'myself, f64>)>> {
-        let mut pairs = Vec::new();
+        &mut self,
+        name_path_pairs: &Vec<(String, String)>,
+        scales: &BTreeMap<String, f64>,
+    ) -> anyhow::Result<()> {
         for (name, path) in name_path_pairs {
-            let pair = build_metric_pair(name, path, self.registry, &self.query)?;
-            pairs.push(pair);
+            let counter = match self.add_counter_or_skip(name, path)? {
+                Some(counter) => counter,
+                None => continue,
+            };
+            // Dropping the counter removes it from our query again.
+            if !self.take_series(name, path) {
+                continue;
+            }
+            let configured_scale = self.configured_scale(name, &counter, scales);
+            let (name, scale) = self.metric_name(name, path, &counter);
+            let scale = scale * configured_scale;
+            let gauge = register_budgeted_gauge(self.series_budget, &name, path, self.registry)?;
+            self.gauges.push(gauge.clone());
+            let integer = self.is_integer(&counter);
+            self.pairs.push(ScalarBinding {
+                name: name,
+                path: path.clone(),
+                gauge: gauge,
+                counter: counter,
+                integer: integer,
+                precision_warned: false,
+                scale: scale,
+            });
         }
-        Ok(pairs)
+        Ok(())
     }
 
     pub fn register_wildcard_pairs(
-        &'myself self,
-        name_path_pairs: Vec<(&'static str, &str)>,
-    ) -> anyhow::Result<
-        Vec<(
-            &str,
-            GaugeVec,
-            (&'static str, String),
-            CounterStream<'myself, f64>,
-        )>,
-    > {
-        let mut pairs = Vec::new();
+        &mut self,
+        name_path_pairs: &Vec<(String, String)>,
+        scales: &BTreeMap<String, f64>,
+    ) -> anyhow::Result<()> {
         for (name, path) in name_path_pairs {
-            let expanded_paths = self
-                .pdh
-                .expand_counter_path_string(path)
-                .map_err(|s| anyhow::Error::msg(pdh_status_friendly_name(s)))?;
-            for expanded in expanded_paths {
-                // TODO(jwall): Parse the instance out first.
-                let instance = parse_instance(&expanded);
-                let gauge = GaugeVec::new(prometheus::Opts::new(name, &expanded), &["instance"])?;
-                self.registry.register(Box::new(gauge.clone()))?;
-                pairs.push((
-                    name,
-                    gauge,
-                    ("instance", instance),
-                    get_value_stream::<f64>(&self.query, &expanded)
-                        .map_err(|s| anyhow::Error::msg(pdh_status_friendly_name(s)))?,
-                ));
+            // One gauge per metric name with a series per instance. The
+            // instances are read from the counter array each collection so
+            // instances that come and go are picked up as they do.
+            let rule = instance_label_rule(path);
+            if rule.map(|r| r.high_cardinality).unwrap_or(false) {
+                warn!(
+                    "{} = {} reports a series per instance and can have thousands of them.",
+                    name, path
+                );
+            }
+            let labels = rule.map(|r| r.labels).unwrap_or(DEFAULT_INSTANCE_LABELS);
+            let counter = match self.add_counter_or_skip(name, path)? {
+                Some(counter) => counter,
+                None => continue,
+            };
+            let configured_scale = self.configured_scale(name, &counter, scales);
+            let (name, scale) = self.metric_name(name, path, &counter);
+            let scale = scale * configured_scale;
+            let gauge = register_gauge(&name, path, labels, self.registry)?;
+            self.gauges.push(gauge.clone());
+            let integer = self.is_integer(&counter);
+            self.wildcard_pairs.push(WildcardBinding {
+                name: name,
+                path: path.clone(),
+                gauge: gauge,
+                counter: counter,
+                scale: scale,
+                integer: integer,
+                precision_warned: false,
+                rule: rule,
+                instances: BTreeSet::new(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The number of PDH counters in our query. Wildcard metrics use a single
+    /// counter no matter how many instances they match.
+    pub fn counter_count(&self) -> usize {
+        self.pairs.len() + self.wildcard_pairs.len()
+    }
+
+    /// Removes every counter from our query and unregisters all of our gauges
+    /// leaving the query open so a new set of metrics can be registered.
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        let mut series = self.pairs.len();
+        self.pairs.clear();
+        for binding in self.wildcard_pairs.drain(0..) {
+            series += binding.instances.len();
+            // The series may not exist yet if we never collected.
+            let _ = self.instance_counts.remove_label_values(&[&binding.path]);
+        }
+        self.query
+            .clear_counters()
+            .map_err(|s| anyhow::Error::msg(pdh_status_friendly_name(s)))?;
+        if let Some(budget) = self.series_budget {
+            budget.release(series);
+        }
+        for gauge in self.gauges.drain(0..) {
+            self.registry.unregister(Box::new(gauge))?;
+        }
+        Ok(())
+    }
+
+    /// Removes every series we have reported while keeping our counters so
+    /// prometheus sees the series go stale rather than repeating the last
+    /// values. Series come back on the next successful collection.
+    pub fn clear_series(&mut self) {
+        let mut series = 0;
+        for binding in self.wildcard_pairs.iter_mut() {
+            series += binding.instances.len();
+            binding.instances.clear();
+            let _ = self.instance_counts.remove_label_values(&[&binding.path]);
+        }
+        for gauge in self.gauges.iter() {
+            gauge.reset();
+        }
+        if let Some(budget) = self.series_budget {
+            budget.release(series);
+        }
+    }
+
+    /// Adds one of our counters to a new query keeping its format flags.
+    fn readd_counter(
+        &self,
+        query: &PdhQuery,
+        counter: &PdhCounter,
+    ) -> Result<PdhCounter, PdhError> {
+        let new_counter = if counter.is_english() {
+            query.add_english_counter(&counter.path())?
+        } else {
+            query.add_counter_string(counter.path())?
+        };
+        query.set_counter_format_flags(&new_counter, self.query.counter_format_flags(counter));
+        Ok(new_counter)
+    }
+
+    /// Opens a new query and adds every bound counter to it again. Every
+    /// handle in a query becomes invalid when e.g. perflib restarts.
+    /// If any counter can't be added again our bindings are left as they
+    /// were.
+    fn reconnect(&mut self) -> Result<(), PdhError> {
+        let query = self.pdh().open_query()?;
+        let pairs = self
+            .pairs
+            .iter()
+            .map(|binding| self.readd_counter(&query, &binding.counter))
+            .collect::<Result<Vec<PdhCounter>, PdhError>>()?;
+        let wildcard_pairs = self
+            .wildcard_pairs
+            .iter()
+            .map(|binding| self.readd_counter(&query, &binding.counter))
+            .collect::<Result<Vec<PdhCounter>, PdhError>>()?;
+        for (binding, counter) in self.pairs.iter_mut().zip(pairs) {
+            binding.counter = counter;
+        }
+        for (binding, counter) in self.wildcard_pairs.iter_mut().zip(wildcard_pairs) {
+            binding.counter = counter;
+        }
+        // The old counters were dropped above while their query was still
+        // open.
+        self.query = query;
+        Ok(())
+    }
+
+    /// Collects our query and formats the f64 scalar counters into
+    /// scalar_values in the order they appear in pairs.
+    fn collect_scalars(&mut self) -> Result<(), PdhError> {
+        let counters = self
+            .pairs
+            .iter()
+            .filter(|binding| !binding.integer)
+            .map(|binding| &binding.counter)
+            .collect::<Vec<&PdhCounter>>();
+        self.query
+            .collect_all_double_into(&counters, &mut self.scalar_values)
+    }
+
+    /// Collects every counter in our query at once and updates the gauges.
+    /// Returns an Err if the collection itself failed or the list of paths and
+    /// statuses for any individual counters or instances that could not be
+    /// read.
+    ///
+    /// A counter whose handle is no longer valid is reported with the other
+    /// errors and skipped. If the query's handle is no longer valid we
+    /// reconnect and add all of our counters again before retrying the
+    /// collection once. If that fails we try again on the next collection.
+    ///
+    /// Every counter lives in our one query and is read after a single
+    /// `PdhCollectQueryData` so the rate counters are all computed over the
+    /// same interval. Debug builds of the perf wrapper warn if a rate counter
+    /// is read without its query being collected.
+    pub fn collect(&mut self) -> Result<Vec<(String, PdhError)>, PdhError> {
+        collect_or_reconnect(self, Self::collect_scalars, Self::reconnect)?;
+        let mut errors = Vec::new();
+        let mut scalar_values = self.scalar_values.iter();
+        for binding in self.pairs.iter_mut() {
+            if !binding.integer {
+                match scalar_values.next() {
+                    Some(Ok(v)) => binding
+                        .gauge
+                        .with(&prometheus::labels! {})
+                        .set(v * binding.scale),
+                    Some(Err(s)) => errors.push((binding.path.clone(), *s)),
+                    None => {}
+                }
+                continue;
+            }
+            match self.query.format_large_data(&binding.counter) {
+                Ok(v) => {
+                    if loses_precision(v) && !binding.precision_warned {
+                        warn!(
+                            "{} = {} is {} which can't be represented exactly. The reported value will be rounded.",
+                            binding.name, binding.path, v
+                        );
+                        binding.precision_warned = true;
+                    }
+                    binding
+                        .gauge
+                        .with(&prometheus::labels! {})
+                        .set(v as f64 * binding.scale);
+                }
+                Err(s) => errors.push((binding.path.clone(), s)),
+            }
+        }
+        let series_budget = self.series_budget;
+        for binding in self.wildcard_pairs.iter_mut() {
+            let result = if binding.integer {
+                match self.query.format_large_array_into(
+                    &binding.counter,
+                    &mut self.array_buffer,
+                    &mut self.large_items,
+                ) {
+                    Ok(()) => Ok(large_items_to_f64(&self.large_items, &mut self.array_items)),
+                    Err(s) => Err(s),
+                }
+            } else {
+                self.query
+                    .format_double_array_into(
+                        &binding.counter,
+                        &mut self.array_buffer,
+                        &mut self.array_items,
+                    )
+                    .map(|_| None)
+            };
+            match result {
+                Ok(Some(v)) if !binding.precision_warned => {
+                    warn!(
+                        "{} = {} has a value of {} which can't be represented exactly. The reported values will be rounded.",
+                        binding.name, binding.path, v
+                    );
+                    binding.precision_warned = true;
+                }
+                Ok(_) => {}
+                Err(s) => {
+                    errors.push((binding.path.clone(), s));
+                    continue;
+                }
+            }
+            self.instance_counts
+                .with_label_values(&[&binding.path])
+                .set(self.array_items.len() as i64);
+            let (valid, invalid) = partition_items(&self.array_items);
+            // Skip instances with invalid data rather than report a bogus
+            // value for them.
+            for (instance, status) in invalid {
+                errors.push((binding.path.replace('*', &instance), status.into()));
             }
+            let mut instances = BTreeSet::new();
+            for (instance, value) in valid {
+                // New instances need a series from the budget.
+                if !binding.instances.contains(&instance) {
+                    let taken = series_budget
+                        .map(|budget| budget.try_take(&binding.name, &binding.path))
+                        .unwrap_or(true);
+                    if !taken {
+                        continue;
+                    }
+                }
+                binding
+                    .gauge
+                    .with_label_values(&as_strs(&binding.label_values(&instance)))
+                    .set(value * binding.scale);
+                instances.insert(instance);
+            }
+            // Drop the series for any instances that have gone away.
+            let mut gone_count = 0;
+            for gone in binding.instances.difference(&instances) {
+                let _ = binding
+                    .gauge
+                    .remove_label_values(&as_strs(&binding.label_values(gone)));
+                gone_count += 1;
+            }
+            if let Some(budget) = series_budget {
+                budget.release(gone_count);
+            }
+            binding.instances = instances;
+        }
+        Ok(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(instance: &str, status: u32, value: f64) -> ArrayItem {
+        ArrayItem {
+            instance: instance.to_owned(),
+            status: status,
+            value: value,
+        }
+    }
+
+    /// Stands in for a query. Each collection pops the next result and sets
+    /// the per-counter values.
+    struct MockQuery {
+        collections: Vec<Result<(), PdhError>>,
+        values: Vec<Result<f64, PdhError>>,
+        reconnects: usize,
+    }
+
+    impl MockQuery {
+        fn new(collections: Vec<Result<(), PdhError>>) -> Self {
+            Self {
+                collections: collections,
+                values: Vec::new(),
+                reconnects: 0,
+            }
+        }
+
+        fn collect(&mut self) -> Result<(), PdhError> {
+            self.collections.remove(0)?;
+            self.values = vec![Ok(1.0), Err(PdhError::InvalidHandle)];
+            Ok(())
+        }
+
+        fn reconnect(&mut self) -> Result<(), PdhError> {
+            self.reconnects += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn invalid_counter_is_skipped_without_reconnecting() {
+        let mut query = MockQuery::new(vec![Ok(())]);
+        collect_or_reconnect(&mut query, MockQuery::collect, MockQuery::reconnect).unwrap();
+        assert_eq!(query.reconnects, 0);
+        assert_eq!(query.values, vec![Ok(1.0), Err(PdhError::InvalidHandle)]);
+    }
+
+    #[test]
+    fn invalid_query_reconnects_and_retries() {
+        let mut query = MockQuery::new(vec![Err(PdhError::InvalidHandle), Ok(())]);
+        collect_or_reconnect(&mut query, MockQuery::collect, MockQuery::reconnect).unwrap();
+        assert_eq!(query.reconnects, 1);
+        assert_eq!(query.values.len(), 2);
+    }
+
+    #[test]
+    fn invalid_query_is_only_retried_once() {
+        let mut query = MockQuery::new(vec![
+            Err(PdhError::InvalidHandle),
+            Err(PdhError::InvalidHandle),
+            Ok(()),
+        ]);
+        let result = collect_or_reconnect(&mut query, MockQuery::collect, MockQuery::reconnect);
+        assert_eq!(result, Err(PdhError::InvalidHandle));
+        assert_eq!(query.reconnects, 1);
+    }
+
+    #[test]
+    fn other_collection_errors_dont_reconnect() {
+        let mut query = MockQuery::new(vec![Err(PdhError::NoData)]);
+        let result = collect_or_reconnect(&mut query, MockQuery::collect, MockQuery::reconnect);
+        assert_eq!(result, Err(PdhError::NoData));
+        assert_eq!(query.reconnects, 0);
+    }
+
+    #[test]
+    fn failed_registration_returns_its_series() {
+        let registry = Registry::new();
+        let budget = SeriesBudget::new(2);
+        assert!(budget.try_take("mem", r"\Memory\Available Bytes"));
+        register_budgeted_gauge(Some(&budget), "mem", "first", &registry).unwrap();
+        assert_eq!(budget.used.load(Ordering::SeqCst), 1);
+        // The same name again fails to register.
+        assert!(budget.try_take("mem", r"\Memory\Cache Bytes"));
+        assert!(register_budgeted_gauge(Some(&budget), "mem", "second", &registry).is_err());
+        assert_eq!(budget.used.load(Ordering::SeqCst), 1);
+        // So reloading the same bad config never exhausts the budget.
+        for _ in 0..3 {
+            assert!(budget.try_take("mem", r"\Memory\Cache Bytes"));
+            assert!(register_budgeted_gauge(Some(&budget), "mem", "again", &registry).is_err());
         }
-        Ok(pairs)
+        assert_eq!(budget.used.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn large_items_report_the_first_rounded_valid_value() {
+        let large = |instance: &str, status, value| ArrayItem {
+            instance: instance.to_owned(),
+            status: status,
+            value: value,
+        };
+        let large_items = vec![
+            large("C:", PDH_CSTATUS_VALID_DATA, 1 << 40),
+            // Invalid values are never reported so they can't be rounded.
+            large("D:", PDH_CSTATUS_INVALID_DATA, i64::MAX),
+            large("E:", PDH_CSTATUS_NEW_DATA, F64_EXACT_MAX + 1),
+            large("F:", PDH_CSTATUS_VALID_DATA, -F64_EXACT_MAX - 3),
+        ];
+        let mut items = vec![item("stale", PDH_CSTATUS_VALID_DATA, 1.0)];
+        assert_eq!(
+            large_items_to_f64(&large_items, &mut items),
+            Some(F64_EXACT_MAX + 1)
+        );
+        assert_eq!(items.len(), 4);
+        assert_eq!(
+            items[0],
+            item("C:", PDH_CSTATUS_VALID_DATA, (1u64 << 40) as f64)
+        );
+        assert_eq!(items[1].status, PDH_CSTATUS_INVALID_DATA);
+
+        let exact = vec![large("C:", PDH_CSTATUS_VALID_DATA, F64_EXACT_MAX)];
+        assert_eq!(large_items_to_f64(&exact, &mut items), None);
+        assert_eq!(
+            items,
+            vec![item("C:", PDH_CSTATUS_VALID_DATA, F64_EXACT_MAX as f64)]
+        );
+    }
+
+    #[test]
+    fn thread_instances_split_into_process_and_thread() {
+        let rule = INSTANCE_LABEL_RULES
+            .iter()
+            .find(|rule| rule.object == "Thread")
+            .unwrap();
+        let split = |instance| split_instance(instance, rule.separator, rule.labels);
+        assert_eq!(split("svchost/3"), vec!["svchost", "3"]);
+        assert_eq!(split("_Total/_Total"), vec!["_Total", "_Total"]);
+        // Only the first separator splits so the thread keeps the rest.
+        assert_eq!(split("a/b/c"), vec!["a", "b/c"]);
+        // Missing parts are empty rather than dropping a label.
+        assert_eq!(split("Idle"), vec!["Idle", ""]);
+        assert_eq!(split(""), vec!["", ""]);
+    }
+
+    #[test]
+    fn partition_items_skips_invalid_instances() {
+        let items = vec![
+            item("C:", PDH_CSTATUS_VALID_DATA, 12.5),
+            item("D:", PDH_CSTATUS_INVALID_DATA, 99.0),
+        ];
+        let (valid, invalid) = partition_items(&items);
+        assert_eq!(valid, vec![("C:".to_owned(), 12.5)]);
+        assert_eq!(invalid, vec![("D:".to_owned(), PDH_CSTATUS_INVALID_DATA)]);
+    }
+
+    #[test]
+    fn partition_items_numbers_repeats_across_valid_and_invalid() {
+        let items = vec![
+            item("svchost", PDH_CSTATUS_INVALID_DATA, 0.0),
+            item("svchost", PDH_CSTATUS_NEW_DATA, 1.0),
+        ];
+        let (valid, invalid) = partition_items(&items);
+        assert_eq!(valid, vec![("svchost#1".to_owned(), 1.0)]);
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].0, "svchost");
+    }
+
+    /// Reads the one value of a gathered metric family.
+    #[cfg(windows)]
+    fn gathered_value(registry: &Registry, name: &str) -> f64 {
+        let families = registry.gather();
+        let family = families.iter().find(|f| f.get_name() == name).unwrap();
+        return family.get_metric()[0].get_gauge().get_value();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn kilobyte_counter_scaled_to_bytes() {
+        let registry = Registry::new();
+        let instance_counts = IntGaugeVec::new(
+            prometheus::Opts::new("instance_count", "instances"),
+            &["path"],
+        )
+        .unwrap();
+        let mut binding = CounterToPrometheus::try_new(&registry, instance_counts).unwrap();
+        let group = crate::config::parse_config(
+            "mem_available_kb_bytes = \\Memory\\Available KBytes\n\
+             mem_available_kb_bytes.scale = 1024\n\
+             mem_available_bytes = \\Memory\\Available Bytes\n",
+        )
+        .unwrap()
+        .remove(0);
+        binding.register_group(&group).unwrap();
+        binding.collect().unwrap();
+        let scaled = gathered_value(&registry, "mem_available_kb_bytes");
+        let bytes = gathered_value(&registry, "mem_available_bytes");
+        // Available KBytes truncates to whole kilobytes and memory use
+        // changes between the two reads so allow a little slack.
+        assert!(
+            (scaled - bytes).abs() < 64.0 * 1024.0 * 1024.0,
+            "{} scaled vs {} bytes",
+            scaled,
+            bytes
+        );
+        assert!(scaled > 1024.0 * 1024.0);
     }
 }
@@ -0,0 +1,325 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configuration of the metrics the exporter collects.
+//!
+//! The config file is a list of `metric_name = \Counter\Path` lines. Paths
+//! containing a `*` are expanded into a series per instance. Lines starting
+//! with `#` are comments. A `[group]` line starts a new group of metrics that
+//! will be collected together from the same query.
+//!
+//! ```text
+//! [cpu]
+//! cpu_total_pct = \Processor Information(_Total)\% Processor Time
+//!
+//! [network]
+//! network_ifc_bytes_sent_sec = \Network Interface(*)\Bytes Sent/sec
+//! ```
+//!
+//! Instances of the `Thread` object are named `process/thread_index` and are
+//! reported with separate `process` and `thread` labels instead of a single
+//! `instance` label. Beware that there is a series for every thread on the
+//! machine.
+//!
+//! With `--conventionalUnits` metric names and values are rewritten to follow
+//! the prometheus unit conventions based on the type of their counter.
+//! Informal suffixes like `_pct` and `_sec` are replaced.
+//!
+//! | Counter type   | Suffix               | Value      |
+//! |----------------|----------------------|------------|
+//! | Percentage     | `_ratio`             | 0-1        |
+//! | Bytes/sec      | `_bytes_per_second`  | unchanged  |
+//! | Other per sec  | `_per_second`        | unchanged  |
+//! | Seconds        | `_seconds`           | unchanged  |
+//!
+//! e.g. `cpu_total_pct` becomes `cpu_total_ratio`. Without the flag the names
+//! in the config are used as is.
+//!
+//! A `metric_name.scale = factor` line reports the metric's unscaled values
+//! multiplied by factor instead of letting PDH apply the counter's default
+//! scale. Use it to put counters whose default scale differs by driver into
+//! a common unit.
+//!
+//! ```text
+//! [memory]
+//! mem_available_bytes = \Memory\Available KBytes
+//! mem_available_bytes.scale = 1024
+//! ```
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use crate::perf_paths;
+
+/// A Group of metrics that must be collected together from the same query.
+/// Rate counters are computed from consecutive collections of their query so
+/// any counters that need to line up with each other belong in one group.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricGroup {
+    pub name: String,
+    pub pairs: Vec<(String, String)>,
+    pub wildcard_pairs: Vec<(String, String)>,
+    /// The explicit scale factors for metrics by name.
+    pub scales: BTreeMap<String, f64>,
+}
+
+impl MetricGroup {
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            pairs: Vec::new(),
+            wildcard_pairs: Vec::new(),
+            scales: BTreeMap::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pairs.is_empty() && self.wildcard_pairs.is_empty()
+    }
+
+    fn has_metric(&self, name: &str) -> bool {
+        self.pairs
+            .iter()
+            .chain(self.wildcard_pairs.iter())
+            .any(|(n, _)| n == name)
+    }
+
+    fn add<S: Into<String>>(mut self, name: S, path: S) -> Self {
+        let (name, path) = (name.into(), path.into());
+        if path.contains('*') {
+            self.wildcard_pairs.push((name, path));
+        } else {
+            self.pairs.push((name, path));
+        }
+        self
+    }
+}
+
+/// The metrics we collect when no config file was provided.
+pub fn default_groups() -> Vec<MetricGroup> {
+    vec![
+        MetricGroup::new("cpu")
+            .add("cpu_total_pct", perf_paths::CPU_TOTAL_PCT)
+            .add("cpu_user_pct", perf_paths::CPU_USER_PCT)
+            .add("cpu_idle_pct", perf_paths::CPU_IDLE_PCT)
+            .add("cpu_privileged_pct", perf_paths::CPU_PRIVILEGED_PCT)
+            .add("cpu_priority_pct", perf_paths::CPU_PRIORITY_PCT)
+            .add("cpu_frequency_gauge", perf_paths::CPU_FREQUENCY),
+        MetricGroup::new("memory")
+            .add("mem_available_bytes", perf_paths::MEM_AVAILABLE_BYTES)
+            .add("mem_cache_bytes", perf_paths::MEM_CACHE_BYTES)
+            .add("mem_committed_bytes", perf_paths::MEM_COMMITTED_BYTES),
+        MetricGroup::new("disk")
+            .add("disk_pct_read_time", perf_paths::DISK_PCT_READ_TIME)
+            .add("disk_pct_write_time", perf_paths::DISK_PCT_WRITE_TIME)
+            .add("disk_read_bytes_sec", perf_paths::DISK_READ_BYTES_SEC)
+            .add("disk_write_bytes_sec", perf_paths::DISK_WRITE_BYTES_SEC),
+        MetricGroup::new("system")
+            .add("sys_processes_count", perf_paths::SYS_PROCESSES_COUNT)
+            .add("sys_threads_count", perf_paths::SYS_THREADS_COUNT)
+            .add("sys_context_switch_sec", perf_paths::SYS_CONTEXT_SWITCH_SEC)
+            .add("sys_system_calls_sec", perf_paths::SYS_SYSTEM_CALLS_SEC)
+            .add("sys_uptime_seconds", perf_paths::SYS_UP_TIME),
+        MetricGroup::new("network")
+            .add(
+                "network_ifc_bytes_rcvd_sec",
+                perf_paths::NET_IFC_BYTES_RCVD_SEC,
+            )
+            .add(
+                "network_ifc_bytes_sent_sec",
+                perf_paths::NET_IFC_BYTES_SENT_SEC,
+            )
+            .add(
+                "network_ifc_pkts_rcvd_sec",
+                perf_paths::NET_IFC_PKTS_RCVD_SEC,
+            )
+            .add(
+                "network_ifc_pkts_sent_sec",
+                perf_paths::NET_IFC_PKTS_SENT_SEC,
+            )
+            .add(
+                "network_ifc_pkts_rcvd_err",
+                perf_paths::NET_IFC_PKTS_RCVD_ERR,
+            )
+            .add(
+                "network_ifc_bytes_rcvd_discard",
+                perf_paths::NET_IFC_PKTS_RCVD_DISCARD,
+            ),
+    ]
+}
+
+/// Checks that every scale line in a group is for one of its metrics.
+/// scale_lines maps each scaled metric to the line its scale was set on.
+fn check_scales(group: &MetricGroup, scale_lines: &BTreeMap<String, usize>) -> anyhow::Result<()> {
+    for (name, line_num) in scale_lines {
+        if !group.has_metric(name) {
+            return Err(anyhow::anyhow!(
+                "Line {}: No metric named {} in group {} to scale",
+                line_num,
+                name,
+                group.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Parses the contents of a config file into metric groups.
+pub fn parse_config(contents: &str) -> anyhow::Result<Vec<MetricGroup>> {
+    let mut groups = Vec::new();
+    let mut current = MetricGroup::new("default");
+    let mut scale_lines = BTreeMap::new();
+    for (line_num, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            check_scales(&current, &scale_lines)?;
+            scale_lines.clear();
+            let next = MetricGroup::new(line[1..line.len() - 1].trim());
+            let finished = std::mem::replace(&mut current, next);
+            if !finished.is_empty() {
+                groups.push(finished);
+            }
+            continue;
+        }
+        match line.find('=') {
+            Some(idx) => {
+                let name = line[0..idx].trim();
+                let path = line[idx + 1..].trim();
+                if name.is_empty() || path.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "Line {}: Expected metric_name = counter_path",
+                        line_num + 1
+                    ));
+                }
+                if name.ends_with(".scale") {
+                    let factor = path.parse::<f64>().map_err(|_| {
+                        anyhow::anyhow!("Line {}: Expected a number for the scale", line_num + 1)
+                    })?;
+                    let name = &name[0..name.len() - ".scale".len()];
+                    current.scales.insert(name.to_owned(), factor);
+                    scale_lines.insert(name.to_owned(), line_num + 1);
+                    continue;
+                }
+                current = current.add(name, path);
+            }
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Line {}: Expected metric_name = counter_path",
+                    line_num + 1
+                ));
+            }
+        }
+    }
+    check_scales(&current, &scale_lines)?;
+    if !current.is_empty() {
+        groups.push(current);
+    }
+    Ok(groups)
+}
+
+/// Loads and parses a config file.
+pub fn load_config<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<MetricGroup>> {
+    parse_config(&std::fs::read_to_string(path)?)
+}
+
+/// The set of metric names configured across all of the groups.
+pub fn metric_names(groups: &Vec<MetricGroup>) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    for group in groups {
+        for (name, _) in group.pairs.iter().chain(group.wildcard_pairs.iter()) {
+            names.insert(name.clone());
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_groups_and_wildcards() {
+        let groups = parse_config(
+            "# comment\n\
+             [cpu]\n\
+             cpu_total_pct = \\Processor(_Total)\\% Processor Time\n\
+             \n\
+             [network]\n\
+             net_bytes = \\Network Interface(*)\\Bytes Total/sec\n",
+        )
+        .unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].name, "cpu");
+        assert_eq!(groups[0].pairs.len(), 1);
+        assert_eq!(groups[1].name, "network");
+        assert_eq!(groups[1].wildcard_pairs.len(), 1);
+    }
+
+    #[test]
+    fn parses_scales_before_or_after_their_metric() {
+        let groups = parse_config(
+            "[memory]\n\
+             mem_cache_bytes.scale = 1024\n\
+             mem_cache_bytes = \\Memory\\Cache KBytes\n\
+             mem_available_bytes = \\Memory\\Available KBytes\n\
+             mem_available_bytes.scale = 1024\n",
+        )
+        .unwrap();
+        assert_eq!(groups[0].scales.get("mem_cache_bytes"), Some(&1024.0));
+        assert_eq!(groups[0].scales.get("mem_available_bytes"), Some(&1024.0));
+    }
+
+    #[test]
+    fn rejects_scale_for_unknown_metric() {
+        let err = parse_config(
+            "[memory]\n\
+             mem_available_bytes = \\Memory\\Available KBytes\n\
+             mem_avail_bytes.scale = 1024\n\
+             [cpu]\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Line 3: No metric named mem_avail_bytes in group memory to scale"
+        );
+        // A scale only applies to the metrics of its own group.
+        let err = parse_config(
+            "[memory]\n\
+             mem_available_bytes = \\Memory\\Available KBytes\n\
+             [cpu]\n\
+             mem_available_bytes.scale = 1024\n",
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("Line 4:"));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let err =
+            parse_config("[memory]\nmem = \\Memory\\Cache Bytes\nmem.scale = lots\n").unwrap_err();
+        assert_eq!(err.to_string(), "Line 3: Expected a number for the scale");
+        let err = parse_config("no equals sign\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Line 1: Expected metric_name = counter_path"
+        );
+        let err = parse_config("name =\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Line 1: Expected metric_name = counter_path"
+        );
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Appending each collection to a CSV file for a local record of the metrics
+//! that doesn't depend on prometheus.
+//!
+//! Each row is the time of the collection followed by one column per series
+//! named the way prometheus prints them e.g. `cpu_total_pct{cpu="0"}`. The
+//! header of an existing file is read back so rows stay aligned across
+//! restarts. The columns of a file never change. A series missing from a row
+//! is left empty and a series the header doesn't have is dropped with a
+//! warning until the file is rotated and the next one gets a column for it.
+//!
+//! Files are rotated when the day changes and when they grow past the size
+//! limit if there is one. The old file is renamed with the time it was
+//! rotated e.g. `metrics.csv.20201010-120000`, followed by a counter if a
+//! file with that name exists already e.g. `metrics.csv.20201010-120000.1`.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local, NaiveDate};
+use log::warn;
+use prometheus::proto::{MetricFamily, MetricType};
+
+/// Quotes a field if it contains anything CSV treats specially.
+fn escape(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Splits a CSV line into its fields undoing escape.
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::replace(&mut field, String::new())),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// The value of every gauge, counter and untyped series keyed by its name
+/// and labels.
+fn series_values(families: &[MetricFamily]) -> BTreeMap<String, f64> {
+    let mut series = BTreeMap::new();
+    for family in families {
+        for metric in family.get_metric() {
+            let value = match family.get_field_type() {
+                MetricType::GAUGE => metric.get_gauge().get_value(),
+                MetricType::COUNTER => metric.get_counter().get_value(),
+                MetricType::UNTYPED => metric.get_untyped().get_value(),
+                _ => continue,
+            };
+            let labels = metric
+                .get_label()
+                .iter()
+                .map(|l| format!("{}=\"{}\"", l.get_name(), l.get_value()))
+                .collect::<Vec<String>>();
+            let name = if labels.is_empty() {
+                family.get_name().to_owned()
+            } else {
+                format!("{}{{{}}}", family.get_name(), labels.join(","))
+            };
+            series.insert(name, value);
+        }
+    }
+    series
+}
+
+/// A CSV file rows of metric values are appended to.
+pub struct CsvLog {
+    path: PathBuf,
+    /// The size in bytes past which the file is rotated. 0 for no limit.
+    max_bytes: u64,
+    /// The series columns after the time column. Empty until the header
+    /// has been written.
+    columns: Vec<String>,
+    /// The day the file was last written on.
+    day: Option<NaiveDate>,
+    /// The series dropped from the current file since it has no column for
+    /// them.
+    dropped: BTreeSet<String>,
+}
+
+/// The name to rotate a file to. The time it was rotated is appended to its
+/// path along with a counter if needed to avoid replacing an older file.
+fn rotated_path(path: &PathBuf, now: &DateTime<Local>) -> PathBuf {
+    let mut base = path.clone().into_os_string();
+    base.push(format!(".{}", now.format("%Y%m%d-%H%M%S")));
+    let mut rotated = PathBuf::from(base.clone());
+    let mut count = 0;
+    while rotated.exists() {
+        count += 1;
+        let mut name = base.clone();
+        name.push(format!(".{}", count));
+        rotated = PathBuf::from(name);
+    }
+    rotated
+}
+
+impl CsvLog {
+    /// Opens the log at path reading the header of the file if it exists
+    /// already.
+    pub fn open<P: Into<PathBuf>>(path: P, max_bytes: u64) -> io::Result<Self> {
+        let mut log = CsvLog {
+            path: path.into(),
+            max_bytes: max_bytes,
+            columns: Vec::new(),
+            day: None,
+            dropped: BTreeSet::new(),
+        };
+        match File::open(&log.path) {
+            Ok(file) => {
+                let modified = file.metadata()?.modified()?;
+                log.day = Some(DateTime::<Local>::from(modified).naive_local().date());
+                let mut header = String::new();
+                BufReader::new(file).read_line(&mut header)?;
+                let header = header.trim_end_matches(|c| c == '\r' || c == '\n');
+                if !header.is_empty() {
+                    log.columns = split_fields(header).into_iter().skip(1).collect();
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(log)
+    }
+
+    /// Renames the current file out of the way so the next row starts a new
+    /// one.
+    fn rotate(&mut self, now: &DateTime<Local>) -> io::Result<()> {
+        fs::rename(&self.path, rotated_path(&self.path, now))?;
+        self.columns.clear();
+        self.dropped.clear();
+        Ok(())
+    }
+
+    /// Appends a row with the values of the families. The header is written
+    /// first if the file is new. Series the header doesn't have are left out
+    /// of the row.
+    pub fn append(&mut self, families: &[MetricFamily]) -> io::Result<()> {
+        let series = series_values(families);
+        let now = Local::now();
+        let today = now.naive_local().date();
+        if !self.columns.is_empty() {
+            let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+            if self.day != Some(today) || (self.max_bytes > 0 && size >= self.max_bytes) {
+                self.rotate(&now)?;
+            }
+        }
+        if !self.columns.is_empty() {
+            for name in series.keys() {
+                if !self.columns.contains(name) && self.dropped.insert(name.clone()) {
+                    warn!(
+                        "Dropping {} from {} until it is rotated since it has no column for it",
+                        name,
+                        self.path.display()
+                    );
+                }
+            }
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut out = String::new();
+        if self.columns.is_empty() {
+            self.columns = series.keys().cloned().collect();
+            out.push_str("time");
+            for column in self.columns.iter() {
+                out.push(',');
+                out.push_str(&escape(column));
+            }
+            out.push('\n');
+        }
+        out.push_str(&now.to_rfc3339());
+        for column in self.columns.iter() {
+            out.push(',');
+            if let Some(value) = series.get(column) {
+                out.push_str(&value.to_string());
+            }
+        }
+        out.push('\n');
+        file.write_all(out.as_bytes())?;
+        self.day = Some(today);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Gauge, Opts, Registry};
+
+    /// A fresh directory under the system temp directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("csvlog-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn gauges(names: &[&str]) -> Vec<MetricFamily> {
+        let registry = Registry::new();
+        for name in names {
+            let gauge = Gauge::with_opts(Opts::new(*name, "help")).unwrap();
+            gauge.set(1.0);
+            registry.register(Box::new(gauge)).unwrap();
+        }
+        registry.gather()
+    }
+
+    #[test]
+    fn unknown_series_are_dropped_until_rotation() {
+        let dir = temp_dir("columns");
+        let path = dir.join("metrics.csv");
+        let mut log = CsvLog::open(&path, 0).unwrap();
+        log.append(&gauges(&["a"])).unwrap();
+        log.append(&gauges(&["a", "b"])).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "time,a");
+        assert!(lines.iter().skip(1).all(|l| split_fields(l).len() == 2));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotated_paths_are_unique() {
+        let dir = temp_dir("rotate");
+        let path = dir.join("metrics.csv");
+        let now = Local::now();
+        let first = rotated_path(&path, &now);
+        fs::write(&first, "").unwrap();
+        let second = rotated_path(&path, &now);
+        assert_ne!(first, second);
+        assert!(second.to_string_lossy().ends_with(".1"));
+        fs::write(&second, "").unwrap();
+        assert!(rotated_path(&path, &now).to_string_lossy().ends_with(".2"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
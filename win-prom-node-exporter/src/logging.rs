@@ -0,0 +1,110 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Structured logging support.
+//!
+//! The JsonLogger writes one JSON object per line to stderr with the
+//! timestamp, level, message and any structured fields of the record.
+use std::io::Write;
+
+use log::kv::{self, Key, Value, Visitor};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value as JsonValue};
+
+/// A logger that writes records as JSON lines to stderr.
+pub struct JsonLogger {
+    level: LevelFilter,
+}
+
+impl JsonLogger {
+    /// Installs a JsonLogger as the global logger.
+    pub fn init(level: LevelFilter) -> anyhow::Result<()> {
+        log::set_boxed_logger(Box::new(JsonLogger { level: level }))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+/// Collects a record's key values into a JSON object.
+struct FieldCollector<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'a, 'kvs> Visitor<'kvs> for FieldCollector<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0
+            .insert(key.to_string(), JsonValue::String(value.to_string()));
+        Ok(())
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = Map::new();
+        line.insert(
+            "timestamp".to_owned(),
+            JsonValue::String(chrono::Utc::now().to_rfc3339()),
+        );
+        line.insert(
+            "level".to_owned(),
+            JsonValue::String(record.level().to_string()),
+        );
+        line.insert(
+            "message".to_owned(),
+            JsonValue::String(record.args().to_string()),
+        );
+        line.insert(
+            "target".to_owned(),
+            JsonValue::String(record.target().to_owned()),
+        );
+        let mut fields = Map::new();
+        let _ = record.key_values().visit(&mut FieldCollector(&mut fields));
+        if !fields.is_empty() {
+            line.insert("fields".to_owned(), JsonValue::Object(fields));
+        }
+        let stderr = std::io::stderr();
+        let mut handle = stderr.lock();
+        let _ = writeln!(handle, "{}", JsonValue::Object(line));
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Logs a message with structured fields. Loggers that don't support
+/// structured fields log just the message.
+pub fn log_with_fields(
+    level: Level,
+    target: &str,
+    fields: &[(&str, &str)],
+    args: std::fmt::Arguments,
+) {
+    if level > log::max_level() {
+        return;
+    }
+    log::logger().log(
+        &Record::builder()
+            .level(level)
+            .target(target)
+            .key_values(&fields)
+            .args(args)
+            .build(),
+    );
+}
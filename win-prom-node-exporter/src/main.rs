@@ -15,15 +15,16 @@
 use std::convert::Into;
 use std::env;
 use std::ffi::OsString;
-use std::sync::{Mutex, RwLock};
-use std::time::Duration;
+use std::sync::mpsc;
+use std::sync::{Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow;
 use crossbeam_utils::thread;
 use docopt;
 use eventlog;
 use lazy_static;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use prometheus;
 use prometheus::Encoder;
 use windows_service;
@@ -34,15 +35,34 @@ use windows_service::service::{
 use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
 use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
 
-use winapi_perf_wrapper::ValueStream;
+use winapi_perf_wrapper::constants::{pdh_status_friendly_name, PdhError};
+use winapi_perf_wrapper::Machine;
 
 mod binding;
+mod config;
+mod csvlog;
+mod logging;
+mod openmetrics;
+#[cfg(feature = "trace")]
+mod pdh_trace;
 mod perf_paths;
+mod pipe;
+mod remote;
+mod services;
+mod state;
+mod statsd;
 
 lazy_static::lazy_static! {
     static ref STOP_SIGNAL: RwLock<bool> = RwLock::new(false);
 }
 
+lazy_static::lazy_static! {
+    static ref METRIC_CONFIG: RwLock<MetricConfig> = RwLock::new(MetricConfig {
+        generation: 0,
+        groups: Vec::new(),
+    });
+}
+
 lazy_static::lazy_static! {
     static ref SERVICE_ARGS: std::sync::Mutex<Option<docopt::ArgvMap>> = Mutex::new(None);
 }
@@ -54,25 +74,133 @@ const LOGNAME: &'static str = "Prometheus Node Exporter Log";
 const USAGE: &'static str = "
 Windows Prometheus Node Exporter
 
-Usage: win-prom-node-exporter [options]
+Usage: win-prom-node-exporter [options] [--watchService=NAME]... [--remoteHost=NAME]...
+
+Every option but --help, --install and --remove can also be set with an environment
+variable named for it, e.g. WIN_EXPORTER_DELAY_SECS=30 for --delaySecs or
+WIN_EXPORTER_NO_HTTP=true for --noHttp. Options given on the command line take
+precedence over environment variables which take precedence over the defaults.
 
 Options:
     -h --help            Show this help text
     --delaySecs=S        Delay between collections from windows performance counters in seconds. [default: 10]
     --listenHost=IPPORT  IP and Port combination for the http service to export prometheus metrics on. [default: 0.0.0.0:8080]
+    --alignCollection    Align collections to wall clock multiples of --delaySecs.
+    --conventionalUnits  Name metrics and scale their values following the prometheus unit
+                         conventions. e.g. percentages are reported as 0-1 _ratio metrics.
+    --maxTotalSeries=N   Most series to report across all metrics. Counters and instances past
+                         the cap are skipped. 0 for no limit. [default: 0]
+    --remoteHost=NAME    Also collect the metrics from the remote machine NAME labeled with
+                         host=NAME. May be repeated.
+    --collectWorkers=N   Number of worker threads to spread counter collection across. [default: 1]
+    --configFile=PATH    File listing the metrics to collect. Changes are reloaded automatically.
+    --exposition=FMT     Format to serve the metrics in. Either text or openmetrics. [default: text]
+    --noHttp             Don't serve the metrics over http. Use with --statsdAddr, --pipeName or --csvLog.
+    --pipeName=NAME      Also serve the metrics to local clients of the named pipe \\\\.\\pipe\\NAME.
+    --statsdAddr=HOSTPORT  Also push the metrics as gauges to the statsd server at HOST:PORT each collection.
+    --watchService=NAME  Report the state of the windows service NAME as win_service_state.
+                         May be repeated.
+    --dogstatsd          Send labels to the statsd server as dogstatsd tags.
+    --csvLog=FILE        Also append the metrics to the CSV file FILE each collection. The file
+                         is rotated daily.
+    --csvLogMaxMB=N      Also rotate the --csvLog file once it is larger than N megabytes.
+                         0 for no limit. [default: 0]
+    --statePath=FILE     Save the last metric values to FILE when stopping and serve them after
+                         starting again until fresh values are collected.
+    --scrapeTimeout=S    Seconds to wait for the metrics to be gathered before responding with a 503. [default: 5]
     --debug              Enable debug logging.
+    --logFormat=FMT      Format of the logs written to stderr with --no-service. Either text or json. [default: text]
     --install            Install this windows service with the provided command line flags.
+                         Updates the service in place if it is already installed.
     --remove             Delete this windows service.
 
     --no-service         Don't run as a Windows Service.
 ";
 
+/// The prefix of the environment variables options can be set with.
+const ENV_PREFIX: &'static str = "WIN_EXPORTER_";
+
+/// Options that can only be given on the command line.
+const COMMAND_LINE_ONLY: &'static [&'static str] = &["--help", "--install", "--remove"];
+
 fn flags() -> docopt::Docopt {
     docopt::Docopt::new(USAGE).unwrap()
 }
 
+/// The environment variable for an option, e.g. WIN_EXPORTER_DELAY_SECS for
+/// --delaySecs.
+fn env_var_name(option: &str) -> String {
+    let mut name = ENV_PREFIX.to_owned();
+    for c in option.trim_start_matches('-').chars() {
+        if c == '-' {
+            name.push('_');
+        } else if c.is_ascii_uppercase() {
+            name.push('_');
+            name.push(c);
+        } else {
+            name.push(c.to_ascii_uppercase());
+        }
+    }
+    name
+}
+
+/// The long options in USAGE and whether each takes a value.
+fn usage_options() -> Vec<(&'static str, bool)> {
+    USAGE
+        .lines()
+        .map(|l| l.trim_start())
+        .filter(|l| l.starts_with("--"))
+        .filter_map(|l| l.split_whitespace().next())
+        .map(|option| match option.find('=') {
+            Some(i) => (&option[..i], true),
+            None => (option, false),
+        })
+        .filter(|(option, _)| !COMMAND_LINE_ONLY.contains(option))
+        .collect()
+}
+
+/// Adds the options set in the environment to the command line args unless
+/// the args already set them. Flags are set by any value but an empty
+/// string, 0 or false.
+fn merge_env_args<F>(mut args: Vec<String>, lookup: F) -> Vec<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    for (option, takes_value) in usage_options() {
+        let given = args
+            .iter()
+            .any(|a| a == option || a.starts_with(&format!("{}=", option)));
+        if given {
+            continue;
+        }
+        let value = match lookup(&env_var_name(option)) {
+            Some(value) => value,
+            None => continue,
+        };
+        if takes_value {
+            args.push(format!("{}={}", option, value));
+        } else if !(value.is_empty() || value == "0" || value.eq_ignore_ascii_case("false")) {
+            args.push(option.to_owned());
+        }
+    }
+    args
+}
+
 fn init_log(argv: &docopt::ArgvMap) -> anyhow::Result<()> {
-    if argv.get_bool("--no-service") {
+    let log_format = argv.get_str("--logFormat");
+    if log_format != "text" && log_format != "json" {
+        return Err(anyhow::anyhow!(
+            "Invalid --logFormat {}. Expected text or json",
+            log_format
+        ));
+    }
+    if argv.get_bool("--no-service") && log_format == "json" {
+        logging::JsonLogger::init(if argv.get_bool("--debug") {
+            log::LevelFilter::Debug
+        } else {
+            log::LevelFilter::Info
+        })?;
+    } else if argv.get_bool("--no-service") {
         stderrlog::new()
             .timestamp(stderrlog::Timestamp::Millisecond)
             .verbosity(if argv.get_bool("--debug") { 3 } else { 2 })
@@ -167,6 +295,420 @@ fn win_service_main(_args: Vec<OsString>) {
         .unwrap(); // if this failed then we are in deep trouble. Just crash.
 }
 
+/// How often sleeping threads check the STOP_SIGNAL.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn stopping() -> bool {
+    *STOP_SIGNAL.read().unwrap()
+}
+
+/// Sleeps until target waking up periodically to check the STOP_SIGNAL so a
+/// long collection interval doesn't hold up stopping the service. Returns
+/// false if we are stopping.
+fn sleep_until(target: Instant) -> bool {
+    loop {
+        if stopping() {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= target {
+            return true;
+        }
+        std::thread::sleep(std::cmp::min(target - now, STOP_POLL_INTERVAL));
+    }
+}
+
+/// The state of a ReloadBarrier shared between the workers.
+struct BarrierState {
+    /// The number of workers waiting.
+    waiting: usize,
+    /// Incremented every time all of the workers have arrived.
+    round: u64,
+    /// The config generation and groups read by the last worker to arrive.
+    config: (u64, Vec<config::MetricGroup>),
+}
+
+/// The barrier the collection workers meet at when reloading metrics. Unlike
+/// std's Barrier it gives up when the service is stopping. Workers that have
+/// already stopped never arrive so waiting for them would hang shutdown.
+struct ReloadBarrier {
+    count: usize,
+    state: Mutex<BarrierState>,
+    cvar: Condvar,
+}
+
+impl ReloadBarrier {
+    fn new(count: usize) -> Self {
+        Self {
+            count: count,
+            state: Mutex::new(BarrierState {
+                waiting: 0,
+                round: 0,
+                config: (0, Vec::new()),
+            }),
+            cvar: Condvar::new(),
+        }
+    }
+
+    /// Waits for every worker to arrive and returns the config generation and
+    /// groups they should all register. The last worker to arrive reads the
+    /// config so every worker registers from the same snapshot even if the
+    /// config is reloaded again while they wait. Returns None if we are
+    /// stopping.
+    fn wait(&self) -> Option<(u64, Vec<config::MetricGroup>)> {
+        let mut state = self.state.lock().unwrap();
+        let round = state.round;
+        state.waiting += 1;
+        if state.waiting == self.count {
+            let guard = METRIC_CONFIG.read().unwrap();
+            state.config = (guard.generation, guard.groups.clone());
+            state.waiting = 0;
+            state.round += 1;
+            self.cvar.notify_all();
+            return Some(state.config.clone());
+        }
+        while state.round == round {
+            if stopping() {
+                return None;
+            }
+            state = self.cvar.wait_timeout(state, STOP_POLL_INTERVAL).unwrap().0;
+        }
+        Some(state.config.clone())
+    }
+}
+
+/// The currently configured metric groups along with a generation number
+/// that is incremented every time the config is reloaded.
+struct MetricConfig {
+    generation: u64,
+    groups: Vec<config::MetricGroup>,
+}
+
+/// Returns the groups a given worker is responsible for.
+fn worker_share(
+    groups: &Vec<config::MetricGroup>,
+    worker_id: usize,
+    worker_count: usize,
+) -> Vec<config::MetricGroup> {
+    groups
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| i % worker_count == worker_id)
+        .map(|(_, g)| g.clone())
+        .collect()
+}
+
+fn register_groups(binding: &mut binding::CounterToPrometheus, groups: &Vec<config::MetricGroup>) {
+    for group in groups.iter() {
+        if let Err(e) = binding.register_group(group) {
+            logging::log_with_fields(
+                log::Level::Error,
+                module_path!(),
+                &[("group", &group.name), ("error", &e.to_string())],
+                format_args!("Failed to register metric group {}: {}", group.name, e),
+            );
+        }
+    }
+    debug!("Registered {} pdh counters", binding.counter_count());
+}
+
+/// Polls the config file for changes and publishes the new metric groups
+/// to the collection workers when it changes.
+fn config_watcher(config_file: &str) {
+    let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified(config_file);
+    loop {
+        {
+            if *STOP_SIGNAL.read().unwrap() {
+                info!("Stopping config watcher thread.");
+                return;
+            }
+        }
+        std::thread::sleep(Duration::from_secs(1));
+        let current = modified(config_file);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+        info!("Config file {} changed. Reloading", config_file);
+        let groups = match config::load_config(config_file) {
+            Ok(groups) => groups,
+            Err(e) => {
+                error!("Failed to reload config file {}: {}", config_file, e);
+                continue;
+            }
+        };
+        let mut guard = METRIC_CONFIG.write().unwrap();
+        let old_names = config::metric_names(&guard.groups);
+        let new_names = config::metric_names(&groups);
+        for added in new_names.difference(&old_names) {
+            info!("Adding metric {}", added);
+        }
+        for removed in old_names.difference(&new_names) {
+            info!("Removing metric {}", removed);
+        }
+        guard.generation += 1;
+        guard.groups = groups;
+    }
+}
+
+/// The time of the first collection. Aligned collections start at the next
+/// wall clock multiple of the interval so hosts collect at the same moments.
+fn first_collection(interval: Duration, align: bool) -> Instant {
+    let now = Instant::now();
+    if !align || interval.as_secs() == 0 {
+        return now;
+    }
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let offset = since_epoch.as_secs() % interval.as_secs();
+    if offset == 0 && since_epoch.subsec_nanos() == 0 {
+        return now;
+    }
+    now + interval - Duration::new(offset, since_epoch.subsec_nanos())
+}
+
+/// Advances target to the next collection time after now. If collection fell
+/// behind by more than an interval the missed collections are skipped rather
+/// than run back to back. Returns the new target and the number skipped.
+fn next_collection(target: Instant, interval: Duration, now: Instant) -> (Instant, u32) {
+    let mut next = target + interval;
+    let mut skipped = 0;
+    while next <= now && interval > Duration::from_secs(0) {
+        next += interval;
+        skipped += 1;
+    }
+    (next, skipped)
+}
+
+/// Pushes the gathered metrics to a statsd server every delay_secs.
+fn statsd_pusher(
+    addr: &str,
+    dogstatsd: bool,
+    registries: &[&prometheus::Registry],
+    delay_secs: u64,
+) {
+    let socket = match std::net::UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to open a socket for statsd: {}", e);
+            return;
+        }
+    };
+    info!("Pushing metrics to statsd at {}", addr);
+    loop {
+        {
+            if *STOP_SIGNAL.read().unwrap() {
+                info!("Stopping statsd thread.");
+                return;
+            }
+        }
+        if !sleep_until(Instant::now() + Duration::from_secs(delay_secs)) {
+            info!("Stopping statsd thread.");
+            return;
+        }
+        let lines = statsd::gauge_lines(&remote::gather_all(registries), dogstatsd);
+        if let Err(e) = statsd::send_lines(&socket, addr, &lines) {
+            error!("Failed to send metrics to statsd at {}: {}", addr, e);
+        }
+    }
+}
+
+/// Appends the gathered metrics to a CSV file every delay_secs.
+fn csv_logger(path: &str, max_mb: u64, registries: &[&prometheus::Registry], delay_secs: u64) {
+    let mut log = match csvlog::CsvLog::open(path, max_mb * 1024 * 1024) {
+        Ok(log) => log,
+        Err(e) => {
+            error!("Failed to open the csv log {}: {}", path, e);
+            return;
+        }
+    };
+    info!("Logging metrics to {}", path);
+    loop {
+        {
+            if *STOP_SIGNAL.read().unwrap() {
+                info!("Stopping csv log thread.");
+                return;
+            }
+        }
+        if !sleep_until(Instant::now() + Duration::from_secs(delay_secs)) {
+            info!("Stopping csv log thread.");
+            return;
+        }
+        if let Err(e) = log.append(&remote::gather_all(registries)) {
+            error!("Failed to write metrics to the csv log {}: {}", path, e);
+        }
+    }
+}
+
+fn collection_worker(
+    worker_id: usize,
+    worker_count: usize,
+    reload_barrier: &ReloadBarrier,
+    registry: &prometheus::Registry,
+    collection_errors: &prometheus::IntCounterVec,
+    instance_counts: &prometheus::IntGaugeVec,
+    series_budget: &binding::SeriesBudget,
+    delay_secs: u64,
+    align: bool,
+    conventional_units: bool,
+    host: Option<&remote::RemoteHost>,
+) {
+    debug!(
+        "Setting up counters and prometheus guages for worker {}",
+        worker_id
+    );
+    // Each worker owns its own query since PDH handles can't be shared
+    // across threads.
+    let mut binding = binding::CounterToPrometheus::try_new(registry, instance_counts.clone())
+        .unwrap()
+        .with_conventional_units(conventional_units)
+        .with_series_budget(series_budget);
+    if let Some(host) = host {
+        binding = match binding.with_remote_host(&host.name) {
+            Ok(binding) => binding,
+            Err(e) => {
+                error!("Failed to open a query on {}: {}", host.name, e);
+                return;
+            }
+        };
+    }
+    // A remote host has a worker of its own that collects every group.
+    let share = |groups: &Vec<config::MetricGroup>| match host {
+        Some(_) => groups.clone(),
+        None => worker_share(groups, worker_id, worker_count),
+    };
+    let mut generation = {
+        let guard = METRIC_CONFIG.read().unwrap();
+        register_groups(&mut binding, &share(&guard.groups));
+        guard.generation
+    };
+    info!("Starting collection thread {}", worker_id);
+    run_collections(worker_id, Duration::from_secs(delay_secs), align, || {
+        let current_generation = METRIC_CONFIG.read().unwrap().generation;
+        if current_generation != generation {
+            info!("Reloading metrics for worker {}", worker_id);
+            if let Err(e) = binding.clear() {
+                error!("Failed to clear metrics for worker {}: {}", worker_id, e);
+            }
+            // A metric may move between workers so every worker must finish
+            // unregistering its old gauges before any registers new ones.
+            // They all register the barrier's snapshot of the config so a
+            // reload that lands while they wait is picked up by all of them
+            // on the next collection rather than only some.
+            let (snapshot_generation, groups) = match reload_barrier.wait() {
+                Some(snapshot) => snapshot,
+                None => return false,
+            };
+            register_groups(&mut binding, &share(&groups));
+            generation = snapshot_generation;
+        } else if host.is_some() && binding.counter_count() == 0 {
+            // The remote host was unreachable when we registered so try
+            // again.
+            let groups = share(&METRIC_CONFIG.read().unwrap().groups);
+            register_groups(&mut binding, &groups);
+        }
+        let collect_start = std::time::Instant::now();
+        let result = binding.collect();
+        debug!(
+            "Collection for worker {} took {:?}",
+            worker_id,
+            collect_start.elapsed()
+        );
+        if let Some(host) = host {
+            let down = match result {
+                Ok(ref errors) => errors.iter().any(|(_, s)| *s == PdhError::NoMachine),
+                Err(_) => true,
+            };
+            if down {
+                warn!(
+                    "Unable to collect from {}. Marking its series stale",
+                    host.name
+                );
+                binding.clear_series();
+            }
+            host.up.set(if down { 0.0 } else { 1.0 });
+        }
+        match result {
+            Ok(errors) => {
+                for (path, s) in errors {
+                    let reason = binding::collection_error_reason(s);
+                    logging::log_with_fields(
+                        log::Level::Debug,
+                        module_path!(),
+                        &[
+                            ("worker", &worker_id.to_string()),
+                            ("counter", &path),
+                            ("pdh_status", &pdh_status_friendly_name(s)),
+                            ("reason", reason),
+                        ],
+                        format_args!(
+                            "Counter read failed for worker {}: {}: {}",
+                            worker_id,
+                            path,
+                            pdh_status_friendly_name(s)
+                        ),
+                    );
+                    collection_errors
+                        .with_label_values(&[binding::collection_error_reason(s)])
+                        .inc();
+                }
+            }
+            Err(s) => {
+                logging::log_with_fields(
+                    log::Level::Error,
+                    module_path!(),
+                    &[
+                        ("worker", &worker_id.to_string()),
+                        ("pdh_status", &pdh_status_friendly_name(s)),
+                        ("reason", binding::collection_error_reason(s)),
+                    ],
+                    format_args!(
+                        "Collection failed for worker {}: {}",
+                        worker_id,
+                        pdh_status_friendly_name(s)
+                    ),
+                );
+                collection_errors
+                    .with_label_values(&[binding::collection_error_reason(s)])
+                    .inc();
+            }
+        }
+        true
+    });
+}
+
+/// Runs collect on the collection schedule until we are stopping or
+/// collect returns false. We sleep until a target time rather than for the
+/// interval so the time spent collecting doesn't cause the schedule to
+/// drift. A stop requested during a collection is seen as soon as it
+/// finishes rather than after the next interval.
+fn run_collections<F>(worker_id: usize, interval: Duration, align: bool, mut collect: F)
+where
+    F: FnMut() -> bool,
+{
+    let mut target = first_collection(interval, align);
+    // Stopping is handled at the top of the loop.
+    sleep_until(target);
+    loop {
+        if stopping() || !collect() {
+            info!("Stopping metric collection thread {}.", worker_id);
+            return;
+        }
+        let (next, skipped) = next_collection(target, interval, Instant::now());
+        if skipped > 0 {
+            debug!(
+                "Worker {} fell behind. Skipping {} collections",
+                worker_id, skipped
+            );
+        }
+        target = next;
+        debug!("Sleeping until next collection");
+        sleep_until(target);
+    }
+}
+
 fn win_service_impl<F>(ready_hook: F) -> anyhow::Result<()>
 where
     F: FnOnce() -> anyhow::Result<()>,
@@ -179,129 +721,306 @@ where
     };
     debug!("service_impl args{:?}", argv);
     let registry = prometheus::Registry::new();
+    let collection_errors = prometheus::IntCounterVec::new(
+        prometheus::Opts::new(
+            "pdh_collection_errors_total",
+            "Count of failed performance counter collections by reason",
+        ),
+        &["reason"],
+    )?;
+    registry.register(Box::new(collection_errors.clone()))?;
+    let instance_counts = prometheus::IntGaugeVec::new(
+        prometheus::Opts::new(
+            "win_exporter_instance_count",
+            "Number of instances currently matched by a wildcard counter",
+        ),
+        &["counter"],
+    )?;
+    registry.register(Box::new(instance_counts.clone()))?;
+    #[cfg(feature = "trace")]
+    registry.register(Box::new(pdh_trace::PdhCallCollector::new()?))?;
+    // Processors are only hot added on rare hardware so they are counted once.
+    match Machine::local().logical_processor_count() {
+        Ok(count) => {
+            let cpu_count = prometheus::IntGauge::new(
+                "win_cpu_count",
+                "Number of logical processors on the machine",
+            )?;
+            cpu_count.set(count as i64);
+            registry.register(Box::new(cpu_count))?;
+        }
+        Err(e) => warn!("Failed to count the logical processors: {}", e),
+    }
+    let watched_services = argv.get_vec("--watchService");
+    if !watched_services.is_empty() {
+        registry.register(Box::new(services::ServiceStateCollector::new(
+            watched_services.iter().map(|s| s.to_string()).collect(),
+        )?))?;
+    }
 
     ready_hook()?;
 
     let listen_host = argv.get_str("--listenHost");
-    let delay_secs: u64 = argv.get_count("--delaySecs");
-
-    Ok(thread::scope(|s| {
-        s.spawn(|_| {
-            info!("Starting server on {}", listen_host);
-            let server = tiny_http::Server::http(listen_host).unwrap();
-            loop {
-                {
-                    if *STOP_SIGNAL.read().unwrap() {
-                        info!("Stopping prometheus metric server thread.");
-                        return;
-                    }
-                }
-                debug!("Waiting for request");
-                // NOTE(jwall): We have to not block for longer than the 10 millis to avoid not detecting
-                // the stop signal above.
-                match server.recv_timeout(std::time::Duration::from_millis(10)) {
-                    Ok(Some(req)) => {
-                        info!("Handling request");
+    let delay_secs: u64 = argv.get_str("--delaySecs").parse()?;
+    let align_collection = argv.get_bool("--alignCollection");
+    let conventional_units = argv.get_bool("--conventionalUnits");
+    let serve_http = !argv.get_bool("--noHttp");
+    let statsd_addr = argv.get_str("--statsdAddr");
+    let dogstatsd = argv.get_bool("--dogstatsd");
+    let pipe_name = argv.get_str("--pipeName");
+    let csv_log = argv.get_str("--csvLog");
+    let csv_log_max_mb: u64 = argv.get_str("--csvLogMaxMB").parse()?;
+    if !serve_http && statsd_addr == "" && pipe_name == "" && csv_log == "" {
+        return Err(anyhow::anyhow!(
+            "--noHttp requires --statsdAddr, --pipeName or --csvLog"
+        ));
+    }
+    let collect_workers: usize = argv.get_str("--collectWorkers").parse()?;
+    let series_budget = binding::SeriesBudget::new(argv.get_str("--maxTotalSeries").parse()?);
+    let config_file = argv.get_str("--configFile");
+    let scrape_timeout = Duration::from_secs(argv.get_str("--scrapeTimeout").parse()?);
+    let openmetrics = match argv.get_str("--exposition") {
+        "text" => false,
+        "openmetrics" => true,
+        exposition => {
+            return Err(anyhow::anyhow!(
+                "Invalid --exposition {}. Expected text or openmetrics",
+                exposition
+            ))
+        }
+    };
+    let content_type = if openmetrics {
+        openmetrics::CONTENT_TYPE.to_owned()
+    } else {
+        prometheus::TextEncoder::new().format_type().to_owned()
+    };
+
+    let groups = if config_file != "" {
+        config::load_config(config_file)?
+    } else {
+        config::default_groups()
+    };
+    // Spread the metric groups across our workers. Each group stays in a single
+    // worker so counters that must be sampled together share a query.
+    let worker_count = std::cmp::max(1, std::cmp::min(collect_workers, groups.len()));
+    info!("Using {} collection workers", worker_count);
+    {
+        let mut guard = METRIC_CONFIG.write().unwrap();
+        guard.generation += 1;
+        guard.groups = groups;
+    }
+    let reload_barrier = ReloadBarrier::new(worker_count);
+    let remote_hosts = argv
+        .get_vec("--remoteHost")
+        .iter()
+        .map(|host| remote::RemoteHost::new(host.trim_start_matches('\\')))
+        .collect::<prometheus::Result<Vec<remote::RemoteHost>>>()?;
+    let registries: Vec<&prometheus::Registry> = std::iter::once(&registry)
+        .chain(remote_hosts.iter().map(|host| &host.registry))
+        .collect();
+    let state_path = argv.get_str("--statePath");
+    let restored = state::RestoredState::new(if state_path != "" {
+        match state::load(state_path) {
+            Ok(families) => {
+                info!("Restored {} metrics from {}", families.len(), state_path);
+                families
+            }
+            Err(e) => {
+                info!("Not restoring metrics from {}: {}", state_path, e);
+                Vec::new()
+            }
+        }
+    } else {
+        Vec::new()
+    });
+
+    // Metrics are gathered on their own thread so the server can give up on a
+    // scrape that takes too long. Each request carries the channel its
+    // response should be sent on.
+    // The gather thread exits once every sender has been dropped so we only
+    // keep the ones a server will use.
+    let (gather_sender, gather_receiver) = mpsc::channel::<mpsc::Sender<Vec<u8>>>();
+    let pipe_gather_sender = if pipe_name != "" {
+        Some(gather_sender.clone())
+    } else {
+        None
+    };
+    let http_gather_sender = if serve_http {
+        Some(gather_sender)
+    } else {
+        drop(gather_sender);
+        None
+    };
+
+    thread::scope(|s| {
+        if serve_http || pipe_name != "" {
+            let gather_registries = &registries;
+            let restored = &restored;
+            s.spawn(move |_| {
+                // This exits once the server thread drops the sender.
+                for response_sender in gather_receiver.iter() {
+                    let mut metric_families = remote::gather_all(gather_registries);
+                    restored.merge_into(&mut metric_families);
+                    let buffer = if openmetrics {
+                        openmetrics::encode(&metric_families).into_bytes()
+                    } else {
                         let mut buffer = vec![];
-                        // Gather the metrics.
                         let encoder = prometheus::TextEncoder::new();
-                        let metric_families = registry.gather();
-                        encoder.encode(&metric_families, &mut buffer).unwrap();
-
-                        let response = tiny_http::Response::from_data(buffer).with_status_code(200);
-                        if let Err(e) = req.respond(response) {
-                            error!("Error responding to request {}", e);
+                        if let Err(e) = encoder.encode(&metric_families, &mut buffer) {
+                            error!("Failed to encode metrics: {}", e);
                         }
-                    }
-                    Ok(None) => {
-                        // Receive timed out so noop
-                    }
-                    Err(e) => {
-                        error!("Invalid http request! {}", e);
-                    }
+                        buffer
+                    };
+                    // The server may have already given up on this request.
+                    let _ = response_sender.send(buffer);
                 }
-            }
-        });
-        s.spawn(|_| {
-            debug!("Setting up counters and prometheus guages");
-            let binding = binding::CounterToPrometheus::try_new(&registry).unwrap();
-
-            let pairs = binding
-                .register_pairs(vec![
-                    ("cpu_total_pct", perf_paths::CPU_TOTAL_PCT),
-                    ("cpu_user_pct", perf_paths::CPU_USER_PCT),
-                    ("cpu_idle_pct", perf_paths::CPU_IDLE_PCT),
-                    ("cpu_privileged_pct", perf_paths::CPU_PRIVILEGED_PCT),
-                    ("cpu_priority_pct", perf_paths::CPU_PRIORITY_PCT),
-                    ("cpu_frequency_gauge", perf_paths::CPU_FREQUENCY),
-                    ("mem_available_bytes", perf_paths::MEM_AVAILABLE_BYTES),
-                    ("mem_cache_bytes", perf_paths::MEM_CACHE_BYTES),
-                    ("mem_committed_bytes", perf_paths::MEM_COMMITTED_BYTES),
-                    ("disk_pct_read_time", perf_paths::DISK_PCT_READ_TIME),
-                    ("disk_pct_write_time", perf_paths::DISK_PCT_WRITE_TIME),
-                    ("disk_read_bytes_sec", perf_paths::DISK_READ_BYTES_SEC),
-                    ("disk_write_bytes_sec", perf_paths::DISK_WRITE_BYTES_SEC),
-                    ("sys_processes_count", perf_paths::SYS_PROCESSES_COUNT),
-                    ("sys_threads_count", perf_paths::SYS_THREADS_COUNT),
-                    ("sys_context_switch_sec", perf_paths::SYS_CONTEXT_SWITCH_SEC),
-                    ("sys_system_calls_sec", perf_paths::SYS_SYSTEM_CALLS_SEC),
-                ])
-                .unwrap();
-
-            let labled_pairs = binding
-                .register_wildcard_pairs(vec![
-                    (
-                        "network_ifc_bytes_rcvd_sec",
-                        perf_paths::NET_IFC_BYTES_RCVD_SEC,
-                    ),
-                    (
-                        "network_ifc_bytes_sent_sec",
-                        perf_paths::NET_IFC_BYTES_SENT_SEC,
-                    ),
-                    (
-                        "network_ifc_pkts_rcvd_sec",
-                        perf_paths::NET_IFC_PKTS_RCVD_SEC,
-                    ),
-                    (
-                        "network_ifc_pkts_sent_sec",
-                        perf_paths::NET_IFC_PKTS_SENT_SEC,
-                    ),
-                    (
-                        "network_ifc_pkts_rcvd_err",
-                        perf_paths::NET_IFC_PKTS_RCVD_ERR,
-                    ),
-                    (
-                        "network_ifc_bytes_rcvd_discard",
-                        perf_paths::NET_IFC_PKTS_RCVD_DISCARD,
-                    ),
-                ])
-                .unwrap();
-            info!("Starting collection thread");
-            loop {
-                {
-                    if *STOP_SIGNAL.read().unwrap() {
-                        info!("Stopping metric collection thread.");
-                        return;
+            });
+        }
+        if let Some(gather_sender) = pipe_gather_sender {
+            s.spawn(move |_| {
+                let gather = || {
+                    let (response_sender, response_receiver) = mpsc::channel();
+                    gather_sender.send(response_sender).ok()?;
+                    match response_receiver.recv_timeout(scrape_timeout) {
+                        Ok(buffer) => Some(buffer),
+                        Err(e) => {
+                            error!(
+                                "Failed to gather metrics within {:?}: {}",
+                                scrape_timeout, e
+                            );
+                            None
+                        }
                     }
+                };
+                if let Err(e) = pipe::serve(pipe_name, gather, STOP_POLL_INTERVAL, stopping) {
+                    error!(
+                        "Failed to serve metrics on {}: {}",
+                        pipe::pipe_path(pipe_name),
+                        e
+                    );
                 }
-                for (_, metric, stream) in pairs.iter() {
-                    if let Ok(v) = stream.next() {
-                        metric.with(&prometheus::labels! {}).set(v);
+            });
+        }
+        if let Some(gather_sender) = http_gather_sender {
+            s.spawn(move |_| {
+                info!("Starting server on {}", listen_host);
+                let server = tiny_http::Server::http(listen_host).unwrap();
+                loop {
+                    {
+                        if *STOP_SIGNAL.read().unwrap() {
+                            info!("Stopping prometheus metric server thread.");
+                            return;
+                        }
                     }
-                }
-                for (_, metric, (label_name, label_value), stream) in labled_pairs.iter() {
-                    if let Ok(v) = stream.next() {
-                        let mut labels = std::collections::HashMap::with_capacity(1);
-                        labels.insert(*label_name, label_value.as_str());
-                        metric.with(&labels).set(v);
+                    debug!("Waiting for request");
+                    // NOTE(jwall): We have to not block for longer than the 10 millis to avoid not detecting
+                    // the stop signal above.
+                    match server.recv_timeout(std::time::Duration::from_millis(10)) {
+                        Ok(Some(req)) => {
+                            info!("Handling request");
+                            let (response_sender, response_receiver) = mpsc::channel();
+                            // Gather the metrics.
+                            let response = match gather_sender
+                                .send(response_sender)
+                                .map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+                                .and_then(|_| response_receiver.recv_timeout(scrape_timeout))
+                            {
+                                Ok(buffer) => tiny_http::Response::from_data(buffer)
+                                    .with_status_code(200)
+                                    .with_header(
+                                        tiny_http::Header::from_bytes(
+                                            &b"Content-Type"[..],
+                                            content_type.as_bytes(),
+                                        )
+                                        .unwrap(),
+                                    ),
+                                Err(e) => {
+                                    error!(
+                                        "Failed to gather metrics within {:?}: {}",
+                                        scrape_timeout, e
+                                    );
+                                    tiny_http::Response::from_data(Vec::new()).with_status_code(503)
+                                }
+                            };
+                            if let Err(e) = req.respond(response) {
+                                error!("Error responding to request {}", e);
+                            }
+                        }
+                        Ok(None) => {
+                            // Receive timed out so noop
+                        }
+                        Err(e) => {
+                            error!("Invalid http request! {}", e);
+                        }
                     }
                 }
-                debug!("Sleeping until next collection");
-                std::thread::sleep(std::time::Duration::from_secs(delay_secs));
-            }
-        });
+            });
+        }
+        if statsd_addr != "" {
+            let statsd_registries = &registries;
+            s.spawn(move |_| statsd_pusher(statsd_addr, dogstatsd, statsd_registries, delay_secs));
+        }
+        if csv_log != "" {
+            let csv_registries = &registries;
+            s.spawn(move |_| csv_logger(csv_log, csv_log_max_mb, csv_registries, delay_secs));
+        }
+        if config_file != "" {
+            s.spawn(|_| config_watcher(config_file));
+        }
+        for worker_id in 0..worker_count {
+            let registry = &registry;
+            let collection_errors = &collection_errors;
+            let instance_counts = &instance_counts;
+            let series_budget = &series_budget;
+            let reload_barrier = &reload_barrier;
+            s.spawn(move |_| {
+                collection_worker(
+                    worker_id,
+                    worker_count,
+                    reload_barrier,
+                    registry,
+                    collection_errors,
+                    instance_counts,
+                    series_budget,
+                    delay_secs,
+                    align_collection,
+                    conventional_units,
+                    None,
+                )
+            });
+        }
+        for (idx, host) in remote_hosts.iter().enumerate() {
+            let series_budget = &series_budget;
+            s.spawn(move |_| {
+                let reload_barrier = ReloadBarrier::new(1);
+                collection_worker(
+                    worker_count + idx,
+                    1,
+                    &reload_barrier,
+                    &host.registry,
+                    &host.collection_errors,
+                    &host.instance_counts,
+                    series_budget,
+                    delay_secs,
+                    align_collection,
+                    conventional_units,
+                    Some(host),
+                )
+            });
+        }
     })
-    .unwrap())
+    .unwrap();
+    if state_path != "" {
+        // Values restored at startup that were never replaced are saved
+        // again.
+        let mut metric_families = remote::gather_all(&registries);
+        restored.merge_into(&mut metric_families);
+        match state::save(state_path, &metric_families) {
+            Ok(()) => info!("Saved metrics to {}", state_path),
+            Err(e) => error!("Failed to save metrics to {}: {}", state_path, e),
+        }
+    }
+    Ok(())
 }
 
 windows_service::define_windows_service!(ffi_service_main, win_service_main);
@@ -321,12 +1040,83 @@ fn flags_from_argmap(argv: &docopt::ArgvMap) -> Vec<OsString> {
         args.push("--delaySecs".into());
         args.push(secs.into());
     }
+    if argv.get_bool("--alignCollection") {
+        args.push("--alignCollection".into());
+    }
+    if argv.get_bool("--conventionalUnits") {
+        args.push("--conventionalUnits".into());
+    }
+    let config_file = argv.get_str("--configFile");
+    if config_file != "" {
+        args.push("--configFile".into());
+        args.push(config_file.into());
+    }
+    let max_series = argv.get_str("--maxTotalSeries");
+    if max_series != "" {
+        args.push("--maxTotalSeries".into());
+        args.push(max_series.into());
+    }
+    let workers = argv.get_str("--collectWorkers");
+    if workers != "" {
+        args.push("--collectWorkers".into());
+        args.push(workers.into());
+    }
+    if argv.get_bool("--noHttp") {
+        args.push("--noHttp".into());
+    }
+    let statsd_addr = argv.get_str("--statsdAddr");
+    if statsd_addr != "" {
+        args.push("--statsdAddr".into());
+        args.push(statsd_addr.into());
+    }
+    if argv.get_bool("--dogstatsd") {
+        args.push("--dogstatsd".into());
+    }
+    let pipe_name = argv.get_str("--pipeName");
+    if pipe_name != "" {
+        args.push("--pipeName".into());
+        args.push(pipe_name.into());
+    }
+    for host in argv.get_vec("--remoteHost") {
+        args.push("--remoteHost".into());
+        args.push(host.into());
+    }
+    for service in argv.get_vec("--watchService") {
+        args.push("--watchService".into());
+        args.push(service.into());
+    }
+    let exposition = argv.get_str("--exposition");
+    if exposition != "" {
+        args.push("--exposition".into());
+        args.push(exposition.into());
+    }
+    let csv_log = argv.get_str("--csvLog");
+    if csv_log != "" {
+        args.push("--csvLog".into());
+        args.push(csv_log.into());
+    }
+    let csv_log_max_mb = argv.get_str("--csvLogMaxMB");
+    if csv_log_max_mb != "" {
+        args.push("--csvLogMaxMB".into());
+        args.push(csv_log_max_mb.into());
+    }
+    let state_path = argv.get_str("--statePath");
+    if state_path != "" {
+        args.push("--statePath".into());
+        args.push(state_path.into());
+    }
+    let scrape_timeout = argv.get_str("--scrapeTimeout");
+    if scrape_timeout != "" {
+        args.push("--scrapeTimeout".into());
+        args.push(scrape_timeout.into());
+    }
     return args;
 }
 
 fn main() -> anyhow::Result<()> {
     let docopt = flags();
-    let argv = docopt.parse().unwrap_or_else(|e| e.exit());
+    let args = merge_env_args(env::args().collect(), |name| env::var(name).ok());
+    let argv = docopt.argv(args).parse().unwrap_or_else(|e| e.exit());
 
     {
         // Ensure this is scoped very tightly
@@ -336,8 +1126,10 @@ fn main() -> anyhow::Result<()> {
 
     init_log(&argv).unwrap();
     if argv.get_bool("--install") {
-        let manager =
-            ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
+        let manager = ServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        )?;
 
         let my_service_info = ServiceInfo {
             name: OsString::from(SERVICENAME),
@@ -354,7 +1146,19 @@ fn main() -> anyhow::Result<()> {
             account_password: None,
         };
 
-        manager.create_service(&my_service_info, ServiceAccess::QUERY_STATUS)?;
+        // Update the service in place if it's already installed so changing
+        // the flags doesn't require removing it first.
+        match manager.open_service(SERVICENAME, ServiceAccess::CHANGE_CONFIG) {
+            Ok(service) => {
+                service.change_config(&my_service_info)?;
+                println!("Updated existing service {}", SERVICENAME);
+            }
+            Err(_) => {
+                manager.create_service(&my_service_info, ServiceAccess::QUERY_STATUS)?;
+                println!("Created service {}", SERVICENAME);
+            }
+        }
+        // Registering again is harmless and picks up a moved executable.
         eventlog::register(LOGNAME)?;
     } else if argv.get_bool("--remove") {
         let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::all())?;
@@ -368,3 +1172,141 @@ fn main() -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn env_var_names_split_camel_case_and_dashes() {
+        assert_eq!(env_var_name("--delaySecs"), "WIN_EXPORTER_DELAY_SECS");
+        assert_eq!(env_var_name("--no-service"), "WIN_EXPORTER_NO_SERVICE");
+        assert_eq!(env_var_name("--debug"), "WIN_EXPORTER_DEBUG");
+    }
+
+    #[test]
+    fn env_sets_options_the_args_dont() {
+        let vars = &[
+            ("WIN_EXPORTER_DELAY_SECS", "30"),
+            ("WIN_EXPORTER_LISTEN_HOST", "127.0.0.1:9100"),
+            ("WIN_EXPORTER_DEBUG", "1"),
+        ];
+        let merged = merge_env_args(
+            args(&["exporter", "--listenHost=0.0.0.0:8080"]),
+            lookup(vars),
+        );
+        assert_eq!(
+            merged,
+            args(&[
+                "exporter",
+                "--listenHost=0.0.0.0:8080",
+                "--delaySecs=30",
+                "--debug"
+            ])
+        );
+    }
+
+    #[test]
+    fn false_env_flags_and_command_line_only_options_are_ignored() {
+        let vars = &[
+            ("WIN_EXPORTER_DEBUG", "false"),
+            ("WIN_EXPORTER_ALIGN_COLLECTION", "0"),
+            ("WIN_EXPORTER_DOGSTATSD", ""),
+            ("WIN_EXPORTER_INSTALL", "1"),
+            ("WIN_EXPORTER_REMOVE", "true"),
+        ];
+        let merged = merge_env_args(args(&["exporter"]), lookup(vars));
+        assert_eq!(merged, args(&["exporter"]));
+    }
+
+    #[test]
+    fn merged_args_parse() {
+        let vars = &[
+            ("WIN_EXPORTER_DELAY_SECS", "30"),
+            ("WIN_EXPORTER_NO_SERVICE", "yes"),
+        ];
+        let argv = flags()
+            .argv(merge_env_args(args(&["exporter"]), lookup(vars)))
+            .parse()
+            .unwrap();
+        assert_eq!(argv.get_str("--delaySecs"), "30");
+        assert!(argv.get_bool("--no-service"));
+    }
+
+    /// Stands in for a query that takes a while to collect.
+    #[derive(Default)]
+    struct MockQuery {
+        collections: usize,
+    }
+
+    impl MockQuery {
+        fn collect(&mut self) {
+            std::thread::sleep(Duration::from_millis(10));
+            self.collections += 1;
+        }
+    }
+
+    // Everything that reads the STOP_SIGNAL is tested in one test since
+    // setting it affects every thread.
+    #[test]
+    fn waits_give_up_once_stopping() {
+        // Every worker arriving releases them all with the same config.
+        let barrier = ReloadBarrier::new(3);
+        let generation = METRIC_CONFIG.read().unwrap().generation;
+        thread::scope(|s| {
+            let waiters = (0..3)
+                .map(|_| s.spawn(|_| barrier.wait()))
+                .collect::<Vec<_>>();
+            for waiter in waiters {
+                assert_eq!(waiter.join().unwrap().map(|c| c.0), Some(generation));
+            }
+        })
+        .unwrap();
+        let start = Instant::now();
+        assert!(sleep_until(start + Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        *STOP_SIGNAL.write().unwrap() = true;
+        let start = Instant::now();
+        assert!(!sleep_until(start + Duration::from_secs(3600)));
+        // A worker that stopped never arrives so the rest give up waiting.
+        assert!(ReloadBarrier::new(2).wait().is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        *STOP_SIGNAL.write().unwrap() = false;
+
+        // A stop requested while a worker is collecting is seen as soon as
+        // the collection finishes even with an hour until the next one.
+        let mut query = MockQuery::default();
+        let start = Instant::now();
+        run_collections(0, Duration::from_secs(3600), false, || {
+            query.collect();
+            *STOP_SIGNAL.write().unwrap() = true;
+            // The collection carries on after the stop was requested.
+            query.collect();
+            true
+        });
+        assert_eq!(query.collections, 2);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        *STOP_SIGNAL.write().unwrap() = false;
+
+        // A worker that stops itself isn't run again.
+        let mut query = MockQuery::default();
+        run_collections(0, Duration::from_millis(1), false, || {
+            query.collect();
+            query.collections < 3
+        });
+        assert_eq!(query.collections, 3);
+    }
+}
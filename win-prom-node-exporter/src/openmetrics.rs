@@ -0,0 +1,276 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encodes gathered metrics in the OpenMetrics text exposition format.
+use std::fmt::Write;
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+
+pub const CONTENT_TYPE: &'static str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// The base units we recognize on the end of a metric name. OpenMetrics
+/// requires a family's unit to be the suffix of its name.
+const UNITS: &'static [&'static str] = &[
+    "seconds", "bytes", "ratio", "celsius", "volts", "amperes", "joules", "grams", "meters",
+];
+
+/// Returns the unit a family name ends with if it is one we recognize.
+fn unit(name: &str) -> Option<&'static str> {
+    UNITS
+        .iter()
+        .find(|u| name.ends_with(&format!("_{}", u)))
+        .cloned()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_value(v: f64) -> String {
+    if v == std::f64::INFINITY {
+        "+Inf".to_owned()
+    } else if v == std::f64::NEG_INFINITY {
+        "-Inf".to_owned()
+    } else if v.is_nan() {
+        "NaN".to_owned()
+    } else {
+        format!("{}", v)
+    }
+}
+
+/// Formats a sample's timestamp as the space separated seconds since the
+/// epoch that follow its value or nothing if it has none.
+fn format_timestamp(metric: &Metric) -> String {
+    match metric.get_timestamp_ms() {
+        0 => String::new(),
+        ms => format!(" {}.{:03}", ms.div_euclid(1000), ms.rem_euclid(1000)),
+    }
+}
+
+/// Formats the `{name="value",...}` label set for a sample including any
+/// extra label such as a bucket's `le`.
+fn format_labels(labels: &[LabelPair], extra: Option<(&str, String)>) -> String {
+    let mut pairs = labels
+        .iter()
+        .map(|l| format!("{}=\"{}\"", l.get_name(), escape(l.get_value())))
+        .collect::<Vec<String>>();
+    if let Some((name, value)) = extra {
+        pairs.push(format!("{}=\"{}\"", name, escape(&value)));
+    }
+    if pairs.is_empty() {
+        return String::new();
+    }
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Encodes the metric families as OpenMetrics text. Counters are reported
+/// under a family name without the `_total` suffix and samples with it as
+/// the format requires. Families whose name ends in a base unit such as
+/// `_seconds` or `_bytes` get a `# UNIT` line.
+pub fn encode(families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let (name, type_name) = match family.get_field_type() {
+            MetricType::COUNTER => (
+                family
+                    .get_name()
+                    .strip_suffix("_total")
+                    .unwrap_or(family.get_name()),
+                "counter",
+            ),
+            MetricType::GAUGE => (family.get_name(), "gauge"),
+            MetricType::HISTOGRAM => (family.get_name(), "histogram"),
+            MetricType::SUMMARY => (family.get_name(), "summary"),
+            MetricType::UNTYPED => (family.get_name(), "unknown"),
+        };
+        let _ = writeln!(out, "# TYPE {} {}", name, type_name);
+        if let Some(unit) = unit(name) {
+            let _ = writeln!(out, "# UNIT {} {}", name, unit);
+        }
+        let _ = writeln!(out, "# HELP {} {}", name, escape(family.get_help()));
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    let _ = writeln!(
+                        out,
+                        "{}_total{} {}{}",
+                        name,
+                        format_labels(labels, None),
+                        format_value(metric.get_counter().get_value()),
+                        format_timestamp(metric)
+                    );
+                }
+                MetricType::GAUGE => {
+                    let _ = writeln!(
+                        out,
+                        "{}{} {}{}",
+                        name,
+                        format_labels(labels, None),
+                        format_value(metric.get_gauge().get_value()),
+                        format_timestamp(metric)
+                    );
+                }
+                MetricType::UNTYPED => {
+                    let _ = writeln!(
+                        out,
+                        "{}{} {}{}",
+                        name,
+                        format_labels(labels, None),
+                        format_value(metric.get_untyped().get_value()),
+                        format_timestamp(metric)
+                    );
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let mut has_inf = false;
+                    for bucket in histogram.get_bucket() {
+                        has_inf |= bucket.get_upper_bound() == std::f64::INFINITY;
+                        let _ = writeln!(
+                            out,
+                            "{}_bucket{} {}",
+                            name,
+                            format_labels(
+                                labels,
+                                Some(("le", format_value(bucket.get_upper_bound())))
+                            ),
+                            bucket.get_cumulative_count()
+                        );
+                    }
+                    // OpenMetrics requires a +Inf bucket.
+                    if !has_inf {
+                        let _ = writeln!(
+                            out,
+                            "{}_bucket{} {}",
+                            name,
+                            format_labels(labels, Some(("le", "+Inf".to_owned()))),
+                            histogram.get_sample_count()
+                        );
+                    }
+                    let _ = writeln!(
+                        out,
+                        "{}_count{} {}",
+                        name,
+                        format_labels(labels, None),
+                        histogram.get_sample_count()
+                    );
+                    let _ = writeln!(
+                        out,
+                        "{}_sum{} {}",
+                        name,
+                        format_labels(labels, None),
+                        format_value(histogram.get_sample_sum())
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let _ = writeln!(
+                            out,
+                            "{}{} {}",
+                            name,
+                            format_labels(
+                                labels,
+                                Some(("quantile", format_value(quantile.get_quantile())))
+                            ),
+                            format_value(quantile.get_value())
+                        );
+                    }
+                    let _ = writeln!(
+                        out,
+                        "{}_count{} {}",
+                        name,
+                        format_labels(labels, None),
+                        summary.get_sample_count()
+                    );
+                    let _ = writeln!(
+                        out,
+                        "{}_sum{} {}",
+                        name,
+                        format_labels(labels, None),
+                        format_value(summary.get_sample_sum())
+                    );
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::proto::{Counter, Gauge};
+
+    fn family(name: &str, field_type: MetricType, value: f64) -> MetricFamily {
+        let mut metric = Metric::default();
+        match field_type {
+            MetricType::COUNTER => {
+                let mut counter = Counter::default();
+                counter.set_value(value);
+                metric.set_counter(counter);
+            }
+            _ => {
+                let mut gauge = Gauge::default();
+                gauge.set_value(value);
+                metric.set_gauge(gauge);
+            }
+        }
+        let mut family = MetricFamily::default();
+        family.set_name(name.to_owned());
+        family.set_help("help".to_owned());
+        family.set_field_type(field_type);
+        family.mut_metric().push(metric);
+        family
+    }
+
+    #[test]
+    fn counters_drop_one_total_suffix() {
+        let out = encode(&[family("requests_total_total", MetricType::COUNTER, 3.0)]);
+        assert_eq!(
+            out,
+            "# TYPE requests_total counter\n\
+             # HELP requests_total help\n\
+             requests_total_total 3\n\
+             # EOF\n"
+        );
+    }
+
+    #[test]
+    fn units_are_reported_from_the_name() {
+        let out = encode(&[
+            family("cpu_seconds_total", MetricType::COUNTER, 1.5),
+            family("mem_available_bytes", MetricType::GAUGE, 1024.0),
+            family("disk_queue_length", MetricType::GAUGE, 2.0),
+        ]);
+        assert_eq!(
+            out,
+            "# TYPE cpu_seconds counter\n\
+             # UNIT cpu_seconds seconds\n\
+             # HELP cpu_seconds help\n\
+             cpu_seconds_total 1.5\n\
+             # TYPE mem_available_bytes gauge\n\
+             # UNIT mem_available_bytes bytes\n\
+             # HELP mem_available_bytes help\n\
+             mem_available_bytes 1024\n\
+             # TYPE disk_queue_length gauge\n\
+             # HELP disk_queue_length help\n\
+             disk_queue_length 2\n\
+             # EOF\n"
+        );
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Exposes the durations of PDH calls recorded by the perf wrapper's
+//! `trace` feature as a prometheus histogram.
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{Bucket, Histogram, LabelPair, Metric, MetricFamily, MetricType};
+use winapi_perf_wrapper::trace::{pdh_metrics, BUCKETS};
+
+const NAME: &'static str = "win_exporter_pdh_call_duration_seconds";
+const HELP: &'static str = "Duration of PDH calls made by the exporter";
+
+/// Collects the PDH call durations each time the registry is gathered.
+pub struct PdhCallCollector {
+    desc: Desc,
+}
+
+impl PdhCallCollector {
+    pub fn new() -> prometheus::Result<Self> {
+        Ok(Self {
+            desc: Desc::new(
+                NAME.to_owned(),
+                HELP.to_owned(),
+                vec!["call".to_owned()],
+                std::collections::HashMap::new(),
+            )?,
+        })
+    }
+}
+
+impl Collector for PdhCallCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut family = MetricFamily::default();
+        family.set_name(NAME.to_owned());
+        family.set_help(HELP.to_owned());
+        family.set_field_type(MetricType::HISTOGRAM);
+        let metrics = pdh_metrics();
+        for (call, times) in metrics.calls() {
+            let mut histogram = Histogram::default();
+            histogram.set_sample_count(times.count);
+            histogram.set_sample_sum(times.sum_seconds);
+            for (upper_bound, count) in BUCKETS.iter().zip(times.counts.iter()) {
+                let mut bucket = Bucket::default();
+                bucket.set_upper_bound(*upper_bound);
+                bucket.set_cumulative_count(*count);
+                histogram.mut_bucket().push(bucket);
+            }
+            let mut label = LabelPair::default();
+            label.set_name("call".to_owned());
+            label.set_value(call.name().to_owned());
+            let mut metric = Metric::default();
+            metric.mut_label().push(label);
+            metric.set_histogram(histogram);
+            family.mut_metric().push(metric);
+        }
+        vec![family]
+    }
+}
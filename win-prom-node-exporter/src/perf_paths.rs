@@ -47,3 +47,10 @@ pub const SYS_PROCESSES_COUNT: &'static str = r"\System\Processes"; // Count
 pub const SYS_THREADS_COUNT: &'static str = r"\System\Threads"; // Count
 pub const SYS_CONTEXT_SWITCH_SEC: &'static str = r"\System\Context Switches/sec";
 pub const SYS_SYSTEM_CALLS_SEC: &'static str = r"\System\System Calls/sec";
+pub const SYS_UP_TIME: &'static str = r"\System\System Up Time";
+
+// Thread statistics. Instances are named `process/thread_index` and are
+// reported as separate process and thread labels. There is a series per
+// thread on the machine so these are not collected by default.
+#[allow(dead_code)]
+pub const THREAD_PROCESSOR_PCT: &'static str = r"\Thread(*)\% Processor Time";
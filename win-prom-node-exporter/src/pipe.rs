@@ -0,0 +1,229 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serves the metrics over a local named pipe for hosts that forbid
+//! listening sockets. Each client that connects to `\\.\pipe\<name>` is
+//! sent the current exposition and then disconnected. Remote clients are
+//! rejected.
+use std::io;
+use std::ptr::null_mut;
+use std::time::Duration;
+
+use log::{debug, error, info};
+use winapi::shared::minwindef::{DWORD, FALSE, TRUE};
+use winapi::shared::winerror::{ERROR_IO_PENDING, ERROR_PIPE_CONNECTED, WAIT_TIMEOUT};
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::fileapi::{FlushFileBuffers, WriteFile};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::ioapiset::{CancelIo, GetOverlappedResult};
+use winapi::um::minwinbase::OVERLAPPED;
+use winapi::um::namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe};
+use winapi::um::synchapi::{CreateEventW, WaitForSingleObject};
+use winapi::um::winbase::{
+    FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED, PIPE_ACCESS_OUTBOUND,
+    PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_WAIT, WAIT_OBJECT_0,
+};
+use winapi::um::winnt::HANDLE;
+
+/// The size of the pipe's output buffer. Bigger expositions are written as
+/// the client reads them.
+const OUT_BUFFER_SIZE: DWORD = 64 * 1024;
+
+/// The full path of the pipe with the given name.
+pub fn pipe_path(name: &str) -> String {
+    format!(r"\\.\pipe\{}", name)
+}
+
+/// A handle that is closed when dropped.
+struct OwnedHandle(HANDLE);
+
+impl Drop for OwnedHandle {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.0);
+        }
+    }
+}
+
+/// Waits for an overlapped operation on the pipe to finish checking
+/// stopping every poll_interval. Returns the number of bytes transferred or
+/// None if we stopped first in which case the operation is cancelled.
+fn wait_overlapped<S>(
+    pipe: &OwnedHandle,
+    overlapped: &mut OVERLAPPED,
+    poll_interval: Duration,
+    stopping: &S,
+) -> io::Result<Option<DWORD>>
+where
+    S: Fn() -> bool,
+{
+    let mut transferred: DWORD = 0;
+    loop {
+        match unsafe { WaitForSingleObject(overlapped.hEvent, poll_interval.as_millis() as DWORD) }
+        {
+            WAIT_OBJECT_0 => {
+                if unsafe { GetOverlappedResult(pipe.0, overlapped, &mut transferred, FALSE) } == 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                return Ok(Some(transferred));
+            }
+            WAIT_TIMEOUT if stopping() => {
+                unsafe {
+                    CancelIo(pipe.0);
+                    // The overlapped struct must outlive the operation so
+                    // wait for the cancellation to finish.
+                    GetOverlappedResult(pipe.0, overlapped, &mut transferred, TRUE);
+                }
+                return Ok(None);
+            }
+            WAIT_TIMEOUT => {}
+            _ => return Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+/// A fresh OVERLAPPED signaling event when its operation finishes.
+fn overlapped_for(event: &OwnedHandle) -> OVERLAPPED {
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    overlapped.hEvent = event.0;
+    overlapped
+}
+
+/// Waits for a client to connect. Returns false if we stopped first.
+fn wait_for_client<S>(
+    pipe: &OwnedHandle,
+    event: &OwnedHandle,
+    poll_interval: Duration,
+    stopping: &S,
+) -> io::Result<bool>
+where
+    S: Fn() -> bool,
+{
+    let mut overlapped = overlapped_for(event);
+    if unsafe { ConnectNamedPipe(pipe.0, &mut overlapped) } == 0 {
+        match unsafe { GetLastError() } {
+            // The client connected before we started waiting.
+            ERROR_PIPE_CONNECTED => return Ok(true),
+            ERROR_IO_PENDING => {}
+            e => return Err(io::Error::from_raw_os_error(e as i32)),
+        }
+    }
+    Ok(wait_overlapped(pipe, &mut overlapped, poll_interval, stopping)?.is_some())
+}
+
+/// Writes all of buffer to the connected client. Returns false if we
+/// stopped first.
+fn write_all<S>(
+    pipe: &OwnedHandle,
+    event: &OwnedHandle,
+    buffer: &[u8],
+    poll_interval: Duration,
+    stopping: &S,
+) -> io::Result<bool>
+where
+    S: Fn() -> bool,
+{
+    let mut written = 0;
+    while written < buffer.len() {
+        let chunk = &buffer[written..];
+        let len = std::cmp::min(chunk.len(), DWORD::max_value() as usize) as DWORD;
+        let mut overlapped = overlapped_for(event);
+        if unsafe {
+            WriteFile(
+                pipe.0,
+                chunk.as_ptr() as _,
+                len,
+                null_mut(),
+                &mut overlapped,
+            )
+        } == 0
+        {
+            let e = unsafe { GetLastError() };
+            if e != ERROR_IO_PENDING {
+                return Err(io::Error::from_raw_os_error(e as i32));
+            }
+        }
+        match wait_overlapped(pipe, &mut overlapped, poll_interval, stopping)? {
+            Some(n) => written += n as usize,
+            None => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+/// Serves the exposition returned by gather to each client that connects
+/// to the named pipe one at a time until stopping returns true. A client
+/// is disconnected without any data if gather returns None.
+pub fn serve<F, S>(name: &str, gather: F, poll_interval: Duration, stopping: S) -> io::Result<()>
+where
+    F: Fn() -> Option<Vec<u8>>,
+    S: Fn() -> bool,
+{
+    let path = pipe_path(name);
+    let mut wide_path: Vec<u16> = path.encode_utf16().collect();
+    wide_path.push(0);
+    // We only ever create one instance so refusing to be anything but the
+    // first keeps another process from serving the pipe in our place.
+    let pipe = unsafe {
+        CreateNamedPipeW(
+            wide_path.as_ptr(),
+            PIPE_ACCESS_OUTBOUND | FILE_FLAG_OVERLAPPED | FILE_FLAG_FIRST_PIPE_INSTANCE,
+            PIPE_TYPE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            1,
+            OUT_BUFFER_SIZE,
+            0,
+            0,
+            null_mut(),
+        )
+    };
+    if pipe == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    let pipe = OwnedHandle(pipe);
+    let event = unsafe { CreateEventW(null_mut(), TRUE, FALSE, null_mut()) };
+    if event.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let event = OwnedHandle(event);
+    info!("Serving metrics on {}", path);
+    loop {
+        if !wait_for_client(&pipe, &event, poll_interval, &stopping)? {
+            info!("Stopping named pipe server.");
+            return Ok(());
+        }
+        debug!("Handling pipe client");
+        let finished = match gather() {
+            Some(buffer) => match write_all(&pipe, &event, &buffer, poll_interval, &stopping) {
+                Ok(finished) => finished,
+                Err(e) => {
+                    error!("Error writing metrics to {}: {}", path, e);
+                    true
+                }
+            },
+            None => true,
+        };
+        unsafe {
+            if finished {
+                // Let the client read everything before we disconnect it.
+                FlushFileBuffers(pipe.0);
+            }
+            DisconnectNamedPipe(pipe.0);
+        }
+        if !finished {
+            info!("Stopping named pipe server.");
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Collecting from remote machines. Each remote host's metrics are registered
+//! in a registry of their own that labels every series with the host. The
+//! registries are merged with the local one when the metrics are gathered.
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+
+use prometheus::proto::MetricFamily;
+use prometheus::{Gauge, IntCounterVec, IntGaugeVec, Opts, Registry};
+
+/// A remote machine we collect from and the metrics registered for it.
+pub struct RemoteHost {
+    pub name: String,
+    pub registry: Registry,
+    pub collection_errors: IntCounterVec,
+    pub instance_counts: IntGaugeVec,
+    /// 1 while the host's last collection succeeded and 0 otherwise.
+    pub up: Gauge,
+}
+
+impl RemoteHost {
+    pub fn new(name: &str) -> prometheus::Result<Self> {
+        let mut labels = HashMap::new();
+        labels.insert("host".to_owned(), name.to_owned());
+        let registry = Registry::new_custom(None, Some(labels))?;
+        let collection_errors = IntCounterVec::new(
+            Opts::new(
+                "pdh_collection_errors_total",
+                "Count of failed performance counter collections by reason",
+            ),
+            &["reason"],
+        )?;
+        registry.register(Box::new(collection_errors.clone()))?;
+        let instance_counts = IntGaugeVec::new(
+            Opts::new(
+                "win_exporter_instance_count",
+                "Number of instances currently matched by a wildcard counter",
+            ),
+            &["counter"],
+        )?;
+        registry.register(Box::new(instance_counts.clone()))?;
+        let up = Gauge::new(
+            "win_exporter_remote_host_up",
+            "Whether the last collection from a remote host succeeded",
+        )?;
+        registry.register(Box::new(up.clone()))?;
+        Ok(Self {
+            name: name.to_owned(),
+            registry: registry,
+            collection_errors: collection_errors,
+            instance_counts: instance_counts,
+            up: up,
+        })
+    }
+}
+
+/// Gathers every registry merging the families that share a name so each
+/// metric is exposed once with the series from every host.
+pub fn gather_all(registries: &[&Registry]) -> Vec<MetricFamily> {
+    let mut families: BTreeMap<String, MetricFamily> = BTreeMap::new();
+    for registry in registries {
+        for mut family in registry.gather() {
+            match families.entry(family.get_name().to_owned()) {
+                Entry::Occupied(mut entry) => {
+                    for metric in family.take_metric().into_iter() {
+                        entry.get_mut().mut_metric().push(metric);
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(family);
+                }
+            }
+        }
+    }
+    families.into_iter().map(|(_, family)| family).collect()
+}
@@ -0,0 +1,143 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Exposes the state of watched windows services as a prometheus gauge.
+use log::{debug, warn};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{Gauge, LabelPair, Metric, MetricFamily, MetricType};
+use winapi::shared::winerror::ERROR_SERVICE_DOES_NOT_EXIST;
+use windows_service::service::{ServiceAccess, ServiceState};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+const NAME: &'static str = "win_service_state";
+const HELP: &'static str =
+    "State of a watched windows service. 1 for the current state, 0 otherwise";
+
+/// The state reported for a service that isn't installed.
+const ABSENT: &'static str = "absent";
+
+/// Every state label in the order they are reported.
+const STATES: &'static [&'static str] = &[
+    "stopped",
+    "start_pending",
+    "stop_pending",
+    "running",
+    "continue_pending",
+    "pause_pending",
+    "paused",
+    ABSENT,
+];
+
+fn state_label(state: ServiceState) -> &'static str {
+    match state {
+        ServiceState::Stopped => "stopped",
+        ServiceState::StartPending => "start_pending",
+        ServiceState::StopPending => "stop_pending",
+        ServiceState::Running => "running",
+        ServiceState::ContinuePending => "continue_pending",
+        ServiceState::PausePending => "pause_pending",
+        ServiceState::Paused => "paused",
+    }
+}
+
+fn label(name: &str, value: &str) -> LabelPair {
+    let mut label = LabelPair::default();
+    label.set_name(name.to_owned());
+    label.set_value(value.to_owned());
+    label
+}
+
+/// Queries the state of each watched service each time the registry is
+/// gathered.
+pub struct ServiceStateCollector {
+    desc: Desc,
+    services: Vec<String>,
+}
+
+impl ServiceStateCollector {
+    pub fn new(services: Vec<String>) -> prometheus::Result<Self> {
+        Ok(Self {
+            desc: Desc::new(
+                NAME.to_owned(),
+                HELP.to_owned(),
+                vec!["service".to_owned(), "state".to_owned()],
+                std::collections::HashMap::new(),
+            )?,
+            services: services,
+        })
+    }
+
+    /// Returns the state label for the service or None if its state couldn't
+    /// be read. Services that aren't installed are reported as absent.
+    fn service_state(&self, manager: &ServiceManager, name: &str) -> Option<&'static str> {
+        let service = match manager.open_service(name, ServiceAccess::QUERY_STATUS) {
+            Ok(service) => service,
+            Err(windows_service::Error::Winapi(ref e))
+                if e.raw_os_error() == Some(ERROR_SERVICE_DOES_NOT_EXIST as i32) =>
+            {
+                debug!("Service {} is not installed", name);
+                return Some(ABSENT);
+            }
+            Err(e) => {
+                // Access denied and the like say nothing about whether the
+                // service exists so we skip it rather than call it absent.
+                warn!("Unable to open service {}: {}", name, e);
+                return None;
+            }
+        };
+        match service.query_status() {
+            Ok(status) => Some(state_label(status.current_state)),
+            Err(e) => {
+                warn!("Unable to query the status of service {}: {}", name, e);
+                None
+            }
+        }
+    }
+}
+
+impl Collector for ServiceStateCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut family = MetricFamily::default();
+        family.set_name(NAME.to_owned());
+        family.set_help(HELP.to_owned());
+        family.set_field_type(MetricType::GAUGE);
+        let manager =
+            match ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT) {
+                Ok(manager) => manager,
+                Err(e) => {
+                    warn!("Unable to connect to the service manager: {}", e);
+                    return vec![family];
+                }
+            };
+        for name in self.services.iter() {
+            let current = match self.service_state(&manager, name) {
+                Some(state) => state,
+                None => continue,
+            };
+            for state in STATES.iter() {
+                let mut gauge = Gauge::default();
+                gauge.set_value(if *state == current { 1.0 } else { 0.0 });
+                let mut metric = Metric::default();
+                metric.mut_label().push(label("service", name));
+                metric.mut_label().push(label("state", state));
+                metric.set_gauge(gauge);
+                family.mut_metric().push(metric);
+            }
+        }
+        vec![family]
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Saving the last gathered metric values when the exporter stops so they
+//! can be served after it starts again.
+//!
+//! The state file is a JSON object with the time it was saved in
+//! milliseconds since the epoch and the gauge and counter families:
+//!
+//! ```json
+//! {"saved_ms": 1600000000000, "families": [
+//!   {"name": "cpu_total_pct", "help": "...", "type": "gauge",
+//!    "metrics": [{"labels": {}, "value": 12.5}]}
+//! ]}
+//! ```
+//!
+//! Restored values carry the time they were saved as their timestamp so
+//! prometheus treats them as old samples. A restored family is only served
+//! until the live registry reports one of the same name.
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use prometheus::proto::{Counter, Gauge, LabelPair, Metric, MetricFamily, MetricType};
+use serde_json::{json, Map, Value as JsonValue};
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn family_to_json(family: &MetricFamily) -> Option<JsonValue> {
+    let type_name = match family.get_field_type() {
+        MetricType::GAUGE => "gauge",
+        MetricType::COUNTER => "counter",
+        _ => return None,
+    };
+    let metrics = family
+        .get_metric()
+        .iter()
+        .map(|metric| {
+            let labels = metric
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name().to_owned(), json!(l.get_value())))
+                .collect::<Map<String, JsonValue>>();
+            let value = match family.get_field_type() {
+                MetricType::COUNTER => metric.get_counter().get_value(),
+                _ => metric.get_gauge().get_value(),
+            };
+            json!({"labels": labels, "value": value})
+        })
+        .collect::<Vec<JsonValue>>();
+    Some(json!({
+        "name": family.get_name(),
+        "help": family.get_help(),
+        "type": type_name,
+        "metrics": metrics,
+    }))
+}
+
+fn family_from_json(value: &JsonValue, saved_ms: i64) -> Option<MetricFamily> {
+    let mut family = MetricFamily::default();
+    family.set_name(value.get("name")?.as_str()?.to_owned());
+    family.set_help(value.get("help")?.as_str()?.to_owned());
+    let field_type = match value.get("type")?.as_str()? {
+        "gauge" => MetricType::GAUGE,
+        "counter" => MetricType::COUNTER,
+        _ => return None,
+    };
+    family.set_field_type(field_type);
+    for saved in value.get("metrics")?.as_array()? {
+        let mut metric = Metric::default();
+        for (name, label_value) in saved.get("labels")?.as_object()? {
+            let mut label = LabelPair::default();
+            label.set_name(name.clone());
+            label.set_value(label_value.as_str()?.to_owned());
+            metric.mut_label().push(label);
+        }
+        let v = saved.get("value")?.as_f64()?;
+        if field_type == MetricType::COUNTER {
+            let mut counter = Counter::default();
+            counter.set_value(v);
+            metric.set_counter(counter);
+        } else {
+            let mut gauge = Gauge::default();
+            gauge.set_value(v);
+            metric.set_gauge(gauge);
+        }
+        metric.set_timestamp_ms(saved_ms);
+        family.mut_metric().push(metric);
+    }
+    Some(family)
+}
+
+/// Writes the gauge and counter families to the state file at path.
+pub fn save(path: &str, families: &[MetricFamily]) -> anyhow::Result<()> {
+    let state = json!({
+        "saved_ms": now_ms(),
+        "families": families.iter().filter_map(family_to_json).collect::<Vec<JsonValue>>(),
+    });
+    // Write to a temporary file first so a failed write doesn't leave a
+    // truncated state file behind.
+    let tmp_path = format!("{}.tmp", path);
+    std::fs::write(&tmp_path, serde_json::to_vec(&state)?)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Reads the families saved in the state file at path. Entries that don't
+/// parse are skipped.
+pub fn load(path: &str) -> anyhow::Result<Vec<MetricFamily>> {
+    let state: JsonValue = serde_json::from_slice(&std::fs::read(path)?)?;
+    let saved_ms = state.get("saved_ms").and_then(|v| v.as_i64()).unwrap_or(0);
+    let families = match state.get("families").and_then(|v| v.as_array()) {
+        Some(families) => families,
+        None => return Err(anyhow::anyhow!("{} has no families", path)),
+    };
+    Ok(families
+        .iter()
+        .filter_map(|f| family_from_json(f, saved_ms))
+        .collect())
+}
+
+/// The families restored from a state file that haven't been replaced by
+/// live values yet.
+pub struct RestoredState {
+    families: Mutex<Vec<MetricFamily>>,
+}
+
+impl RestoredState {
+    pub fn new(families: Vec<MetricFamily>) -> Self {
+        Self {
+            families: Mutex::new(families),
+        }
+    }
+
+    /// Adds the restored families that have no live family of the same name
+    /// to the gathered families keeping them sorted by name. Restored
+    /// families are forgotten once a live one replaces them.
+    pub fn merge_into(&self, gathered: &mut Vec<MetricFamily>) {
+        let mut restored = self.families.lock().unwrap();
+        if restored.is_empty() {
+            return;
+        }
+        let live = gathered
+            .iter()
+            .map(|f| f.get_name().to_owned())
+            .collect::<BTreeSet<String>>();
+        restored.retain(|f| !live.contains(f.get_name()));
+        gathered.extend(restored.iter().cloned());
+        gathered.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    }
+}
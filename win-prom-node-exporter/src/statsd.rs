@@ -0,0 +1,92 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pushes gathered metrics to a statsd or dogstatsd server as gauges.
+use std::net::UdpSocket;
+
+use prometheus::proto::{MetricFamily, MetricType};
+
+/// The largest payload we put in one packet. This fits in the MTU of most
+/// networks without fragmenting.
+const MAX_PACKET_SIZE: usize = 1432;
+
+/// Replaces anything statsd would treat specially in a name segment.
+fn sanitize(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_alphanumeric() || c == '-' || c == '_' {
+            out.push(c);
+        } else if !out.ends_with('_') {
+            out.push('_');
+        }
+    }
+    out.trim_matches('_').to_owned()
+}
+
+/// Formats the metric families as statsd gauge lines. Label values are
+/// appended to the metric name as dotted segments e.g.
+/// `network_ifc_bytes_sent_sec.Intel_R_Ethernet:1234|g`. With dogstatsd
+/// they are sent as tags instead.
+pub fn gauge_lines(families: &[MetricFamily], dogstatsd: bool) -> Vec<String> {
+    let mut lines = Vec::new();
+    for family in families {
+        for metric in family.get_metric() {
+            let value = match family.get_field_type() {
+                MetricType::GAUGE => metric.get_gauge().get_value(),
+                MetricType::COUNTER => metric.get_counter().get_value(),
+                MetricType::UNTYPED => metric.get_untyped().get_value(),
+                _ => continue,
+            };
+            let mut name = sanitize(family.get_name());
+            let mut tags = Vec::new();
+            for label in metric.get_label() {
+                if dogstatsd {
+                    tags.push(format!(
+                        "{}:{}",
+                        label.get_name(),
+                        sanitize(label.get_value())
+                    ));
+                } else {
+                    name.push('.');
+                    name.push_str(&sanitize(label.get_value()));
+                }
+            }
+            if tags.is_empty() {
+                lines.push(format!("{}:{}|g", name, value));
+            } else {
+                lines.push(format!("{}:{}|g|#{}", name, value, tags.join(",")));
+            }
+        }
+    }
+    lines
+}
+
+/// Sends the lines to addr batching as many as fit into each packet.
+pub fn send_lines(socket: &UdpSocket, addr: &str, lines: &Vec<String>) -> std::io::Result<()> {
+    let mut packet = String::with_capacity(MAX_PACKET_SIZE);
+    for line in lines {
+        if !packet.is_empty() && packet.len() + line.len() + 1 > MAX_PACKET_SIZE {
+            socket.send_to(packet.as_bytes(), addr)?;
+            packet.clear();
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        socket.send_to(packet.as_bytes(), addr)?;
+    }
+    Ok(())
+}
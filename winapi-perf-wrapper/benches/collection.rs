@@ -0,0 +1,137 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Collection throughput benchmarks.
+//!
+//! These run against the live performance counters of the machine so they
+//! only work on Windows. Elsewhere the benchmark binary prints a notice and
+//! exits. Run them with `cargo bench -p winapi-perf-wrapper`.
+#[cfg(windows)]
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+#[cfg(windows)]
+use winapi_perf_wrapper::{PdhCounter, PDH};
+
+#[cfg(windows)]
+const SYSTEM_PATHS: &[&str] = &[
+    "\\System\\Processes",
+    "\\System\\Threads",
+    "\\System\\Context Switches/sec",
+    "\\System\\System Calls/sec",
+    "\\Memory\\Available Bytes",
+    "\\Memory\\Cache Bytes",
+    "\\Memory\\Committed Bytes",
+    "\\Processor(_Total)\\% Processor Time",
+];
+
+#[cfg(windows)]
+const WILDCARD_PATH: &str = "\\Process(*)\\% Processor Time";
+
+#[cfg(windows)]
+fn bench_enumerate(c: &mut Criterion) {
+    let mut pdh = PDH::new();
+    c.bench_function("enumerate_counters", |b| {
+        b.iter(|| pdh.enumerate_counters().unwrap())
+    });
+    // A hint big enough for any object saves the sizing call PDH otherwise
+    // makes for every object.
+    let mut hinted = PDH::new().with_buffer_hint(64 * 1024);
+    c.bench_function("enumerate_counters_hinted", |b| {
+        b.iter(|| hinted.enumerate_counters().unwrap())
+    });
+    // Looking up types is slow so take fewer samples. A fresh PDH each
+    // iteration keeps the type cache from hiding the cost.
+    let mut group = c.benchmark_group("enumerate_types");
+    group.sample_size(10);
+    group.bench_function("with_types", |b| {
+        b.iter(|| PDH::new().enumerate_counters_with_types().unwrap())
+    });
+    group.bench_function("counter_info_per_path", |b| {
+        b.iter(|| {
+            let mut pdh = PDH::new();
+            let query = pdh.open_query().unwrap();
+            pdh.enumerate_counters()
+                .unwrap()
+                .iter()
+                .filter_map(|p| {
+                    let counter = query.add_counter_string(p.as_str()).ok()?;
+                    query.counter_info(&counter).ok()
+                })
+                .count()
+        })
+    });
+    group.finish();
+}
+
+#[cfg(windows)]
+fn bench_collect(c: &mut Criterion) {
+    let pdh = PDH::new();
+    let mut group = c.benchmark_group("collect");
+    for n in [1, 4, SYSTEM_PATHS.len()].iter() {
+        let query = pdh.open_query().unwrap();
+        let counters: Vec<PdhCounter> = SYSTEM_PATHS[0..*n]
+            .iter()
+            .map(|p| query.add_counter_string(*p).unwrap())
+            .collect();
+        // Prime the rate counters.
+        query.collect().unwrap();
+        group.bench_with_input(BenchmarkId::new("individual", n), n, |b, _| {
+            b.iter(|| {
+                for counter in counters.iter() {
+                    let _ = query.collect_double_data(counter);
+                }
+            })
+        });
+        let counter_refs: Vec<&PdhCounter> = counters.iter().collect();
+        let mut values = Vec::with_capacity(counter_refs.len());
+        group.bench_with_input(BenchmarkId::new("batch", n), n, |b, _| {
+            b.iter(|| query.collect_all_double_into(&counter_refs, &mut values))
+        });
+    }
+    group.finish();
+}
+
+#[cfg(windows)]
+fn bench_array(c: &mut Criterion) {
+    let pdh = PDH::new();
+    let query = pdh.open_query().unwrap();
+    let counter = query.add_counter_string(WILDCARD_PATH).unwrap();
+    // Prime the rate counters.
+    query.collect().unwrap();
+    let mut group = c.benchmark_group("formatted_array");
+    group.bench_function("allocating", |b| {
+        b.iter(|| {
+            query.collect().unwrap();
+            query.format_double_array(&counter)
+        })
+    });
+    let mut buffer = Vec::new();
+    let mut values = Vec::new();
+    group.bench_function("reused_buffer", |b| {
+        b.iter(|| {
+            query.collect().unwrap();
+            query.format_double_array_into(&counter, &mut buffer, &mut values)
+        })
+    });
+    group.finish();
+}
+
+#[cfg(windows)]
+criterion_group!(benches, bench_enumerate, bench_collect, bench_array);
+#[cfg(windows)]
+criterion_main!(benches);
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("The collection benchmarks read live performance counters and only run on Windows.");
+}
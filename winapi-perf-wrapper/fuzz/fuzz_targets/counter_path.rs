@@ -0,0 +1,40 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Feeds arbitrary strings to the counter path parser. Parsing must never
+//! panic and any path that parses must come back the same after being
+//! assembled again. Our own parser is the one fuzzed since it has no PDH to
+//! lean on. Where PDH parses a path too both must split it up the same way.
+//! Run it on Windows from winapi-perf-wrapper with
+//! `cargo +nightly fuzz run counter_path`.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use winapi_perf_wrapper::counter_path::{
+    canonical_counter_path, parse_counter_path, parse_counter_path_pdh_as_written,
+};
+
+fuzz_target!(|path: &str| {
+    let canonical = canonical_counter_path(path);
+    if let Some(mut elements) = parse_counter_path(path) {
+        let assembled = elements.to_path();
+        assert_eq!(parse_counter_path(&assembled).as_ref(), Some(&elements));
+        assert_eq!(canonical_counter_path(&assembled), canonical);
+        if let Ok(pdh_elements) = parse_counter_path_pdh_as_written(path) {
+            // PDH doesn't tell `#0` apart from no index.
+            if elements.instance_index == Some(0) {
+                elements.instance_index = None;
+            }
+            assert_eq!(elements, pdh_elements);
+        }
+    }
+});
@@ -0,0 +1,43 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Feeds arbitrary utf16 buffers to the null separated list parsing that
+//! every enumeration depends on. Splitting must never panic however the
+//! buffer is terminated and any list of non-empty strings must come back the
+//! same after being built again. Run it on Windows from winapi-perf-wrapper
+//! with `cargo +nightly fuzz run multi_sz`.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use winapi_perf_wrapper::multi_sz::{null_separated_to_vec, vec_to_null_separated};
+
+fuzz_target!(|data: &[u8]| {
+    // An odd trailing byte is dropped.
+    let buf = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect::<Vec<u16>>();
+    let items = null_separated_to_vec(&buf)
+        .into_iter()
+        .filter(|item| !item.is_empty())
+        .collect::<Vec<Vec<u16>>>();
+    // An empty list reads back as a single empty string.
+    let expected = if items.is_empty() {
+        vec![Vec::new()]
+    } else {
+        items.clone()
+    };
+    assert_eq!(
+        null_separated_to_vec(&vec_to_null_separated(&items)),
+        expected
+    );
+});
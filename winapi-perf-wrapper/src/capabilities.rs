@@ -0,0 +1,381 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Runtime detection of the PDH functions available on this version of windows.
+//!
+//! This crate supports Windows XP and Windows Server 2003 or later. Functions
+//! added to PDH after that, like `PdhAddEnglishCounterW` which needs Windows
+//! Vista or Windows Server 2008, are looked up in pdh.dll at runtime instead
+//! of being linked so the crate still loads on older versions and can fall
+//! back to the legacy apis there.
+use std::collections::HashMap;
+use std::ptr::{null, null_mut};
+
+use winapi::shared::basetsd::DWORD_PTR;
+use winapi::shared::minwindef::{DWORD, FARPROC, HKEY};
+use winapi::shared::winerror::{ERROR_MORE_DATA, ERROR_SUCCESS};
+use winapi::um::libloaderapi::{GetModuleHandleW, GetProcAddress};
+use winapi::um::pdh::{
+    PdhGetDllVersion, PdhLookupPerfNameByIndexW, PDH_HCOUNTER, PDH_HQUERY, PDH_STATUS,
+};
+use winapi::um::winnt::{KEY_READ, LPCWSTR};
+use winapi::um::winreg::{
+    RegCloseKey, RegEnumKeyExW, RegOpenKeyExW, RegQueryValueExW, HKEY_LOCAL_MACHINE,
+    HKEY_PERFORMANCE_TEXT,
+};
+
+use crate::constants::*;
+use crate::counter_path::parse_counter_path_pdh_as_written;
+
+/// The signature of `PdhAddEnglishCounterW`.
+pub(crate) type AddEnglishCounterFn =
+    unsafe extern "system" fn(PDH_HQUERY, LPCWSTR, DWORD_PTR, *mut PDH_HCOUNTER) -> PDH_STATUS;
+
+/// The PDH features available at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PdhCapabilities {
+    /// The version reported by `PdhGetDllVersion`. One of the
+    /// `PDH_CVERSION_*` constants or `PDH_VERSION`. Zero if it could not be
+    /// determined.
+    pub dll_version: u32,
+    /// `PdhAddEnglishCounterW` is available.
+    pub add_english_counter: bool,
+    /// `PdhExpandWildCardPathW` is available.
+    pub expand_wildcard_path: bool,
+}
+
+/// Looks up an exported function in the already loaded pdh.dll.
+fn pdh_proc(name: &str) -> FARPROC {
+    let module_name = "pdh.dll\0".encode_utf16().collect::<Vec<u16>>();
+    let module = unsafe { GetModuleHandleW(module_name.as_ptr()) };
+    if module.is_null() {
+        return null_mut();
+    }
+    let proc_name = format!("{}\0", name);
+    unsafe { GetProcAddress(module, proc_name.as_ptr() as *const i8) }
+}
+
+/// Detects the PDH features available on this machine.
+pub fn detect() -> PdhCapabilities {
+    let mut dll_version: DWORD = 0;
+    if unsafe { PdhGetDllVersion(&mut dll_version) } as u32 != ERROR_SUCCESS {
+        dll_version = 0;
+    }
+    PdhCapabilities {
+        dll_version: dll_version,
+        add_english_counter: !pdh_proc("PdhAddEnglishCounterW").is_null(),
+        expand_wildcard_path: !pdh_proc("PdhExpandWildCardPathW").is_null(),
+    }
+}
+
+/// Returns `PdhAddEnglishCounterW` if this version of windows has it.
+pub(crate) fn add_english_counter_fn() -> Option<AddEnglishCounterFn> {
+    let proc = pdh_proc("PdhAddEnglishCounterW");
+    if proc.is_null() {
+        return None;
+    }
+    Some(unsafe { std::mem::transmute::<FARPROC, AddEnglishCounterFn>(proc) })
+}
+
+/// Reads the english object and counter names mapped to their indexes.
+fn english_name_indexes() -> Result<HashMap<String, DWORD>, PdhError> {
+    let value_name = "Counter\0".encode_utf16().collect::<Vec<u16>>();
+    let mut buffer: Vec<u16> = vec![0; 64 * 1024];
+    loop {
+        let mut byte_len = (buffer.len() * 2) as DWORD;
+        let status = unsafe {
+            RegQueryValueExW(
+                HKEY_PERFORMANCE_TEXT,
+                value_name.as_ptr(),
+                null_mut(),
+                null_mut(),
+                buffer.as_mut_ptr() as *mut u8,
+                &mut byte_len,
+            )
+        } as u32;
+        if status == ERROR_MORE_DATA {
+            // The performance keys don't reliably report the size they need
+            // so we just keep doubling.
+            let len = buffer.len();
+            buffer.resize(len * 2, 0);
+            continue;
+        }
+        if status != ERROR_SUCCESS {
+            return Err(status.into());
+        }
+        buffer.truncate(byte_len as usize / 2);
+        break;
+    }
+    // The value is a null separated list of alternating indexes and names.
+    let mut names = HashMap::new();
+    let mut parts = buffer.split(|c| *c == 0);
+    while let (Some(index), Some(name)) = (parts.next(), parts.next()) {
+        if let Ok(index) = String::from_utf16_lossy(index).parse::<DWORD>() {
+            names.entry(String::from_utf16_lossy(name)).or_insert(index);
+        }
+    }
+    Ok(names)
+}
+
+/// Looks up the localized name for a name index on a machine.
+fn localized_name(machine_name: Option<&str>, index: DWORD) -> Result<String, PdhError> {
+    let machine_name = machine_name
+        .and_then(|m| crate::normalize_machine_name(&m.encode_utf16().collect::<Vec<u16>>()));
+    let mut buffer: Vec<u16> = vec![0; PDH_MAX_COUNTER_NAME as usize];
+    let mut len = buffer.len() as DWORD;
+    let status = unsafe {
+        PdhLookupPerfNameByIndexW(
+            machine_name.as_ref().map_or(null(), |m| m.as_ptr()),
+            index,
+            buffer.as_mut_ptr(),
+            &mut len,
+        )
+    } as u32;
+    if status != ERROR_SUCCESS {
+        return Err(status.into());
+    }
+    let end = buffer.iter().position(|c| *c == 0).unwrap_or(buffer.len());
+    Ok(String::from_utf16_lossy(&buffer[0..end]))
+}
+
+/// Translates a counter path using english object and counter names into
+/// the localized path for the machine it refers to.
+pub(crate) fn localize_counter_path(path: &str) -> Result<String, PdhError> {
+    let mut elements = parse_counter_path_pdh_as_written(path)?;
+    let names = english_name_indexes()?;
+    let machine_name = elements.machine_name.clone();
+    let object_index = match names.get(&elements.object_name) {
+        Some(index) => *index,
+        None => return Err(PdhError::NoObject),
+    };
+    let counter_index = match names.get(&elements.counter_name) {
+        Some(index) => *index,
+        None => return Err(PdhError::NoCounter),
+    };
+    elements.object_name = localized_name(machine_name.as_deref(), object_index)?;
+    elements.counter_name = localized_name(machine_name.as_deref(), counter_index)?;
+    Ok(elements.to_path())
+}
+
+/// How to re-enable performance counter providers that have been disabled.
+pub const PROVIDER_DISABLED_REMEDIATION: &'static str = "Clear the \
+    \"Disable Performance Counters\" registry value under the provider's \
+    HKLM\\SYSTEM\\CurrentControlSet\\Services\\<service>\\Performance key or \
+    rebuild the counter settings with `lodctr /R`.";
+
+/// How to repair the counter registry when an object is registered but PDH
+/// still can't find it after a refresh.
+pub const COUNTER_CACHE_REMEDIATION: &'static str = "The object is \
+    registered but PDH can't find it which usually means the performance \
+    counter settings are corrupt. Rebuild them from an elevated prompt with \
+    `lodctr /R` and restart this process.";
+
+/// Reports whether an object is registered under its english name in the
+/// performance counter registry. Used to tell a missing provider apart from
+/// a corrupt counter cache.
+pub fn object_registered(object_name: &str) -> Result<bool, PdhError> {
+    Ok(english_name_indexes()?.contains_key(object_name))
+}
+
+/// Opens a registry key for reading.
+fn open_key(parent: HKEY, name: &str) -> Result<HKEY, PdhError> {
+    let name = crate::str_to_utf16(name);
+    let mut key: HKEY = null_mut();
+    let status = unsafe { RegOpenKeyExW(parent, name.as_ptr(), 0, KEY_READ, &mut key) } as u32;
+    if status != ERROR_SUCCESS {
+        return Err(status.into());
+    }
+    Ok(key)
+}
+
+/// Lists the services whose performance counter providers have been
+/// disabled with the `Disable Performance Counters` registry value. Objects
+/// from a disabled provider are missing from enumeration and fail to add
+/// with `PDH_CSTATUS_NO_OBJECT`, which queries on this machine report as
+/// `PdhError::ProviderDisabled`. See PROVIDER_DISABLED_REMEDIATION for how
+/// to re-enable them.
+pub fn disabled_providers() -> Result<Vec<String>, PdhError> {
+    let services = open_key(HKEY_LOCAL_MACHINE, "SYSTEM\\CurrentControlSet\\Services")?;
+    let mut disabled = Vec::new();
+    let mut index = 0;
+    loop {
+        // Registry key names are at most 255 characters.
+        let mut name: Vec<u16> = vec![0; 256];
+        let mut name_len = name.len() as DWORD;
+        let status = unsafe {
+            RegEnumKeyExW(
+                services,
+                index,
+                name.as_mut_ptr(),
+                &mut name_len,
+                null_mut(),
+                null_mut(),
+                null_mut(),
+                null_mut(),
+            )
+        } as u32;
+        if status != ERROR_SUCCESS {
+            // ERROR_NO_MORE_ITEMS once we run out of services.
+            break;
+        }
+        index += 1;
+        let service = String::from_utf16_lossy(&name[0..name_len as usize]);
+        let performance = match open_key(services, &format!("{}\\Performance", service)) {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+        let value = query_dword(performance, "Disable Performance Counters");
+        unsafe { RegCloseKey(performance) };
+        if value.unwrap_or(0) != 0 {
+            disabled.push(service);
+        }
+    }
+    unsafe { RegCloseKey(services) };
+    Ok(disabled)
+}
+
+/// Reads a DWORD value from a registry key.
+fn query_dword(key: HKEY, name: &str) -> Option<DWORD> {
+    let name = crate::str_to_utf16(name);
+    let mut value: DWORD = 0;
+    let mut value_len = std::mem::size_of::<DWORD>() as DWORD;
+    let status = unsafe {
+        RegQueryValueExW(
+            key,
+            name.as_ptr(),
+            null_mut(),
+            null_mut(),
+            &mut value as *mut DWORD as *mut u8,
+            &mut value_len,
+        )
+    } as u32;
+    if status != ERROR_SUCCESS {
+        return None;
+    }
+    Some(value)
+}
+
+/// Reads a string value from a registry key.
+fn query_string(key: HKEY, name: &str) -> Option<String> {
+    let name = crate::str_to_utf16(name);
+    let mut buffer: Vec<u16> = vec![0; 1024];
+    let mut byte_len = (buffer.len() * 2) as DWORD;
+    let status = unsafe {
+        RegQueryValueExW(
+            key,
+            name.as_ptr(),
+            null_mut(),
+            null_mut(),
+            buffer.as_mut_ptr() as *mut u8,
+            &mut byte_len,
+        )
+    } as u32;
+    if status != ERROR_SUCCESS {
+        return None;
+    }
+    buffer.truncate(byte_len as usize / 2);
+    let end = buffer.iter().position(|c| *c == 0).unwrap_or(buffer.len());
+    Some(String::from_utf16_lossy(&buffer[0..end]))
+}
+
+/// Reports whether a provider's performance settings cover the object with
+/// a name index. Providers register their names in the range from their
+/// `First Counter` to their `Last Counter` value and some also list their
+/// objects' indexes in `Object List`.
+fn provides_object(
+    first_counter: Option<DWORD>,
+    last_counter: Option<DWORD>,
+    object_list: Option<&str>,
+    index: DWORD,
+) -> bool {
+    if let (Some(first), Some(last)) = (first_counter, last_counter) {
+        if first <= index && index <= last {
+            return true;
+        }
+    }
+    object_list
+        .map(|list| {
+            list.split_whitespace()
+                .any(|i| i.parse::<DWORD>() == Ok(index))
+        })
+        .unwrap_or(false)
+}
+
+/// Finds the disabled service that provides an object named in english.
+/// Returns None if the object isn't registered or its provider isn't
+/// disabled.
+pub fn disabled_provider_for_object(object_name: &str) -> Result<Option<String>, PdhError> {
+    let disabled = disabled_providers()?;
+    if disabled.is_empty() {
+        return Ok(None);
+    }
+    let index = match english_name_indexes()?.get(object_name) {
+        Some(index) => *index,
+        None => return Ok(None),
+    };
+    for service in disabled {
+        let key_name = format!(
+            "SYSTEM\\CurrentControlSet\\Services\\{}\\Performance",
+            service
+        );
+        let performance = match open_key(HKEY_LOCAL_MACHINE, &key_name) {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+        let provides = provides_object(
+            query_dword(performance, "First Counter"),
+            query_dword(performance, "Last Counter"),
+            query_string(performance, "Object List").as_deref(),
+            index,
+        );
+        unsafe { RegCloseKey(performance) };
+        if provides {
+            return Ok(Some(service));
+        }
+    }
+    Ok(None)
+}
+
+/// Turns a missing object error into `PdhError::ProviderDisabled` when the
+/// object's provider is disabled on this machine. Every other error is
+/// returned as is.
+pub(crate) fn check_provider_disabled(object_name: &str, err: PdhError) -> PdhError {
+    if err != PdhError::NoObject {
+        return err;
+    }
+    match disabled_provider_for_object(object_name) {
+        Ok(Some(_)) => PdhError::ProviderDisabled,
+        _ => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provides_object_within_counter_range() {
+        assert!(provides_object(Some(100), Some(120), None, 100));
+        assert!(provides_object(Some(100), Some(120), None, 120));
+        assert!(!provides_object(Some(100), Some(120), None, 122));
+        assert!(!provides_object(Some(100), None, None, 100));
+    }
+
+    #[test]
+    fn provides_object_in_object_list() {
+        assert!(provides_object(None, None, Some("230 232  740"), 740));
+        assert!(!provides_object(None, None, Some("230 232 740"), 74));
+        assert!(!provides_object(None, None, None, 230));
+        assert!(provides_object(Some(1), Some(2), Some("230"), 230));
+    }
+}
@@ -15,39 +15,275 @@
 
 // TODO(jwall): Get these constants features in winapi-rs.
 
-pub type PDHStatus = u32;
+use std::fmt;
+
+/// The old name for PdhError from when statuses were bare u32s.
+#[deprecated(note = "use PdhError instead")]
+pub type PDHStatus = PdhError;
 
 // pdh.h
 pub const PDH_MAX_COUNTER_PATH: u32 = 2048;
 pub const PDH_MAX_COUNTER_NAME: u32 = 1024;
 pub const PDH_MAX_INSTANCE_NAME: u32 = 1024;
 pub const PDH_MAX_DATASOURCE_PATH: u32 = 1024;
+pub const PDH_CVERSION_WIN40: u32 = 0x0400;
+pub const PDH_CVERSION_WIN50: u32 = 0x0500;
+pub const PDH_VERSION: u32 = 0x0503;
+/// Show only the file browser in the select data source dialog.
+pub const PDH_FLAGS_FILE_BROWSER_ONLY: u32 = 0x00000001;
+// winperf.h
+/// Detail levels for the counters shown in the browse dialog.
+pub const PERF_DETAIL_NOVICE: u32 = 100;
+pub const PERF_DETAIL_ADVANCED: u32 = 200;
+pub const PERF_DETAIL_EXPERT: u32 = 300;
+pub const PERF_DETAIL_WIZARD: u32 = 400;
 // pdhmsg.h
-pub const PDH_DIALOG_CANCELLED: PDHStatus = 0x800007D9;
-pub const PDH_MORE_DATA: PDHStatus = 0x800007D2;
-pub const PDH_MEMORY_ALLOCATION_FAILURE: PDHStatus = 0xC0000BBB;
-pub const PDH_INVALID_ARGUMENT: PDHStatus = 0xC0000BBD;
+pub const PDH_CSTATUS_VALID_DATA: u32 = 0x00000000;
+pub const PDH_CSTATUS_NEW_DATA: u32 = 0x00000001;
+pub const PDH_NO_DATA: u32 = 0x800007D5;
+pub const PDH_NO_MORE_DATA: u32 = 0xC0000BCC;
+pub const PDH_CALC_NEGATIVE_DENOMINATOR: u32 = 0x800007D6;
+pub const PDH_CALC_NEGATIVE_TIMEBASE: u32 = 0x800007D7;
+pub const PDH_CALC_NEGATIVE_VALUE: u32 = 0x800007D8;
+pub const PDH_CSTATUS_INVALID_DATA: u32 = 0xC0000BBA;
+pub const PDH_DIALOG_CANCELLED: u32 = 0x800007D9;
+pub const PDH_MORE_DATA: u32 = 0x800007D2;
+pub const PDH_MEMORY_ALLOCATION_FAILURE: u32 = 0xC0000BBB;
+pub const PDH_INVALID_ARGUMENT: u32 = 0xC0000BBD;
 pub const PDH_INVALID_DATA: u32 = 0xC0000BC6;
 pub const PDH_INVALID_HANDLE: u32 = 0xC0000BBC;
-pub const PDH_CSTATUS_NO_OBJECT: PDHStatus = 0xC0000BB8;
-pub const PDH_CSTATUS_NO_MACHINE: PDHStatus = 0x800007D0;
+pub const PDH_CSTATUS_NO_OBJECT: u32 = 0xC0000BB8;
+pub const PDH_CSTATUS_NO_MACHINE: u32 = 0x800007D0;
 pub const PDH_CSTATUS_NO_INSTANCE: u32 = 0x800007D1;
 pub const PDH_CSTATUS_NO_COUNTER: u32 = 0xC0000BB9;
 pub const PDH_CSTATUS_BAD_COUNTERNAME: u32 = 0xC0000BC0;
+pub const PDH_ACCESS_DENIED: u32 = 0xC0000BDB;
+// winerror.h
+pub const ERROR_ACCESS_DENIED: u32 = 5;
+// winerror.h
+pub const RPC_S_SERVER_UNAVAILABLE: u32 = 1722;
+pub const RPC_S_SERVER_TOO_BUSY: u32 = 1723;
+pub const RPC_S_CALL_FAILED: u32 = 1726;
+// Our own statuses. winerror.h reserves bit 29 for application defined codes
+// so these never collide with a PDH or win32 status.
+/// A CounterStream's circuit breaker has opened after too many consecutive
+/// errors.
+pub const CIRCUIT_OPEN: u32 = 0xE0000001;
+/// A machine name was given that is only whitespace.
+pub const INVALID_MACHINE_NAME: u32 = 0xE0000002;
+/// An object is missing because the counter provider for it has been
+/// disabled on this machine.
+pub const PROVIDER_DISABLED: u32 = 0xE0000003;
+
+/// The error type for PDH calls. The statuses this crate handles have their
+/// own variant and every other status is kept as is in Other. raw_code
+/// returns the status code either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PdhError {
+    /// The object in a counter path doesn't exist.
+    NoObject,
+    /// The machine in a counter path couldn't be reached.
+    NoMachine,
+    /// The instance in a counter path doesn't exist.
+    NoInstance,
+    /// The counter doesn't exist on the object.
+    NoCounter,
+    /// The counter path couldn't be parsed.
+    BadCounterName,
+    /// The counter's data for this collection isn't valid.
+    InvalidCounterData,
+    /// The buffer passed to PDH was too small.
+    MoreData,
+    /// There was no data to return.
+    NoData,
+    /// A log has no more records.
+    NoMoreData,
+    /// A rate computed a negative denominator.
+    CalcNegativeDenominator,
+    /// A rate computed a negative time base.
+    CalcNegativeTimebase,
+    /// A rate computed a negative value.
+    CalcNegativeValue,
+    /// The user cancelled a PDH dialog.
+    DialogCancelled,
+    /// PDH couldn't allocate memory.
+    MemoryAllocationFailure,
+    /// An argument was not valid.
+    InvalidArgument,
+    /// A counter has no valid data yet.
+    InvalidData,
+    /// A query or counter handle was not valid.
+    InvalidHandle,
+    /// PDH denied access to the object.
+    AccessDenied,
+    /// The win32 ERROR_ACCESS_DENIED status.
+    Win32AccessDenied,
+    /// The RPC server on a remote machine is unavailable.
+    RpcServerUnavailable,
+    /// The RPC server on a remote machine is too busy.
+    RpcServerTooBusy,
+    /// An RPC call to a remote machine failed.
+    RpcCallFailed,
+    /// A CounterStream's circuit breaker is open.
+    CircuitOpen,
+    /// A machine name was only whitespace.
+    InvalidMachineName,
+    /// The object's counter provider is disabled. See
+    /// `capabilities::PROVIDER_DISABLED_REMEDIATION`.
+    ProviderDisabled,
+    /// Any other status.
+    Other(u32),
+}
+
+impl PdhError {
+    /// The status code as PDH returned it.
+    pub fn raw_code(&self) -> u32 {
+        match self {
+            PdhError::NoObject => PDH_CSTATUS_NO_OBJECT,
+            PdhError::NoMachine => PDH_CSTATUS_NO_MACHINE,
+            PdhError::NoInstance => PDH_CSTATUS_NO_INSTANCE,
+            PdhError::NoCounter => PDH_CSTATUS_NO_COUNTER,
+            PdhError::BadCounterName => PDH_CSTATUS_BAD_COUNTERNAME,
+            PdhError::InvalidCounterData => PDH_CSTATUS_INVALID_DATA,
+            PdhError::MoreData => PDH_MORE_DATA,
+            PdhError::NoData => PDH_NO_DATA,
+            PdhError::NoMoreData => PDH_NO_MORE_DATA,
+            PdhError::CalcNegativeDenominator => PDH_CALC_NEGATIVE_DENOMINATOR,
+            PdhError::CalcNegativeTimebase => PDH_CALC_NEGATIVE_TIMEBASE,
+            PdhError::CalcNegativeValue => PDH_CALC_NEGATIVE_VALUE,
+            PdhError::DialogCancelled => PDH_DIALOG_CANCELLED,
+            PdhError::MemoryAllocationFailure => PDH_MEMORY_ALLOCATION_FAILURE,
+            PdhError::InvalidArgument => PDH_INVALID_ARGUMENT,
+            PdhError::InvalidData => PDH_INVALID_DATA,
+            PdhError::InvalidHandle => PDH_INVALID_HANDLE,
+            PdhError::AccessDenied => PDH_ACCESS_DENIED,
+            PdhError::Win32AccessDenied => ERROR_ACCESS_DENIED,
+            PdhError::RpcServerUnavailable => RPC_S_SERVER_UNAVAILABLE,
+            PdhError::RpcServerTooBusy => RPC_S_SERVER_TOO_BUSY,
+            PdhError::RpcCallFailed => RPC_S_CALL_FAILED,
+            PdhError::CircuitOpen => CIRCUIT_OPEN,
+            PdhError::InvalidMachineName => INVALID_MACHINE_NAME,
+            PdhError::ProviderDisabled => PROVIDER_DISABLED,
+            PdhError::Other(code) => *code,
+        }
+    }
+}
+
+impl From<u32> for PdhError {
+    fn from(code: u32) -> Self {
+        match code {
+            PDH_CSTATUS_NO_OBJECT => PdhError::NoObject,
+            PDH_CSTATUS_NO_MACHINE => PdhError::NoMachine,
+            PDH_CSTATUS_NO_INSTANCE => PdhError::NoInstance,
+            PDH_CSTATUS_NO_COUNTER => PdhError::NoCounter,
+            PDH_CSTATUS_BAD_COUNTERNAME => PdhError::BadCounterName,
+            PDH_CSTATUS_INVALID_DATA => PdhError::InvalidCounterData,
+            PDH_MORE_DATA => PdhError::MoreData,
+            PDH_NO_DATA => PdhError::NoData,
+            PDH_NO_MORE_DATA => PdhError::NoMoreData,
+            PDH_CALC_NEGATIVE_DENOMINATOR => PdhError::CalcNegativeDenominator,
+            PDH_CALC_NEGATIVE_TIMEBASE => PdhError::CalcNegativeTimebase,
+            PDH_CALC_NEGATIVE_VALUE => PdhError::CalcNegativeValue,
+            PDH_DIALOG_CANCELLED => PdhError::DialogCancelled,
+            PDH_MEMORY_ALLOCATION_FAILURE => PdhError::MemoryAllocationFailure,
+            PDH_INVALID_ARGUMENT => PdhError::InvalidArgument,
+            PDH_INVALID_DATA => PdhError::InvalidData,
+            PDH_INVALID_HANDLE => PdhError::InvalidHandle,
+            PDH_ACCESS_DENIED => PdhError::AccessDenied,
+            ERROR_ACCESS_DENIED => PdhError::Win32AccessDenied,
+            RPC_S_SERVER_UNAVAILABLE => PdhError::RpcServerUnavailable,
+            RPC_S_SERVER_TOO_BUSY => PdhError::RpcServerTooBusy,
+            RPC_S_CALL_FAILED => PdhError::RpcCallFailed,
+            CIRCUIT_OPEN => PdhError::CircuitOpen,
+            INVALID_MACHINE_NAME => PdhError::InvalidMachineName,
+            PROVIDER_DISABLED => PdhError::ProviderDisabled,
+            code => PdhError::Other(code),
+        }
+    }
+}
+
+impl From<PdhError> for u32 {
+    fn from(err: PdhError) -> Self {
+        err.raw_code()
+    }
+}
+
+impl fmt::Display for PdhError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PdhError::Other(code) => write!(f, "PDH status {:#010X}", code),
+            PdhError::NoObject => f.write_str("PDH_CSTATUS_NO_OBJECT"),
+            PdhError::NoMachine => f.write_str("PDH_CSTATUS_NO_MACHINE"),
+            PdhError::NoInstance => f.write_str("PDH_CSTATUS_NO_INSTANCE"),
+            PdhError::NoCounter => f.write_str("PDH_CSTATUS_NO_COUNTER"),
+            PdhError::BadCounterName => f.write_str("PDH_CSTATUS_BAD_COUNTERNAME"),
+            PdhError::InvalidCounterData => f.write_str("PDH_CSTATUS_INVALID_DATA"),
+            PdhError::MoreData => f.write_str("PDH_MORE_DATA"),
+            PdhError::NoData => f.write_str("PDH_NO_DATA"),
+            PdhError::NoMoreData => f.write_str("PDH_NO_MORE_DATA"),
+            PdhError::CalcNegativeDenominator => f.write_str("PDH_CALC_NEGATIVE_DENOMINATOR"),
+            PdhError::CalcNegativeTimebase => f.write_str("PDH_CALC_NEGATIVE_TIMEBASE"),
+            PdhError::CalcNegativeValue => f.write_str("PDH_CALC_NEGATIVE_VALUE"),
+            PdhError::DialogCancelled => f.write_str("PDH_DIALOG_CANCELLED"),
+            PdhError::MemoryAllocationFailure => f.write_str("PDH_MEMORY_ALLOCATION_FAILURE"),
+            PdhError::InvalidArgument => f.write_str("PDH_INVALID_ARGUMENT"),
+            PdhError::InvalidData => f.write_str("PDH_INVALID_DATA"),
+            PdhError::InvalidHandle => f.write_str("PDH_INVALID_HANDLE"),
+            PdhError::AccessDenied => f.write_str("PDH_ACCESS_DENIED"),
+            PdhError::Win32AccessDenied => f.write_str("ERROR_ACCESS_DENIED"),
+            PdhError::RpcServerUnavailable => f.write_str("RPC_S_SERVER_UNAVAILABLE"),
+            PdhError::RpcServerTooBusy => f.write_str("RPC_S_SERVER_TOO_BUSY"),
+            PdhError::RpcCallFailed => f.write_str("RPC_S_CALL_FAILED"),
+            PdhError::CircuitOpen => f.write_str("CIRCUIT_OPEN"),
+            PdhError::InvalidMachineName => f.write_str("INVALID_MACHINE_NAME"),
+            PdhError::ProviderDisabled => f.write_str("PROVIDER_DISABLED"),
+        }
+    }
+}
 
-pub fn pdh_status_friendly_name(s: PDHStatus) -> String {
-    match s {
-        PDH_CSTATUS_NO_OBJECT => "PDH_CSTATUS_NO_OBJECT".to_owned(),
-        PDH_CSTATUS_NO_MACHINE => "PDH_CSTATUS_NO_MACHINE".to_owned(),
-        PDH_MORE_DATA => "PDH_MORE_DATA".to_owned(),
-        PDH_MEMORY_ALLOCATION_FAILURE => "PDH_MEMORY_ALLOCATION_FAILURE".to_owned(),
-        PDH_INVALID_ARGUMENT => "PDH_INVALID_ARGUMENT".to_owned(),
-        PDH_INVALID_DATA => "PDH_INVALID_DATA".to_owned(),
-        PDH_INVALID_HANDLE => "PDH_INVALID_HANDLE".to_owned(),
-        PDH_CSTATUS_NO_INSTANCE => "PDH_CSTATUS_NO_INSTANCE".to_owned(),
-        PDH_CSTATUS_NO_COUNTER => "PDH_CSTATUS_NO_COUNTER".to_owned(),
-        PDH_CSTATUS_BAD_COUNTERNAME => "PDH_CSTATUS_BAD_COUNTERNAME".to_owned(),
-        _ => format!("{}", s),
+impl std::error::Error for PdhError {}
+
+/// The name of a status. Accepts either a PdhError or a raw status code.
+pub fn pdh_status_friendly_name<S: Into<PdhError>>(s: S) -> String {
+    s.into().to_string()
+}
+
+/// Returns true for the statuses returned when the caller lacks the
+/// privileges to read an object.
+pub fn is_access_denied<S: Into<PdhError>>(s: S) -> bool {
+    match s.into() {
+        PdhError::AccessDenied | PdhError::Win32AccessDenied => true,
+        _ => false,
+    }
+}
+
+/// Returns true for the statuses a call to a remote machine can fail with
+/// transiently when the RPC to it fails. These are worth retrying unlike
+/// errors about the path itself such as `PDH_CSTATUS_BAD_COUNTERNAME`. RPC
+/// errors may come back as a win32 code or wrapped in an HRESULT.
+pub fn is_transient_remote_error<S: Into<PdhError>>(s: S) -> bool {
+    let s = s.into().raw_code();
+    let code = if s & 0xFFFF_0000 == 0x8007_0000 {
+        s & 0xFFFF
+    } else {
+        s
+    };
+    match code {
+        RPC_S_SERVER_UNAVAILABLE | RPC_S_SERVER_TOO_BUSY | RPC_S_CALL_FAILED => true,
+        _ => s == PDH_CSTATUS_NO_MACHINE,
+    }
+}
+
+/// Returns true for the statuses a rate counter reports until it has been
+/// collected twice.
+pub fn is_warmup_error<S: Into<PdhError>>(s: S) -> bool {
+    match s.into() {
+        PdhError::InvalidData
+        | PdhError::InvalidCounterData
+        | PdhError::CalcNegativeDenominator
+        | PdhError::CalcNegativeTimebase
+        | PdhError::CalcNegativeValue => true,
+        _ => false,
     }
 }
 
@@ -61,3 +297,38 @@ pub const PDH_FMT_LARGE: u32 = 0x00000400;
 pub const PDH_FMT_RAW: u32 = 0x00000010;
 pub const PDH_FMT_ANSI: u32 = 0x00000020;
 pub const PDH_FMT_UNICODE: u32 = 0x00000040;
+/// Don't apply the counter's default scaling factor.
+pub const PDH_FMT_NOSCALE: u32 = 0x00001000;
+/// Multiply the final value by 1000.
+pub const PDH_FMT_1000: u32 = 0x00002000;
+/// Don't cap percentage values at 100.
+pub const PDH_FMT_NOCAP100: u32 = 0x00008000;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provider_disabled_status_maps_to_its_variant() {
+        assert_eq!(
+            PdhError::from(PROVIDER_DISABLED),
+            PdhError::ProviderDisabled
+        );
+        assert_eq!(PdhError::ProviderDisabled.raw_code(), PROVIDER_DISABLED);
+        assert_eq!(
+            pdh_status_friendly_name(PROVIDER_DISABLED),
+            "PROVIDER_DISABLED"
+        );
+        // A plain missing object stays one.
+        assert_eq!(PdhError::from(PDH_CSTATUS_NO_OBJECT), PdhError::NoObject);
+    }
+
+    #[test]
+    fn access_denied_statuses() {
+        assert!(is_access_denied(PDH_ACCESS_DENIED));
+        assert!(is_access_denied(ERROR_ACCESS_DENIED));
+        assert!(is_access_denied(PdhError::AccessDenied));
+        assert!(!is_access_denied(PDH_CSTATUS_NO_OBJECT));
+        assert!(!is_access_denied(PdhError::InvalidHandle));
+    }
+}
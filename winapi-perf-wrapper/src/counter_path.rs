@@ -0,0 +1,295 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Utilities for working with performance counter paths.
+//!
+//! Counter paths have the form
+//! `\\Machine\Object(ParentInstance/Instance#Index)\Counter` where everything
+//! but the object and counter is optional. Instance names may themselves
+//! contain parentheses, e.g. `\Process(foo(bar))\% Processor Time`.
+use std::collections::HashMap;
+use std::ptr::null_mut;
+
+use winapi::shared::minwindef::DWORD;
+use winapi::shared::winerror::ERROR_SUCCESS;
+use winapi::um::pdh::{PdhParseCounterPathW, PDH_COUNTER_PATH_ELEMENTS_W};
+
+use crate::constants::*;
+
+/// Reads a null terminated utf16 string from a pointer if it isn't null.
+unsafe fn wide_ptr_to_option(ptr: *const u16) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+    Some(crate::wide_ptr_to_string(ptr))
+}
+
+/// The component parts of a counter path.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CounterPathElements {
+    pub machine_name: Option<String>,
+    pub object_name: String,
+    pub parent_instance: Option<String>,
+    pub instance_name: Option<String>,
+    pub instance_index: Option<u32>,
+    pub counter_name: String,
+}
+
+impl CounterPathElements {
+    /// Assembles the elements back into a counter path.
+    pub fn to_path(&self) -> String {
+        let mut path = String::new();
+        if let Some(ref machine_name) = self.machine_name {
+            path.push_str("\\\\");
+            path.push_str(machine_name);
+        }
+        path.push('\\');
+        path.push_str(&self.object_name);
+        if let Some(ref instance_name) = self.instance_name {
+            path.push('(');
+            if let Some(ref parent_instance) = self.parent_instance {
+                path.push_str(parent_instance);
+                path.push('/');
+            }
+            path.push_str(instance_name);
+            if let Some(index) = self.instance_index {
+                path.push('#');
+                path.push_str(&index.to_string());
+            }
+            path.push(')');
+        }
+        path.push('\\');
+        path.push_str(&self.counter_name);
+        path
+    }
+}
+
+/// Parses a counter path into its elements. Returns None if the path is not a
+/// well formed counter path.
+pub fn parse_counter_path(path: &str) -> Option<CounterPathElements> {
+    let mut elements = CounterPathElements::default();
+    let mut rest = path;
+    if rest.starts_with("\\\\") {
+        rest = &rest[2..];
+        let machine_end = rest.find('\\')?;
+        elements.machine_name = Some(rest[0..machine_end].to_owned());
+        rest = &rest[machine_end..];
+    }
+    if !rest.starts_with('\\') {
+        return None;
+    }
+    rest = &rest[1..];
+    // The counter name is everything after the last backslash.
+    let counter_start = rest.rfind('\\')?;
+    elements.counter_name = rest[counter_start + 1..].to_owned();
+    let object = &rest[0..counter_start];
+    // Instances may themselves contain parens so we take everything from the
+    // first open paren to the final close paren.
+    if object.ends_with(')') {
+        let instance_start = object.find('(')?;
+        elements.object_name = object[0..instance_start].to_owned();
+        let mut instance = &object[instance_start + 1..object.len() - 1];
+        if let Some(parent_end) = instance.find('/') {
+            elements.parent_instance = Some(instance[0..parent_end].to_owned());
+            instance = &instance[parent_end + 1..];
+        }
+        if let Some(index_start) = instance.rfind('#') {
+            if let Ok(idx) = instance[index_start + 1..].parse::<u32>() {
+                elements.instance_index = Some(idx);
+                instance = &instance[0..index_start];
+            }
+        }
+        elements.instance_name = Some(instance.to_owned());
+    } else {
+        elements.object_name = object.to_owned();
+    }
+    if elements.object_name.is_empty() || elements.counter_name.is_empty() {
+        return None;
+    }
+    Some(elements)
+}
+
+/// Returns the canonical spelling of a counter path. PDH names are case
+/// insensitive and the first instance of a name may be written with or
+/// without `#0` so paths that differ only in those ways canonicalize to the
+/// same string. Paths that don't parse are returned lowercased as is.
+pub fn canonical_counter_path(path: &str) -> String {
+    match parse_counter_path(path) {
+        Some(mut elements) => {
+            if elements.instance_index == Some(0) {
+                elements.instance_index = None;
+            }
+            elements.to_path().to_lowercase()
+        }
+        None => path.to_lowercase(),
+    }
+}
+
+/// Sorts a list of counter paths by their canonical spelling and removes
+/// any that canonicalize to the same path keeping the first spelling seen.
+/// The sort is stable so the output is deterministic for the same input.
+pub fn sort_unique_counter_paths(paths: &mut Vec<String>) {
+    let mut keyed = paths
+        .drain(0..)
+        .map(|p| (canonical_counter_path(&p), p))
+        .collect::<Vec<(String, String)>>();
+    keyed.sort_by(|a, b| a.0.cmp(&b.0));
+    keyed.dedup_by(|a, b| a.0 == b.0);
+    paths.extend(keyed.into_iter().map(|(_, p)| p));
+}
+
+/// Numbers repeated instance names `name#1`, `name#2` and so on the way
+/// expanded counter paths do. The first instance of a name is left as is.
+/// PDH lists each instance of a name without disambiguating them when
+/// enumerating items or formatting counter arrays.
+pub fn number_instances<'a, I>(instances: I) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    let mut numbered = Vec::new();
+    for instance in instances {
+        let count = seen.entry(instance).or_insert(0);
+        if *count == 0 || instance.is_empty() {
+            numbered.push(instance.to_owned());
+        } else {
+            numbered.push(format!("{}#{}", instance, count));
+        }
+        *count += 1;
+    }
+    numbered
+}
+
+/// Parses a counter path into its elements using `PdhParseCounterPathW`. This
+/// is the authoritative parse of a path but unlike parse_counter_path it
+/// calls into PDH which fills in the local machine name when the path has
+/// none.
+pub fn parse_counter_path_pdh(path: &str) -> Result<CounterPathElements, PdhError> {
+    let mut wide_path = path.encode_utf16().collect::<Vec<u16>>();
+    wide_path.push(0);
+    let mut buffer_size: DWORD = 0;
+    // The first time we call this to find out what the required buffer
+    // size is.
+    let mut status =
+        unsafe { PdhParseCounterPathW(wide_path.as_ptr(), null_mut(), &mut buffer_size, 0) } as u32;
+    if status != PDH_MORE_DATA {
+        return Err(status.into());
+    }
+    // The elements struct is followed by the strings it points to. We use a
+    // u64 buffer to get the alignment the struct requires.
+    let mut buffer = vec![0u64; (buffer_size as usize + 7) / 8];
+    status = unsafe {
+        PdhParseCounterPathW(
+            wide_path.as_ptr(),
+            buffer.as_mut_ptr() as *mut PDH_COUNTER_PATH_ELEMENTS_W,
+            &mut buffer_size,
+            0,
+        )
+    } as u32;
+    if status != ERROR_SUCCESS {
+        return Err(status.into());
+    }
+    let elements = unsafe { &*(buffer.as_ptr() as *const PDH_COUNTER_PATH_ELEMENTS_W) };
+    unsafe {
+        Ok(CounterPathElements {
+            machine_name: wide_ptr_to_option(elements.szMachineName)
+                .map(|m| m.trim_start_matches('\\').to_owned()),
+            object_name: wide_ptr_to_option(elements.szObjectName).unwrap_or_default(),
+            parent_instance: wide_ptr_to_option(elements.szParentInstance),
+            instance_name: wide_ptr_to_option(elements.szInstanceName),
+            instance_index: match elements.dwInstanceIndex {
+                0 => None,
+                idx => Some(idx),
+            },
+            counter_name: wide_ptr_to_option(elements.szCounterName).unwrap_or_default(),
+        })
+    }
+}
+
+/// Parses a counter path with `PdhParseCounterPathW` like
+/// parse_counter_path_pdh but only keeps the machine name if the path names
+/// one. Use this where a path without a machine means the local machine.
+pub fn parse_counter_path_pdh_as_written(path: &str) -> Result<CounterPathElements, PdhError> {
+    let mut elements = parse_counter_path_pdh(path)?;
+    if !path.starts_with("\\\\") {
+        elements.machine_name = None;
+    }
+    Ok(elements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn elements(
+        object: &str,
+        parent: Option<&str>,
+        instance: Option<&str>,
+        index: Option<u32>,
+        counter: &str,
+    ) -> CounterPathElements {
+        CounterPathElements {
+            machine_name: None,
+            object_name: object.to_owned(),
+            parent_instance: parent.map(|p| p.to_owned()),
+            instance_name: instance.map(|i| i.to_owned()),
+            instance_index: index,
+            counter_name: counter.to_owned(),
+        }
+    }
+
+    #[test]
+    fn parses_parenthesized_instance() {
+        let path = r"\Process(foo (x86))\% Processor Time";
+        let parsed = parse_counter_path(path).unwrap();
+        assert_eq!(
+            parsed,
+            elements("Process", None, Some("foo (x86)"), None, "% Processor Time")
+        );
+        assert_eq!(parsed.to_path(), path);
+    }
+
+    #[test]
+    fn parses_nested_paren_instance() {
+        let path = r"\Process(a(b(c))#2)\ID Process";
+        let parsed = parse_counter_path(path).unwrap();
+        assert_eq!(
+            parsed,
+            elements("Process", None, Some("a(b(c))"), Some(2), "ID Process")
+        );
+        assert_eq!(parsed.to_path(), path);
+    }
+
+    #[test]
+    fn parses_parent_and_machine() {
+        let parsed = parse_counter_path(r"\\host\Thread(svc(1)/3)\Context Switches/sec").unwrap();
+        assert_eq!(parsed.machine_name, Some("host".to_owned()));
+        assert_eq!(parsed.parent_instance, Some("svc(1)".to_owned()));
+        assert_eq!(parsed.instance_name, Some("3".to_owned()));
+        assert_eq!(parsed.counter_name, "Context Switches/sec");
+    }
+
+    #[test]
+    fn repeated_instances_are_numbered_in_order() {
+        assert_eq!(
+            number_instances(vec!["svchost", "init", "svchost", "svchost", "init"]),
+            vec!["svchost", "init", "svchost#1", "svchost#2", "init#1"]
+        );
+    }
+
+    #[test]
+    fn empty_instances_are_never_numbered() {
+        assert_eq!(number_instances(vec!["", ""]), vec!["", ""]);
+        assert!(number_instances(Vec::new()).is_empty());
+    }
+}
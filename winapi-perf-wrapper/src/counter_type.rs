@@ -0,0 +1,231 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Decoding of performance counter types.
+//!
+//! A counter type is a bit field built from the `PERF_*` flags in winperf.h
+//! describing the size of the raw value, how it is calculated and how it is
+//! displayed.
+
+// Size of the raw value.
+pub const PERF_SIZE_DWORD: u32 = 0x00000000;
+pub const PERF_SIZE_LARGE: u32 = 0x00000100;
+pub const PERF_SIZE_ZERO: u32 = 0x00000200;
+pub const PERF_SIZE_VARIABLE_LEN: u32 = 0x00000300;
+pub const PERF_SIZE_MASK: u32 = 0x00000300;
+
+// The counter's basic type.
+pub const PERF_TYPE_NUMBER: u32 = 0x00000000;
+pub const PERF_TYPE_COUNTER: u32 = 0x00000400;
+pub const PERF_TYPE_TEXT: u32 = 0x00000800;
+pub const PERF_TYPE_ZERO: u32 = 0x00000C00;
+pub const PERF_TYPE_MASK: u32 = 0x00000C00;
+
+// Subtypes of PERF_TYPE_NUMBER.
+pub const PERF_NUMBER_HEX: u32 = 0x00000000;
+pub const PERF_NUMBER_DECIMAL: u32 = 0x00010000;
+pub const PERF_NUMBER_DEC_1000: u32 = 0x00020000;
+
+// Subtypes of PERF_TYPE_COUNTER.
+pub const PERF_COUNTER_VALUE: u32 = 0x00000000;
+pub const PERF_COUNTER_RATE: u32 = 0x00010000;
+pub const PERF_COUNTER_FRACTION: u32 = 0x00020000;
+pub const PERF_COUNTER_BASE: u32 = 0x00030000;
+pub const PERF_COUNTER_ELAPSED: u32 = 0x00040000;
+pub const PERF_COUNTER_QUEUELEN: u32 = 0x00050000;
+pub const PERF_COUNTER_HISTOGRAM: u32 = 0x00060000;
+pub const PERF_COUNTER_PRECISION: u32 = 0x00070000;
+pub const PERF_SUBTYPE_MASK: u32 = 0x000F0000;
+
+// The time base of rate counters.
+pub const PERF_TIMER_TICK: u32 = 0x00000000;
+pub const PERF_TIMER_100NS: u32 = 0x00100000;
+pub const PERF_OBJECT_TIMER: u32 = 0x00200000;
+pub const PERF_TIMER_MASK: u32 = 0x00300000;
+
+// Calculation modifiers.
+pub const PERF_DELTA_COUNTER: u32 = 0x00400000;
+pub const PERF_DELTA_BASE: u32 = 0x00800000;
+pub const PERF_INVERSE_COUNTER: u32 = 0x01000000;
+pub const PERF_MULTI_COUNTER: u32 = 0x02000000;
+
+// How the value is displayed.
+pub const PERF_DISPLAY_NO_SUFFIX: u32 = 0x00000000;
+pub const PERF_DISPLAY_PER_SEC: u32 = 0x10000000;
+pub const PERF_DISPLAY_PERCENT: u32 = 0x20000000;
+pub const PERF_DISPLAY_SECONDS: u32 = 0x30000000;
+pub const PERF_DISPLAY_NOSHOW: u32 = 0x40000000;
+pub const PERF_DISPLAY_MASK: u32 = 0xF0000000;
+
+// Common counter types built from the flags above.
+pub const PERF_COUNTER_COUNTER: u32 = 0x10410400;
+pub const PERF_COUNTER_BULK_COUNT: u32 = 0x10410500;
+pub const PERF_COUNTER_QUEUELEN_TYPE: u32 = 0x00450400;
+pub const PERF_COUNTER_RAWCOUNT: u32 = 0x00010000;
+pub const PERF_COUNTER_LARGE_RAWCOUNT: u32 = 0x00010100;
+pub const PERF_RAW_FRACTION: u32 = 0x20020400;
+pub const PERF_RAW_BASE: u32 = 0x40030403;
+pub const PERF_100NSEC_TIMER: u32 = 0x20510500;
+pub const PERF_ELAPSED_TIME: u32 = 0x30240500;
+
+/// A decoded performance counter type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CounterType(pub u32);
+
+impl CounterType {
+    /// The raw `PERF_*` bit field.
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if this type is of the kind described by pattern, a
+    /// combination of the `PERF_*` constants above. Each enumerated field
+    /// (size, type, subtype, time base and display) set in pattern must be
+    /// equal to this type's rather than merely share bits with it and every
+    /// calculation modifier in pattern must be set. Fields left zero in
+    /// pattern match anything except that the type is always compared when
+    /// the subtype is since subtypes mean different things for each type.
+    /// e.g. `PERF_TYPE_COUNTER | PERF_COUNTER_RATE` matches every rate
+    /// counter whatever its size or display.
+    pub fn matches(&self, pattern: u32) -> bool {
+        let field_matches = |mask: u32| pattern & mask == 0 || self.0 & mask == pattern & mask;
+        let modifiers =
+            PERF_DELTA_COUNTER | PERF_DELTA_BASE | PERF_INVERSE_COUNTER | PERF_MULTI_COUNTER;
+        let type_matches = if pattern & PERF_SUBTYPE_MASK != 0 {
+            self.base_type() == pattern & PERF_TYPE_MASK
+        } else {
+            field_matches(PERF_TYPE_MASK)
+        };
+        type_matches
+            && field_matches(PERF_SIZE_MASK)
+            && field_matches(PERF_SUBTYPE_MASK)
+            && field_matches(PERF_TIMER_MASK)
+            && field_matches(PERF_DISPLAY_MASK)
+            && self.0 & pattern & modifiers == pattern & modifiers
+    }
+
+    /// One of the `PERF_SIZE_*` constants.
+    pub fn size(&self) -> u32 {
+        self.0 & PERF_SIZE_MASK
+    }
+
+    /// The width in bits of the raw value if it is a fixed size integer.
+    pub fn width_bits(&self) -> Option<u32> {
+        match self.size() {
+            PERF_SIZE_DWORD => Some(32),
+            PERF_SIZE_LARGE => Some(64),
+            _ => None,
+        }
+    }
+
+    /// One of the `PERF_TYPE_*` constants.
+    pub fn base_type(&self) -> u32 {
+        self.0 & PERF_TYPE_MASK
+    }
+
+    /// One of the `PERF_COUNTER_*` or `PERF_NUMBER_*` constants depending on
+    /// the base type.
+    pub fn subtype(&self) -> u32 {
+        self.0 & PERF_SUBTYPE_MASK
+    }
+
+    /// One of the `PERF_DISPLAY_*` constants.
+    pub fn display(&self) -> u32 {
+        self.0 & PERF_DISPLAY_MASK
+    }
+
+    /// Returns true if the value is computed from the change between two
+    /// samples. These need two collections before they report valid data.
+    pub fn is_rate(&self) -> bool {
+        self.base_type() == PERF_TYPE_COUNTER && self.subtype() == PERF_COUNTER_RATE
+    }
+
+    /// Returns true if the value is the time elapsed since the raw value's
+    /// start time such as a system or process up time. PDH formats these in
+    /// seconds.
+    pub fn is_elapsed(&self) -> bool {
+        self.base_type() == PERF_TYPE_COUNTER && self.subtype() == PERF_COUNTER_ELAPSED
+    }
+
+    /// Returns true if the value is a plain integer such as a count of
+    /// items or bytes rather than something calculated from the raw values.
+    pub fn is_integer(&self) -> bool {
+        self.base_type() == PERF_TYPE_NUMBER && self.subtype() != PERF_NUMBER_DEC_1000
+    }
+
+    /// Returns true if the value is displayed as a percentage.
+    pub fn is_percent(&self) -> bool {
+        self.display() == PERF_DISPLAY_PERCENT
+    }
+
+    /// Returns true if the value is a length of time in seconds. Either an
+    /// elapsed time or a counter displayed in seconds.
+    pub fn is_duration(&self) -> bool {
+        self.is_elapsed() || self.display() == PERF_DISPLAY_SECONDS
+    }
+
+    /// Returns true if this is a base counter used only to calculate other
+    /// counters.
+    pub fn is_base(&self) -> bool {
+        self.base_type() == PERF_TYPE_COUNTER && self.subtype() == PERF_COUNTER_BASE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_compares_fields_for_equality() {
+        let rate = PERF_TYPE_COUNTER | PERF_COUNTER_RATE;
+        assert!(CounterType(PERF_COUNTER_COUNTER).matches(rate));
+        assert!(CounterType(PERF_COUNTER_BULK_COUNT).matches(rate));
+        assert!(CounterType(PERF_100NSEC_TIMER).matches(rate));
+        // The queue length subtype shares the rate subtype's bit.
+        assert!(!CounterType(PERF_COUNTER_QUEUELEN_TYPE).matches(rate));
+        // A number's decimal subtype has the same bits as a counter's rate.
+        assert!(!CounterType(PERF_COUNTER_RAWCOUNT).matches(rate));
+        assert!(CounterType(PERF_COUNTER_RAWCOUNT).matches(PERF_TYPE_NUMBER | PERF_NUMBER_DECIMAL));
+        assert!(!CounterType(PERF_COUNTER_COUNTER).matches(PERF_TYPE_NUMBER | PERF_NUMBER_DECIMAL));
+        // The seconds display shares the per second display's bit.
+        assert!(CounterType(PERF_ELAPSED_TIME).matches(PERF_DISPLAY_SECONDS));
+        assert!(!CounterType(PERF_COUNTER_COUNTER).matches(PERF_DISPLAY_SECONDS));
+        assert!(CounterType(PERF_COUNTER_LARGE_RAWCOUNT).matches(PERF_SIZE_LARGE));
+        assert!(!CounterType(PERF_COUNTER_RAWCOUNT).matches(PERF_SIZE_LARGE));
+    }
+
+    #[test]
+    fn classifies_common_counter_types() {
+        let timer = CounterType(PERF_100NSEC_TIMER);
+        assert!(timer.is_rate() && timer.is_percent() && !timer.is_duration());
+        let fraction = CounterType(PERF_RAW_FRACTION);
+        assert!(!fraction.is_rate() && fraction.is_percent() && !fraction.is_duration());
+        let raw = CounterType(PERF_COUNTER_RAWCOUNT);
+        assert!(!raw.is_rate() && !raw.is_percent() && !raw.is_duration());
+        assert!(raw.is_integer());
+        let elapsed = CounterType(PERF_ELAPSED_TIME);
+        assert!(!elapsed.is_rate() && !elapsed.is_percent() && elapsed.is_duration());
+        assert!(elapsed.is_elapsed());
+        assert!(CounterType(PERF_RAW_BASE).is_base());
+    }
+
+    #[test]
+    fn matches_requires_every_modifier() {
+        assert!(CounterType(PERF_COUNTER_COUNTER).matches(PERF_DELTA_COUNTER));
+        assert!(
+            !CounterType(PERF_COUNTER_COUNTER).matches(PERF_DELTA_COUNTER | PERF_INVERSE_COUNTER)
+        );
+        assert!(CounterType(PERF_COUNTER_COUNTER).matches(PERF_COUNTER_COUNTER));
+        assert!(!CounterType(PERF_COUNTER_RAWCOUNT).matches(PERF_COUNTER_COUNTER));
+    }
+}
@@ -0,0 +1,355 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Performance counter log files (.blg, .csv, .tsv) bound as data sources.
+use std::path::{Path, PathBuf};
+use std::ptr::{null, null_mut};
+
+use winapi::shared::minwindef::{DWORD, FALSE};
+use winapi::shared::winerror::ERROR_SUCCESS;
+use winapi::um::pdh::{
+    PdhBindInputDataSourceW, PdhCloseLog, PdhEnumMachinesHW, PdhEnumObjectItemsHW,
+    PdhEnumObjectsHW, PDH_HLOG, PERF_DETAIL_STANDARD,
+};
+
+use crate::constants::*;
+use crate::{normalize_machine_name, null_separated_to_vec, str_to_utf16, zeroed_buffer};
+use crate::{PdhCounter, PdhQuery, PDH};
+
+/// Where a PDH reads its counters from. See `PDH::with_data_source`.
+///
+/// Paths added to queries on a log source are validated against the log
+/// rather than the live machine so only counters present in the log can be
+/// added. Queries on a log source return the next sample in the log each
+/// time they are collected starting from the beginning of the log and fail
+/// with `PDH_NO_MORE_DATA` once they reach its end. Rate counters are
+/// calculated between consecutive samples in the log.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataSource {
+    /// The live counters of the configured machine.
+    Live,
+    /// A binary (.blg) counter log.
+    LogFile(PathBuf),
+    /// Several counter logs read as one. Their samples are merged in time
+    /// order.
+    LogFiles(Vec<PathBuf>),
+    /// A comma or tab separated counter log written by perfmon or relog.
+    CsvFile(PathBuf),
+}
+
+/// A performance counter log file bound as a PDH data source.
+pub struct LogDataSource {
+    handle: PDH_HLOG,
+}
+
+impl LogDataSource {
+    /// Binds a log file as a data source.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, PdhError> {
+        Self::open_many(&[path])
+    }
+
+    /// Binds several log files as a single data source.
+    pub fn open_many<P: AsRef<Path>>(paths: &[P]) -> Result<Self, PdhError> {
+        // The file name list is a null separated list terminated by two
+        // nulls.
+        let mut file_list = Vec::new();
+        for path in paths {
+            file_list.extend(str_to_utf16(&path.as_ref().to_string_lossy()));
+        }
+        file_list.push(0);
+        let mut handle: PDH_HLOG = null_mut();
+        let status = unsafe { PdhBindInputDataSourceW(&mut handle, file_list.as_ptr()) } as u32;
+        if status != ERROR_SUCCESS {
+            return Err(status.into());
+        }
+        Ok(Self { handle: handle })
+    }
+
+    /// The PDH log handle for the data source.
+    pub(crate) fn handle(&self) -> PDH_HLOG {
+        self.handle
+    }
+
+    /// Enumerates the machines with counters in the log. The names include
+    /// their leading `\\`.
+    pub fn enumerate_machines(&self) -> Result<Vec<String>, PdhError> {
+        let mut buffer_length: DWORD = 0;
+        let mut status =
+            unsafe { PdhEnumMachinesHW(self.handle, null_mut(), &mut buffer_length) } as u32;
+        if status != PDH_MORE_DATA {
+            return Err(status.into());
+        }
+        let mut machine_list = zeroed_buffer(buffer_length as usize);
+        status = unsafe {
+            PdhEnumMachinesHW(self.handle, machine_list.as_mut_ptr(), &mut buffer_length)
+        } as u32;
+        if status != ERROR_SUCCESS {
+            return Err(status.into());
+        }
+        Ok(to_strings(null_separated_to_vec(&machine_list)))
+    }
+
+    /// Enumerates the objects in the log for a machine. The machine name may
+    /// be given with or without the leading `\\`. An empty name means the
+    /// local machine.
+    pub fn enumerate_objects(&self, machine_name: &str) -> Result<Vec<String>, PdhError> {
+        let machine_name =
+            normalize_machine_name(&machine_name.encode_utf16().collect::<Vec<u16>>());
+        let mut buffer_length: DWORD = 0;
+        let mut status = unsafe {
+            PdhEnumObjectsHW(
+                self.handle,
+                machine_name.as_ref().map_or(null(), |m| m.as_ptr()),
+                null_mut(),
+                &mut buffer_length,
+                PERF_DETAIL_STANDARD,
+                FALSE,
+            )
+        } as u32;
+        if status != PDH_MORE_DATA {
+            return Err(status.into());
+        }
+        let mut object_list = zeroed_buffer(buffer_length as usize);
+        status = unsafe {
+            PdhEnumObjectsHW(
+                self.handle,
+                machine_name.as_ref().map_or(null(), |m| m.as_ptr()),
+                object_list.as_mut_ptr(),
+                &mut buffer_length,
+                PERF_DETAIL_STANDARD,
+                FALSE,
+            )
+        } as u32;
+        if status != ERROR_SUCCESS {
+            return Err(status.into());
+        }
+        Ok(to_strings(null_separated_to_vec(&object_list)))
+    }
+
+    /// Enumerates the counters and instances in the log for an object on a
+    /// machine. The machine name may be given with or without the leading
+    /// `\\`. Returns a tuple of (counters, instances).
+    pub fn enumerate_items(
+        &self,
+        machine_name: &str,
+        object_name: &str,
+    ) -> Result<(Vec<String>, Vec<String>), PdhError> {
+        let machine_name =
+            normalize_machine_name(&machine_name.encode_utf16().collect::<Vec<u16>>());
+        let object_name = str_to_utf16(object_name);
+        let mut counter_list_len: DWORD = 0;
+        let mut instance_list_len: DWORD = 0;
+        let mut status = unsafe {
+            PdhEnumObjectItemsHW(
+                self.handle,
+                machine_name.as_ref().map_or(null(), |m| m.as_ptr()),
+                object_name.as_ptr(),
+                null_mut(),
+                &mut counter_list_len,
+                null_mut(),
+                &mut instance_list_len,
+                PERF_DETAIL_STANDARD,
+                0,
+            )
+        } as u32;
+        if status != PDH_MORE_DATA {
+            return Err(status.into());
+        }
+        let mut counter_list = zeroed_buffer(counter_list_len as usize);
+        let mut instance_list = zeroed_buffer(instance_list_len as usize);
+        status = unsafe {
+            PdhEnumObjectItemsHW(
+                self.handle,
+                machine_name.as_ref().map_or(null(), |m| m.as_ptr()),
+                object_name.as_ptr(),
+                counter_list.as_mut_ptr(),
+                &mut counter_list_len,
+                instance_list.as_mut_ptr(),
+                &mut instance_list_len,
+                PERF_DETAIL_STANDARD,
+                0,
+            )
+        } as u32;
+        if status != ERROR_SUCCESS {
+            return Err(status.into());
+        }
+        Ok((
+            to_strings(null_separated_to_vec(&counter_list)),
+            to_strings(null_separated_to_vec(&instance_list)),
+        ))
+    }
+
+    /// Enumerates the full counter paths present in the log.
+    pub fn enumerate_counters(&self) -> Result<Vec<String>, PdhError> {
+        let mut counter_paths = Vec::new();
+        for machine in self.enumerate_machines()? {
+            for obj in self.enumerate_objects(&machine)? {
+                let (counters, instances) = match self.enumerate_items(&machine, &obj) {
+                    Ok(t) => t,
+                    Err(PdhError::NoObject) => continue,
+                    Err(s) => return Err(s),
+                };
+                if instances.is_empty() {
+                    for c in &counters {
+                        counter_paths.push(format!("{}\\{}\\{}", machine, obj, c));
+                    }
+                }
+                for i in &instances {
+                    for c in &counters {
+                        counter_paths.push(format!("{}\\{}({})\\{}", machine, obj, i, c));
+                    }
+                }
+            }
+        }
+        Ok(counter_paths)
+    }
+}
+
+/// Fills the gaps in a series by interpolating linearly between the valid
+/// values on either side. Gaps at the start or end of the series have
+/// nothing on one side and are left alone.
+pub fn interpolate_gaps(values: &mut Vec<Option<f64>>) {
+    let mut last_valid: Option<usize> = None;
+    for i in 0..values.len() {
+        let value = match values[i] {
+            Some(value) => value,
+            None => continue,
+        };
+        if let Some(start) = last_valid {
+            let start_value = values[start].unwrap();
+            let steps = (i - start) as f64;
+            for j in start + 1..i {
+                let fraction = (j - start) as f64 / steps;
+                values[j] = Some(start_value + (value - start_value) * fraction);
+            }
+        }
+        last_valid = Some(i);
+    }
+}
+
+/// Reads every sample of a set of counters from a log.
+///
+/// Sparse logs are missing some counters at some samples which leaves gaps
+/// in the series. With interpolation the gaps in instantaneous counters are
+/// filled in linearly between the samples on either side. Samples in a log
+/// are usually evenly spaced so we interpolate by sample rather than by
+/// time. Gaps in rate counters are always left alone since a rate
+/// interpolated across a gap doesn't describe anything that happened.
+pub struct LogReplay {
+    query: PdhQuery,
+    counters: Vec<PdhCounter>,
+    /// Whether each counter is a rate counter.
+    rates: Vec<bool>,
+    interpolate: bool,
+}
+
+impl LogReplay {
+    /// Adds the paths to a query on a PDH bound to a log data source.
+    pub fn new(pdh: &PDH, paths: &[&str]) -> Result<Self, PdhError> {
+        let query = pdh.open_query()?;
+        let mut counters = Vec::with_capacity(paths.len());
+        let mut rates = Vec::with_capacity(paths.len());
+        for path in paths {
+            let counter = query.add_counter_string(*path)?;
+            rates.push(counter.counter_type().map_or(false, |t| t.is_rate()));
+            counters.push(counter);
+        }
+        Ok(Self {
+            query: query,
+            counters: counters,
+            rates: rates,
+            interpolate: false,
+        })
+    }
+
+    /// Fill the gaps in instantaneous counters by interpolating between the
+    /// samples on either side.
+    pub fn with_interpolation(mut self, interpolate: bool) -> Self {
+        self.interpolate = interpolate;
+        self
+    }
+
+    /// Reads the rest of the log. Returns a series per counter in the order
+    /// the paths were given with a None for each sample the counter is
+    /// missing from.
+    pub fn read_all(&self) -> Result<Vec<Vec<Option<f64>>>, PdhError> {
+        let mut series = vec![Vec::new(); self.counters.len()];
+        loop {
+            match self.query.collect() {
+                Ok(()) => {}
+                Err(PdhError::NoMoreData) => break,
+                Err(s) => return Err(s),
+            }
+            for (counter, values) in self.counters.iter().zip(series.iter_mut()) {
+                values.push(self.query.format_double_data(counter).ok());
+            }
+        }
+        if self.interpolate {
+            for (values, rate) in series.iter_mut().zip(self.rates.iter()) {
+                if !rate {
+                    interpolate_gaps(values);
+                }
+            }
+        }
+        Ok(series)
+    }
+}
+
+fn to_strings(mut list: Vec<Vec<u16>>) -> Vec<String> {
+    list.drain(0..)
+        .map(|v| String::from_utf16_lossy(v.as_slice()))
+        .collect()
+}
+
+impl Drop for LogDataSource {
+    fn drop(&mut self) {
+        unsafe {
+            PdhCloseLog(self.handle, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaps_are_interpolated_linearly() {
+        let mut values = vec![Some(1.0), None, None, Some(4.0), None, Some(0.0)];
+        interpolate_gaps(&mut values);
+        assert_eq!(
+            values,
+            vec![
+                Some(1.0),
+                Some(2.0),
+                Some(3.0),
+                Some(4.0),
+                Some(2.0),
+                Some(0.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn gaps_at_the_ends_are_left_alone() {
+        let mut values = vec![None, Some(1.0), None, Some(3.0), None];
+        interpolate_gaps(&mut values);
+        assert_eq!(values, vec![None, Some(1.0), Some(2.0), Some(3.0), None]);
+        let mut values = vec![None, None];
+        interpolate_gaps(&mut values);
+        assert_eq!(values, vec![None, None]);
+        let mut values = Vec::new();
+        interpolate_gaps(&mut values);
+        assert!(values.is_empty());
+    }
+}
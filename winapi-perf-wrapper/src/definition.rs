@@ -0,0 +1,51 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Saving the set of counters in a query so it can be rebuilt later.
+//!
+//! With the `serde` feature enabled the definitions can be serialized with
+//! any serde format.
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// How a single counter was added to a query.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CounterDefinition {
+    /// The counter path as it was added.
+    pub path: String,
+    /// Whether the path uses the english object and counter names.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub english: bool,
+    /// The scale factor set for the counter if it was overridden.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub scale: Option<i32>,
+}
+
+impl CounterDefinition {
+    /// Constructs a new CounterDefinition for a localized path.
+    pub fn new<S: Into<String>>(path: S) -> Self {
+        Self {
+            path: path.into(),
+            english: false,
+            scale: None,
+        }
+    }
+}
+
+/// The counters in a query in the order they were added.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct QueryDefinition {
+    pub counters: Vec<CounterDefinition>,
+}
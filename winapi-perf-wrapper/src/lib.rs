@@ -17,31 +17,101 @@
 //! We may add that capability at a later date.
 use winapi::shared::minwindef::{DWORD, FALSE, TRUE};
 use winapi::shared::winerror::ERROR_SUCCESS;
+use winapi::shared::winerror::WAIT_TIMEOUT;
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::pdh::PdhConnectMachineW;
 use winapi::um::pdh::{
     PDH_FMT_COUNTERVALUE_u, PdhAddCounterW, PdhCloseQuery, PdhCollectQueryData,
-    PdhEnumObjectItemsW, PdhEnumObjectsW, PdhExpandCounterPathW, PdhGetFormattedCounterValue,
-    PdhOpenQueryW, PdhRemoveCounter, PdhValidatePathW, PDH_FMT_COUNTERVALUE,
-    PDH_HCOUNTER as HCounter, PDH_HQUERY as HQuery, PERF_DETAIL_STANDARD,
+    PdhCollectQueryDataEx, PdhEnumObjectItemsW, PdhEnumObjectsW, PdhExpandCounterPathW,
+    PdhGetCounterInfoW, PdhGetFormattedCounterArrayW, PdhGetFormattedCounterValue,
+    PdhGetRawCounterValue, PdhOpenQueryH, PdhRemoveCounter, PdhSetCounterScaleFactor,
+    PdhValidatePathExW, PDH_COUNTER_INFO_W, PDH_FMT_COUNTERVALUE, PDH_FMT_COUNTERVALUE_ITEM_W,
+    PDH_HCOUNTER as HCounter, PDH_HQUERY as HQuery, PDH_RAW_COUNTER, PERF_DETAIL_STANDARD,
 };
+use winapi::um::synchapi::{CreateEventW, WaitForSingleObject};
+use winapi::um::winbase::WAIT_OBJECT_0;
+use winapi::um::winnetwk::{
+    WNetAddConnection2W, WNetCancelConnection2W, NETRESOURCEW, RESOURCETYPE_ANY,
+};
+use winapi::um::winnt::HANDLE;
 
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::ptr::null_mut;
+use std::rc::{Rc, Weak};
 use std::time::Duration;
 
+/// How many times we resize a buffer for a PDH call whose required size keeps
+/// changing under us before giving up.
+const MAX_BUFFER_ATTEMPTS: usize = 5;
+
+/// How many extra samples priming takes waiting for valid data once the
+/// skipped samples have been collected.
+const MAX_PRIME_SAMPLES: usize = 5;
+
+/// How many times we validate a counter path before giving up on transient
+/// remote failures.
+const MAX_VALIDATE_ATTEMPTS: u32 = 3;
+
+/// How long we wait before the first retry of a failed validation. Each
+/// retry after that waits twice as long as the last.
+const VALIDATE_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How long priming waits between samples when no interval was given.
+const DEFAULT_PRIME_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait between the two collections rate counters need.
+pub(crate) const RATE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Times a PDH call with the `trace` feature. Without it this is just the
+/// call.
+macro_rules! timed {
+    ($call:ident, $e:expr) => {{
+        #[cfg(feature = "trace")]
+        let start = std::time::Instant::now();
+        let result = $e;
+        #[cfg(feature = "trace")]
+        trace::record(trace::PdhCall::$call, start.elapsed());
+        result
+    }};
+}
+
+pub mod capabilities;
 pub mod constants;
+pub mod counter_path;
+pub mod counter_type;
+pub mod data_source;
+pub mod definition;
+pub mod machine;
+pub mod multi_sz;
+pub mod owned;
+pub mod precise;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod rate;
+pub mod raw;
+pub mod sensors;
+pub mod threshold;
+#[cfg(feature = "trace")]
+pub mod trace;
+#[cfg(feature = "async")]
+pub mod watch;
+pub use capabilities::PdhCapabilities;
+#[allow(deprecated)]
 pub use constants::PDHStatus;
+pub use constants::PdhError;
 use constants::*;
-
-fn null_separated_to_vec(mut buf: Vec<u16>) -> Vec<Vec<u16>> {
-    // The buffer is terminated by two nulls so we pop the last two off
-    // for our partition below to work.
-    buf.pop();
-    buf.pop();
-    let mut v = Vec::new();
-    for item in buf.split(|el| *el == 0) {
-        v.push(item.to_owned());
-    }
-    return v;
-}
+pub use counter_type::CounterType;
+pub use data_source::{DataSource, LogDataSource, LogReplay};
+pub use definition::{CounterDefinition, QueryDefinition};
+pub use machine::{Machine, ProcessStats};
+use multi_sz::null_separated_to_vec;
+pub use owned::OwnedQuery;
+pub use precise::{PreciseSample, PreciseSampler};
+pub use rate::DerivedRateStream;
+pub use raw::{calculate_from_raw, CounterValue, RawCounterValue};
+pub use threshold::{ThresholdEvent, ThresholdWatcher};
 
 fn str_to_utf16(s: &str) -> Vec<u16> {
     let mut v = s.encode_utf16().collect::<Vec<u16>>();
@@ -49,34 +119,376 @@ fn str_to_utf16(s: &str) -> Vec<u16> {
     v
 }
 
+/// Normalizes a machine name into the null terminated `\\HOSTNAME` form that PDH
+/// expects. Names may be passed with or without the leading backslashes.
+/// Returns None for an empty name which PDH would otherwise reject as a
+/// missing machine rather than treat as the local one.
+fn normalize_machine_name(machine_name: &[u16]) -> Option<Vec<u16>> {
+    let backslash = '\\' as u16;
+    let mut name = machine_name;
+    // Trim any null terminators the caller may have provided already.
+    while let Some((0, rest)) = name.split_last() {
+        name = rest;
+    }
+    while let Some((c, rest)) = name.split_first() {
+        if *c != backslash {
+            break;
+        }
+        name = rest;
+    }
+    if name.is_empty() {
+        return None;
+    }
+    let mut v = Vec::with_capacity(name.len() + 3);
+    v.push(backslash);
+    v.push(backslash);
+    v.extend_from_slice(name);
+    v.push(0);
+    Some(v)
+}
+
+/// Returns the `\\HOSTNAME` prefix for counter paths on a normalized
+/// machine name or an empty string for the local machine.
+fn path_prefix(machine_name: &Option<Vec<u16>>) -> String {
+    if let Some(ref machine_name) = machine_name {
+        // Leave off the null terminator.
+        String::from_utf16_lossy(&machine_name[..machine_name.len() - 1])
+    } else {
+        String::new()
+    }
+}
+
+/// Rejects a machine name that is only whitespace. An empty name is allowed
+/// since it means the local machine.
+fn check_machine_name(machine_name: &str) -> Result<(), PdhError> {
+    let name = machine_name.trim_start_matches('\\');
+    if !name.is_empty() && name.trim().is_empty() {
+        return Err(PdhError::InvalidMachineName);
+    }
+    Ok(())
+}
+
+/// Reads a null terminated utf16 string from a pointer.
+pub(crate) unsafe fn wide_ptr_to_string(ptr: *const u16) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    String::from_utf16_lossy(std::slice::from_raw_parts(ptr, len))
+}
+
+/// Takes samples until one has no counters warming up or max_samples have
+/// been taken waiting interval before each. sample collects once and
+/// returns whether any counter is still warming up. Returns how many samples
+/// were taken.
+fn sample_until_warm<F>(
+    max_samples: usize,
+    interval: Duration,
+    mut sample: F,
+) -> Result<usize, PdhError>
+where
+    F: FnMut() -> Result<bool, PdhError>,
+{
+    for taken in 1..=max_samples {
+        std::thread::sleep(interval);
+        if !sample()? {
+            return Ok(taken);
+        }
+    }
+    Ok(max_samples)
+}
+
+/// Removes exact duplicates from a list keeping the first of each.
+fn dedup_in_order(items: Vec<Vec<u16>>) -> Vec<Vec<u16>> {
+    let mut seen = HashSet::new();
+    items
+        .into_iter()
+        .filter(|item| seen.insert(item.clone()))
+        .collect()
+}
+
+/// Splits the counter and instance lists `PdhEnumObjectItemsW` fills in.
+/// Repeated counter names are dropped but repeated instance names are kept
+/// since each is a distinct instance.
+fn split_object_items(
+    counter_list: &[u16],
+    instance_list: &[u16],
+) -> (Vec<Vec<u16>>, Vec<Vec<u16>>) {
+    (
+        dedup_in_order(null_separated_to_vec(counter_list)),
+        null_separated_to_vec(instance_list),
+    )
+}
+
 fn zeroed_buffer(sz: usize) -> Vec<u16> {
     let mut v = Vec::with_capacity(sz);
     v.resize(sz, Default::default());
     return v;
 }
 
+/// Overwrites a buffer with zeros in a way the compiler won't optimize away.
+fn zero_volatile<T: Default>(buffer: &mut [T]) {
+    for c in buffer.iter_mut() {
+        unsafe { std::ptr::write_volatile(c, T::default()) };
+    }
+}
+
+/// A null terminated utf16 copy of a secret that is zeroed when dropped.
+struct WideSecret(Vec<u16>);
+
+impl WideSecret {
+    /// Copies a secret into a buffer allocated once up front. A utf16
+    /// encoding never has more units than the utf8 has bytes so the buffer
+    /// never grows, which would free a copy of the secret without zeroing it.
+    fn new(secret: &str) -> Self {
+        let mut wide = Vec::with_capacity(secret.len() + 1);
+        wide.extend(secret.encode_utf16());
+        wide.push(0);
+        WideSecret(wide)
+    }
+}
+
+impl Drop for WideSecret {
+    fn drop(&mut self) {
+        zero_volatile(&mut self.0);
+    }
+}
+
 /// PDH api integration for an optional machine name.
 pub struct PDH {
     // TODO(jwall): Do we need interior mutability here?
     /// If None then use localhost. If set then use that machine_name.
     machine_name: Option<Vec<u16>>,
+    /// The null terminated `\\HOSTNAME\IPC$` share we connected to with
+    /// explicit credentials if any.
+    ipc_share: Option<Vec<u16>>,
+    /// Counter types we've already looked up by path.
+    counter_types: HashMap<String, CounterType>,
+    /// Object names we've already enumerated for path completion.
+    completion_objects: Option<Vec<String>>,
+    /// Counter names we've already enumerated for path completion by object.
+    completion_counters: HashMap<String, Vec<String>>,
+    /// The size in characters to start enumeration buffers at.
+    buffer_hint: Option<usize>,
+    /// The log file queries read from instead of live counters if any.
+    data_source: Option<Rc<LogDataSource>>,
+    /// The only objects counter enumeration looks at if set.
+    object_allowlist: Option<Vec<String>>,
 }
 
 impl PDH {
     /// Constructs a new PDH instance.
     pub fn new() -> Self {
-        Self { machine_name: None }
+        Self {
+            machine_name: None,
+            ipc_share: None,
+            counter_types: HashMap::new(),
+            completion_objects: None,
+            completion_counters: HashMap::new(),
+            buffer_hint: None,
+            data_source: None,
+            object_allowlist: None,
+        }
+    }
+
+    /// Connects to a remote machine using explicit credentials.
+    ///
+    /// PDH has no way to pass credentials itself and always uses those of
+    /// the current process. So we first establish an authenticated
+    /// connection to the machine's `IPC$` share with `WNetAddConnection2W`
+    /// and then connect PDH to the machine. The share connection is torn
+    /// down by `disconnect` or when the PDH is dropped.
+    ///
+    /// Security considerations:
+    ///
+    /// * The share connection belongs to the logon session and not just this
+    ///   process. Other processes in the same session can reuse it to access
+    ///   the remote machine as `user` while it is open.
+    /// * Windows allows only one set of credentials per server per logon
+    ///   session. This fails if the session is already connected to the
+    ///   machine as a different user.
+    /// * Use an account that only has the access it needs, e.g. a member of
+    ///   the remote machine's Performance Monitor Users group.
+    /// * The password is taken by value so that its bytes can be overwritten
+    ///   along with our utf16 copy of it before this returns. Any other copy
+    ///   the caller keeps is theirs to clear. Callers should avoid taking it
+    ///   from the command line where it is visible to other users.
+    ///
+    /// Errors from the share connection are returned as their win32 error
+    /// codes.
+    pub fn connect_with_credentials<S: Into<String>>(
+        machine_name: S,
+        user: S,
+        mut password: String,
+    ) -> Result<Self, PdhError> {
+        let password = {
+            let wide = WideSecret::new(&password);
+            // Zeros are valid utf8 so the String stays well formed.
+            zero_volatile(unsafe { password.as_mut_vec() });
+            wide
+        };
+        let mut pdh = Self::new().try_with_machine_name_str(machine_name)?;
+        // There is no share to connect to without a machine.
+        if pdh.machine_name.is_none() {
+            return Err(PdhError::InvalidMachineName);
+        }
+        let mut ipc_share = pdh.path_prefix().encode_utf16().collect::<Vec<u16>>();
+        ipc_share.extend("\\IPC$".encode_utf16());
+        ipc_share.push(0);
+        let user = str_to_utf16(&user.into());
+        let mut resource = NETRESOURCEW {
+            dwScope: 0,
+            dwType: RESOURCETYPE_ANY,
+            dwDisplayType: 0,
+            dwUsage: 0,
+            lpLocalName: null_mut(),
+            lpRemoteName: ipc_share.as_mut_ptr(),
+            lpComment: null_mut(),
+            lpProvider: null_mut(),
+        };
+        let status =
+            unsafe { WNetAddConnection2W(&mut resource, password.0.as_ptr(), user.as_ptr(), 0) };
+        drop(password);
+        if status != ERROR_SUCCESS {
+            return Err(status.into());
+        }
+        // From here on dropping the pdh tears down the share connection.
+        pdh.ipc_share = Some(ipc_share);
+        pdh.connect()?;
+        Ok(pdh)
+    }
+
+    /// Connects PDH to the configured machine. This is done implicitly when
+    /// a query uses the machine but connecting up front reports problems
+    /// reaching it early.
+    pub fn connect(&self) -> Result<(), PdhError> {
+        let machine_name = match self.machine_name {
+            Some(ref machine_name) => machine_name.as_ptr(),
+            None => return Ok(()),
+        };
+        let status = unsafe { PdhConnectMachineW(machine_name) } as u32;
+        if status != ERROR_SUCCESS {
+            return Err(status.into());
+        }
+        Ok(())
+    }
+
+    /// Tears down the share connection established by
+    /// `connect_with_credentials` if there is one.
+    pub fn disconnect(&mut self) -> Result<(), PdhError> {
+        if let Some(ipc_share) = self.ipc_share.take() {
+            let status = unsafe { WNetCancelConnection2W(ipc_share.as_ptr(), 0, TRUE) };
+            if status != ERROR_SUCCESS {
+                return Err(status.into());
+            }
+        }
+        Ok(())
     }
 
-    /// Sets the machine name for this PDH instance.
+    /// Sets the machine name for this PDH instance. Accepts a hostname,
+    /// cluster name or FQDN with or without the leading `\\`. An empty name
+    /// means the local machine.
     pub fn with_machine_name(mut self, machine_name: Vec<u16>) -> Self {
-        self.machine_name = Some(machine_name);
         // We need our machine_name to be a null terminated string.
+        self.machine_name = normalize_machine_name(&machine_name);
+        self
+    }
+
+    /// Sets the machine name for this PDH instance from a string. Accepts a
+    /// hostname, cluster name or FQDN with or without the leading `\\`.
+    pub fn with_machine_name_str<S: Into<String>>(self, machine_name: S) -> Self {
+        self.with_machine_name(machine_name.into().encode_utf16().collect())
+    }
+
+    /// Sets the machine name like with_machine_name_str but returns
+    /// `INVALID_MACHINE_NAME` for a name that is only whitespace instead of
+    /// leaving PDH to fail later with `PDH_CSTATUS_NO_MACHINE`. An empty
+    /// name still means the local machine.
+    pub fn try_with_machine_name_str<S: Into<String>>(
+        self,
+        machine_name: S,
+    ) -> Result<Self, PdhError> {
+        let machine_name = machine_name.into();
+        check_machine_name(&machine_name)?;
+        Ok(self.with_machine_name_str(machine_name))
+    }
+
+    /// Sets the size in characters of the buffers enumeration starts with.
+    /// With a big enough hint enumeration fills the buffer on the first call
+    /// instead of first asking PDH for the size it needs. If the hint is too
+    /// small we fall back to asking.
+    pub fn with_buffer_hint(mut self, buffer_hint: usize) -> Self {
+        self.buffer_hint = Some(buffer_hint);
+        self
+    }
+
+    /// Restricts counter enumeration to the listed objects. Enumeration goes
+    /// straight to the items of each listed object instead of enumerating
+    /// every object first which is much faster on machines with many
+    /// objects. Objects that don't exist on the machine are skipped
+    /// silently. Pass an empty list to enumerate everything again.
+    pub fn set_object_allowlist(&mut self, objects: &[&str]) {
+        self.object_allowlist = if objects.is_empty() {
+            None
+        } else {
+            Some(objects.iter().map(|o| (*o).to_owned()).collect())
+        };
+    }
+
+    /// The objects counter enumeration looks at. Either the allowlist or
+    /// every object on the machine.
+    fn enumeration_objects(&mut self) -> Result<Vec<String>, PdhError> {
+        match self.object_allowlist {
+            Some(ref objects) => Ok(objects.clone()),
+            None => self.enumerate_objects_string(),
+        }
+    }
+
+    /// Binds a log file so queries opened by this PDH replay the log rather
+    /// than read live counters.
+    pub fn with_log_data_source(mut self, data_source: LogDataSource) -> Self {
+        self.data_source = Some(Rc::new(data_source));
         self
     }
 
+    /// Sets where queries and enumeration read counters from. Binding a log
+    /// fails if the log can't be opened. See DataSource for how log sources
+    /// behave.
+    pub fn with_data_source(mut self, source: DataSource) -> Result<Self, PdhError> {
+        self.data_source = match source {
+            DataSource::Live => None,
+            DataSource::LogFile(path) | DataSource::CsvFile(path) => {
+                Some(Rc::new(LogDataSource::open(path)?))
+            }
+            DataSource::LogFiles(paths) => Some(Rc::new(LogDataSource::open_many(&paths)?)),
+        };
+        Ok(self)
+    }
+
+    /// The machine to enumerate in a bound log. The configured machine if
+    /// there is one or else the first machine in the log.
+    fn log_machine(&self, data_source: &LogDataSource) -> Result<String, PdhError> {
+        if self.machine_name.is_some() {
+            return Ok(self.path_prefix());
+        }
+        data_source
+            .enumerate_machines()?
+            .into_iter()
+            .next()
+            .ok_or(PdhError::NoMachine)
+    }
+
+    /// Returns the `\\HOSTNAME` prefix for counter paths on the configured
+    /// machine or an empty string for the local machine.
+    fn path_prefix(&self) -> String {
+        path_prefix(&self.machine_name)
+    }
+
     /// Enumerates the counter objects for the provided machine or the local machine.
-    pub fn enumerate_objects_string(&mut self) -> Result<Vec<String>, PDHStatus> {
+    pub fn enumerate_objects_string(&mut self) -> Result<Vec<String>, PdhError> {
         self.enumerate_objects_utf16().map(|mut v| {
             v.drain(0..)
                 .map(|v| String::from_utf16_lossy(v.as_slice()))
@@ -84,52 +496,127 @@ impl PDH {
         })
     }
 
+    /// Enumerates only the counter objects that currently have at least one
+    /// counter and, for objects with instances, at least one instance.
+    /// Objects that error when their items are enumerated are skipped. The
+    /// names are sorted. Use `enumerate_objects_string` for every registered
+    /// object.
+    pub fn enumerate_active_objects(&mut self) -> Result<Vec<String>, PdhError> {
+        let query = self.open_query()?;
+        let path_prefix = self.path_prefix();
+        let mut active = Vec::new();
+        for obj in self.enumerate_objects_utf16()? {
+            let (counters, instances) = match self.enumerate_items_utf16(&obj) {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            let counter = match counters.iter().find(|c| !c.is_empty()) {
+                Some(c) => String::from_utf16_lossy(c),
+                None => continue,
+            };
+            let obj = String::from_utf16_lossy(obj.as_slice());
+            // Objects without instances and objects that have none right now
+            // both list a single empty instance. Only a path without an
+            // instance is valid for the former.
+            if instances.iter().all(|i| i.is_empty()) {
+                let path = format!("{}\\{}\\{}", path_prefix, obj, counter);
+                match query.try_add_counter(&path) {
+                    Ok(Some(_)) => {}
+                    _ => continue,
+                }
+            }
+            active.push(obj);
+        }
+        active.sort();
+        active.dedup();
+        Ok(active)
+    }
+
     /// Enumerates the counter objects for the provided machine or the local machine.
-    pub fn enumerate_objects_utf16(&mut self) -> Result<Vec<Vec<u16>>, PDHStatus> {
+    pub fn enumerate_objects_utf16(&mut self) -> Result<Vec<Vec<u16>>, PdhError> {
+        if let Some(data_source) = self.data_source.clone() {
+            let machine = self.log_machine(&data_source)?;
+            return Ok(data_source
+                .enumerate_objects(&machine)?
+                .iter()
+                .map(|o| o.encode_utf16().collect())
+                .collect());
+        }
         let data_source = null_mut();
         let machine_name = if let Some(ref mut machine_name) = self.machine_name {
             machine_name.as_mut_ptr()
         } else {
             null_mut()
         };
-        let mut buffer_length: DWORD = 0;
-        // The first time we call this to find out what the required buffer
-        // size is.
-        let mut status = unsafe {
-            PdhEnumObjectsW(
-                data_source,
-                machine_name,
-                null_mut(),
-                &mut buffer_length,
-                PERF_DETAIL_STANDARD,
-                TRUE,
-            )
-        } as u32;
-        if status == constants::PDH_MORE_DATA {
-            // buffer length should be set to the appropriate length.
-            // Now call it a second time to get the list of objects.
-            // This will be filled with a null separated list of names.
-            let mut object_list = Vec::<u16>::with_capacity(buffer_length as usize);
-            object_list.resize(buffer_length as usize, 0);
-            status = unsafe {
+        // Without a hint the first call is only to find out what the
+        // required buffer size is.
+        let mut object_list = zeroed_buffer(self.buffer_hint.unwrap_or(0));
+        let mut buffer_length = object_list.len() as DWORD;
+        let mut refresh = TRUE;
+        loop {
+            let status = unsafe {
                 PdhEnumObjectsW(
                     data_source,
                     machine_name,
-                    object_list.as_mut_ptr(),
+                    if object_list.is_empty() {
+                        null_mut()
+                    } else {
+                        object_list.as_mut_ptr()
+                    },
                     &mut buffer_length,
                     PERF_DETAIL_STANDARD,
-                    FALSE,
+                    refresh,
                 )
             } as u32;
-            if status == ERROR_SUCCESS {
-                return Ok(null_separated_to_vec(object_list));
-            } else {
-                return Err(status);
+            refresh = FALSE;
+            if status == ERROR_SUCCESS && !object_list.is_empty() {
+                // This will be filled with a null separated list of names.
+                return Ok(null_separated_to_vec(&object_list));
             }
+            if status != constants::PDH_MORE_DATA {
+                // Error! we expected more data here.
+                return Err(status.into());
+            }
+            // buffer length should be set to the appropriate length.
+            // Now call it again to get the list of objects.
+            object_list = zeroed_buffer(buffer_length as usize);
+        }
+    }
+
+    /// Forces PDH to reload its list of performance objects for the
+    /// configured machine and forgets the counter types and completion names
+    /// we've cached.
+    ///
+    /// PDH caches the objects it knows about for the life of the process so
+    /// counters whose provider was installed or repaired after we started
+    /// are reported missing until this is called. It can't fix a corrupt
+    /// counter registry. See `capabilities::COUNTER_CACHE_REMEDIATION` for
+    /// that.
+    pub fn refresh(&mut self) -> Result<(), PdhError> {
+        self.counter_types.clear();
+        self.completion_objects = None;
+        self.completion_counters.clear();
+        let machine_name = if let Some(ref mut machine_name) = self.machine_name {
+            machine_name.as_mut_ptr()
         } else {
-            // Error! we expected more data here.
-            return Err(status);
+            null_mut()
+        };
+        let mut buffer_length: DWORD = 0;
+        // We only want the refresh so we just ask for the buffer size.
+        let status = unsafe {
+            PdhEnumObjectsW(
+                null_mut(),
+                machine_name,
+                null_mut(),
+                &mut buffer_length,
+                PERF_DETAIL_STANDARD,
+                TRUE,
+            )
+        } as u32;
+        if status != ERROR_SUCCESS && status != constants::PDH_MORE_DATA {
+            return Err(status.into());
         }
+        Ok(())
     }
 
     /// Enumerates the objects counter items for the provided machine or the local machine.
@@ -137,7 +624,7 @@ impl PDH {
     pub fn enumerate_items_string<S: Into<String>>(
         &self,
         obj: S,
-    ) -> Result<(Vec<String>, Vec<String>), PDHStatus> {
+    ) -> Result<(Vec<String>, Vec<String>), PdhError> {
         self.enumerate_items_utf16(&str_to_utf16(&obj.into()))
             .map(|(mut cs, mut insts)| {
                 (
@@ -152,113 +639,241 @@ impl PDH {
             })
     }
 
+    /// Enumerates an object's counters and instances for the provided machine
+    /// or the local machine.
+    pub fn enumerate_object_items(&self, obj: &str) -> Result<ObjectItems, PdhError> {
+        let (counters, instances) = self.enumerate_items_string(obj)?;
+        Ok(ObjectItems {
+            path_prefix: self.path_prefix(),
+            object: obj.to_owned(),
+            counters: counters,
+            instances: instances,
+        })
+    }
+
     /// Enumerates the objects counter items for the provided machine or the local machine.
     /// Returns a tuple of (counters, instances) for each of those counters.
+    /// Some providers list the same counter more than once so only the first
+    /// of each counter name is kept. Instances are left as PDH lists them
+    /// since a repeated instance name is a distinct instance.
     pub fn enumerate_items_utf16(
         &self,
         obj: &Vec<u16>,
-    ) -> Result<(Vec<Vec<u16>>, Vec<Vec<u16>>), PDHStatus> {
+    ) -> Result<(Vec<Vec<u16>>, Vec<Vec<u16>>), PdhError> {
+        if let Some(ref data_source) = self.data_source {
+            let machine = self.log_machine(data_source)?;
+            let end = obj.iter().position(|c| *c == 0).unwrap_or(obj.len());
+            let (counters, instances) =
+                data_source.enumerate_items(&machine, &String::from_utf16_lossy(&obj[0..end]))?;
+            let to_utf16 = |v: Vec<String>| {
+                v.iter()
+                    .map(|s| s.encode_utf16().collect())
+                    .collect::<Vec<Vec<u16>>>()
+            };
+            return Ok((dedup_in_order(to_utf16(counters)), to_utf16(instances)));
+        }
         let mut object_name = obj.clone();
-        let mut counter_list_len: DWORD = 0;
-        let mut instance_list_len: DWORD = 0;
-        let mut status = unsafe {
-            PdhEnumObjectItemsW(
-                null_mut(),
-                null_mut(),
-                object_name.as_mut_ptr(),
-                null_mut(),
-                &mut counter_list_len,
-                null_mut(),
-                &mut instance_list_len,
-                PERF_DETAIL_STANDARD,
-                0,
-            )
-        } as PDHStatus;
-        if status == constants::PDH_MORE_DATA {
-            let mut counter_list = zeroed_buffer(counter_list_len as usize);
-            let mut instance_list = zeroed_buffer(instance_list_len as usize);
-            status = unsafe {
+        let machine_name = if let Some(ref machine_name) = self.machine_name {
+            machine_name.as_ptr()
+        } else {
+            std::ptr::null()
+        };
+        // Without a hint the first call is only to find out what the
+        // required buffer sizes are.
+        let hint = self.buffer_hint.unwrap_or(0);
+        let mut counter_list = zeroed_buffer(hint);
+        let mut instance_list = zeroed_buffer(hint);
+        let mut counter_list_len = hint as DWORD;
+        let mut instance_list_len = hint as DWORD;
+        let mut probing = hint == 0;
+        loop {
+            let status = unsafe {
                 PdhEnumObjectItemsW(
                     null_mut(),
-                    null_mut(),
+                    machine_name,
                     object_name.as_mut_ptr(),
-                    counter_list.as_mut_ptr(),
+                    if probing {
+                        null_mut()
+                    } else {
+                        counter_list.as_mut_ptr()
+                    },
                     &mut counter_list_len,
-                    instance_list.as_mut_ptr(),
+                    if probing {
+                        null_mut()
+                    } else {
+                        instance_list.as_mut_ptr()
+                    },
                     &mut instance_list_len,
                     PERF_DETAIL_STANDARD,
                     0,
                 )
-            } as PDHStatus;
-            if status != ERROR_SUCCESS {
-                return Err(status);
+            } as u32;
+            if status == ERROR_SUCCESS && !probing {
+                return Ok(split_object_items(&counter_list, &instance_list));
             }
-            return Ok((
-                null_separated_to_vec(counter_list),
-                null_separated_to_vec(instance_list),
-            ));
-        } else {
-            return Err(status);
+            if status != constants::PDH_MORE_DATA {
+                // The provider settings we can check are this machine's.
+                if self.machine_name.is_none() {
+                    let end = obj.iter().position(|c| *c == 0).unwrap_or(obj.len());
+                    return Err(capabilities::check_provider_disabled(
+                        &String::from_utf16_lossy(&obj[0..end]),
+                        status.into(),
+                    ));
+                }
+                return Err(status.into());
+            }
+            counter_list = zeroed_buffer(counter_list_len as usize);
+            instance_list = zeroed_buffer(instance_list_len as usize);
+            probing = false;
         }
     }
 
     /// Opens a query for the configured machine or the local machine.
-    pub fn open_query(&self) -> Result<PdhQuery, PDHStatus> {
-        let mut query = PdhQuery(null_mut());
-        let status = unsafe { PdhOpenQueryW(null_mut(), 0, query.query()) } as u32;
+    pub fn open_query(&self) -> Result<PdhQuery, PdhError> {
+        self.open_query_h()
+    }
+
+    /// Opens a query against the bound data source with `PdhOpenQueryH`.
+    /// Without a bound log file this is the live data source. Queries on a
+    /// log file read the next sample in the log each time they are collected
+    /// and keep the log open until they are dropped.
+    pub fn open_query_h(&self) -> Result<PdhQuery, PdhError> {
+        let mut query = PdhQuery::new();
+        // H_REALTIME_DATASOURCE is null.
+        let data_source = self
+            .data_source
+            .as_ref()
+            .map_or(null_mut(), |source| source.handle());
+        let status = unsafe { PdhOpenQueryH(data_source, 0, query.query()) } as u32;
 
         if status != ERROR_SUCCESS {
-            return Err(status);
+            return Err(status.into());
         }
+        query.data_source = self.data_source.clone();
         return Ok(query);
     }
 
-    /// Enumerates all of the counter paths on the configured machien or local machine.
-    pub fn enumerate_counters(&mut self) -> Result<Vec<String>, PDHStatus> {
+    /// Reads a single value of a counter on the configured machine with a
+    /// query that is dropped before returning. format is one of PDH_FMT_LONG,
+    /// PDH_FMT_LARGE or PDH_FMT_DOUBLE. Rate counters need two collections
+    /// so pass a prime_delay to collect once more after waiting that long.
+    /// Without one the first value of a rate counter is garbage.
+    pub fn sample_counter<S: Into<String>>(
+        &self,
+        path: S,
+        format: u32,
+        prime_delay: Option<Duration>,
+    ) -> Result<PDH_FMT_COUNTERVALUE, PdhError> {
+        let query = self.open_query()?;
+        let counter = query.add_counter_string(format!("{}{}", self.path_prefix(), path.into()))?;
+        if let Some(delay) = prime_delay {
+            query.collect()?;
+            std::thread::sleep(delay);
+        }
+        query.collect()?;
+        return query.format_data(&counter, format);
+    }
+
+    /// Reads a single value of a counter as an i32. See sample_counter.
+    pub fn sample_long<S: Into<String>>(
+        &self,
+        path: S,
+        prime_delay: Option<Duration>,
+    ) -> Result<i32, PdhError> {
+        let fmt_counter_value = self.sample_counter(path, PDH_FMT_LONG, prime_delay)?;
+        return Ok(unsafe { *fmt_counter_value.u.longValue() });
+    }
+
+    /// Reads a single value of a counter as an i64. See sample_counter.
+    pub fn sample_large<S: Into<String>>(
+        &self,
+        path: S,
+        prime_delay: Option<Duration>,
+    ) -> Result<i64, PdhError> {
+        let fmt_counter_value = self.sample_counter(path, PDH_FMT_LARGE, prime_delay)?;
+        return Ok(unsafe { *fmt_counter_value.u.largeValue() });
+    }
+
+    /// Reads a single value of a counter as an f64. See sample_counter.
+    pub fn sample_double<S: Into<String>>(
+        &self,
+        path: S,
+        prime_delay: Option<Duration>,
+    ) -> Result<f64, PdhError> {
+        let fmt_counter_value = self.sample_counter(path, PDH_FMT_DOUBLE, prime_delay)?;
+        return Ok(unsafe { *fmt_counter_value.u.doubleValue() });
+    }
+
+    /// Opens a query and adds the counters from a definition exported by
+    /// `PdhQuery::export_definition`. Returns the counters in the same order
+    /// as the definition. Dropping a counter removes it from the query so
+    /// callers must hold on to them.
+    pub fn query_from_definition(
+        &self,
+        definition: &QueryDefinition,
+    ) -> Result<(PdhQuery, Vec<PdhCounter>), PdhError> {
+        let query = self.open_query()?;
+        let mut counters = Vec::with_capacity(definition.counters.len());
+        for counter_def in definition.counters.iter() {
+            let counter = if counter_def.english {
+                query.add_english_counter(&counter_def.path)?
+            } else {
+                query.add_counter_string(counter_def.path.as_str())?
+            };
+            if let Some(scale) = counter_def.scale {
+                query.set_counter_scale(&counter, scale)?;
+            }
+            counters.push(counter);
+        }
+        return Ok((query, counters));
+    }
+
+    /// Enumerates all of the counter paths on the configured machien or local machine
+    /// or only those of the objects in the allowlist if one is set.
+    /// The paths are sorted and deduplicated by their canonical spelling so
+    /// the output is the same from one call to the next.
+    pub fn enumerate_counters(&mut self) -> Result<Vec<String>, PdhError> {
+        let (mut paths, _) = self.enumerate_counters_verbose()?;
+        counter_path::sort_unique_counter_paths(&mut paths);
+        return Ok(paths);
+    }
+
+    /// Enumerates all of the counter paths on the configured machine or local
+    /// machine along with the objects that were skipped and why. Objects
+    /// that have gone away or that we lack the privileges to read are
+    /// skipped rather than failing the whole enumeration.
+    pub fn enumerate_counters_verbose(
+        &mut self,
+    ) -> Result<(Vec<String>, Vec<(String, PdhError)>), PdhError> {
+        if let Some(ref data_source) = self.data_source {
+            // Paths in a log always include the machine they came from.
+            return Ok((data_source.enumerate_counters()?, Vec::new()));
+        }
         let mut counter_path_vec = Vec::new();
-        let path_prefix = if let Some(ref machine_name) = self.machine_name {
-            // First we need to pad the machine name with null bytes.
-            format!("\\\\{}", String::from_utf16_lossy(machine_name.as_slice()))
-        } else {
-            String::new()
-        };
-        for obj in self.enumerate_objects_utf16()? {
-            let (counters, instances) = match self.enumerate_items_utf16(&obj) {
-                Ok(t) => t,
-                Err(PDH_CSTATUS_NO_OBJECT) => {
-                    continue;
+        let mut skipped = Vec::new();
+        let allowlisted = self.object_allowlist.is_some();
+        for obj in self.enumeration_objects()? {
+            match self.enumerate_object_items(&obj) {
+                Ok(items) => counter_path_vec.extend(items.paths()),
+                // Allowlisted objects the machine doesn't have aren't
+                // reported as skipped.
+                Err(PdhError::NoObject) if allowlisted => {}
+                Err(s) if s == PdhError::NoObject || is_access_denied(s) => {
+                    skipped.push((obj, s));
                 }
                 Err(s) => return Err(s),
-            };
-            let obj = String::from_utf16_lossy(obj.as_slice());
-            for i in &instances {
-                let i = if i.is_empty() {
-                    String::new()
-                } else {
-                    format!("({})", String::from_utf16_lossy(i))
-                };
-                for c in &counters {
-                    // TODO
-                    counter_path_vec.push(format!(
-                        "{}\\{}{}\\{}",
-                        path_prefix,
-                        obj,
-                        i,
-                        String::from_utf16_lossy(c)
-                    ));
-                }
             }
         }
-        return Ok(counter_path_vec);
+        return Ok((counter_path_vec, skipped));
     }
 
-    pub fn expand_counter_path_utf16(&self, path: &Vec<u16>) -> Result<Vec<Vec<u16>>, PDHStatus> {
+    pub fn expand_counter_path_utf16(&self, path: &Vec<u16>) -> Result<Vec<Vec<u16>>, PdhError> {
         let mut counter_list_len: DWORD = 0;
         let mut status =
             unsafe { PdhExpandCounterPathW(path.as_ptr(), null_mut(), &mut counter_list_len) }
-                as PDHStatus;
+                as u32;
         if status != constants::PDH_MORE_DATA {
-            return Err(status);
+            return Err(status.into());
         }
         let mut unparsed_list = zeroed_buffer(counter_list_len as usize);
         status = unsafe {
@@ -267,17 +882,110 @@ impl PDH {
                 unparsed_list.as_mut_ptr(),
                 &mut counter_list_len,
             )
-        } as PDHStatus;
+        } as u32;
         if status != ERROR_SUCCESS {
-            return Err(status);
+            return Err(status.into());
+        }
+        Ok(null_separated_to_vec(&unparsed_list))
+    }
+
+    /// Enumerates the full counter paths present in a counter log file rather
+    /// than those on a live machine. Equivalent to enumerating counters on a
+    /// PDH bound to `DataSource::LogFile` for the path.
+    pub fn enumerate_log_counters<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+    ) -> Result<Vec<String>, PdhError> {
+        LogDataSource::open(path)?.enumerate_counters()
+    }
+
+    /// Enumerates all of the counter paths on the configured machine or
+    /// local machine along with their types. Sorted and deduplicated the
+    /// same way as `enumerate_counters`.
+    ///
+    /// Finding a counter's type requires adding it to a query and looking up
+    /// its info which is far slower than enumerating the paths. Every
+    /// instance of a counter has the same type though so we only look up one
+    /// per object and counter name rather than one per path. The types are
+    /// cached for the lifetime of this PDH so subsequent calls only pay for
+    /// counters that have appeared since. Counters that can't be added are
+    /// skipped.
+    pub fn enumerate_counters_with_types(
+        &mut self,
+    ) -> Result<Vec<(String, CounterType)>, PdhError> {
+        let query = self.open_query()?;
+        let path_prefix = self.path_prefix();
+        let mut paths = Vec::new();
+        let mut path_types = HashMap::new();
+        for obj in self.enumeration_objects()? {
+            let items = match self.enumerate_object_items(&obj) {
+                Ok(items) => items,
+                Err(s) if s == PdhError::NoObject || is_access_denied(s) => continue,
+                Err(s) => return Err(s),
+            };
+            let mut types = Vec::with_capacity(items.counters.len());
+            for c in &items.counters {
+                // Objects with instances are looked up with a wildcard
+                // instance since any instance will do.
+                let type_path = if items.instances.iter().any(|i| !i.is_empty()) {
+                    format!("{}\\{}(*)\\{}", path_prefix, obj, c)
+                } else {
+                    format!("{}\\{}\\{}", path_prefix, obj, c)
+                };
+                let counter_type = match self.counter_types.get(&type_path) {
+                    Some(counter_type) => Some(*counter_type),
+                    None => {
+                        let info = query
+                            .add_counter_string(type_path.as_str())
+                            .and_then(|counter| query.counter_info(&counter));
+                        match info {
+                            Ok(info) => {
+                                self.counter_types.insert(type_path, info.counter_type);
+                                Some(info.counter_type)
+                            }
+                            Err(_) => None,
+                        }
+                    }
+                };
+                types.push(counter_type);
+            }
+            // The paths go through every counter for one instance before the
+            // next instance.
+            for (idx, path) in items.paths().enumerate() {
+                if let Some(counter_type) = types[idx % types.len()] {
+                    path_types.entry(path.clone()).or_insert(counter_type);
+                    paths.push(path);
+                }
+            }
         }
-        Ok(null_separated_to_vec(unparsed_list))
+        counter_path::sort_unique_counter_paths(&mut paths);
+        Ok(paths
+            .into_iter()
+            .map(|p| {
+                let counter_type = path_types[&p];
+                (p, counter_type)
+            })
+            .collect())
+    }
+
+    /// Enumerates the counter paths whose type matches type_mask as
+    /// `CounterType::matches` does. e.g. `PERF_TYPE_COUNTER |
+    /// PERF_COUNTER_RATE` for all rate counters. See the counter_type module
+    /// for the flags and
+    /// `enumerate_counters_with_types` for the cost of looking up types.
+    pub fn enumerate_counters_of_type(&mut self, type_mask: u32) -> Result<Vec<String>, PdhError> {
+        Ok(self
+            .enumerate_counters_with_types()?
+            .into_iter()
+            .filter(|(_, counter_type)| counter_type.matches(type_mask))
+            .map(|(path, _)| path)
+            .collect())
     }
 
     pub fn expand_counter_path_string<S: Into<String>>(
         &self,
         path: S,
-    ) -> Result<Vec<String>, PDHStatus> {
+    ) -> Result<Vec<String>, PdhError> {
         self.expand_counter_path_utf16(&str_to_utf16(&path.into()))
             .map(|mut ps| {
                 ps.drain(0..)
@@ -285,73 +993,971 @@ impl PDH {
                     .collect()
             })
     }
-}
-
-/// A handle for a PDH Query. Queries can have multiple associated PdhCounters.
-pub struct PdhQuery(HQuery);
 
-impl PdhQuery {
-    /// Convenience query accessor
-    pub fn query(&mut self) -> &mut HQuery {
-        &mut self.0
+    /// Suggests completions for a partial counter path. Depending on how far
+    /// into the path the prefix gets this returns the matching object paths
+    /// (`\Mem`), instance paths (`\Process(chr`) or counter paths
+    /// (`\Memory\Av`, `\Process(chrome)`). Matching is case insensitive and
+    /// the results are sorted. A `\\HOSTNAME` on the prefix is kept on the
+    /// suggestions but the names always come from the configured machine.
+    ///
+    /// Object and counter names are cached for the lifetime of this PDH so
+    /// completing as someone types doesn't enumerate them again on every
+    /// key. Instances come and go so they are always enumerated. Call
+    /// `refresh` to forget the cached names.
+    pub fn complete_path(&mut self, prefix: &str) -> Result<Vec<String>, PdhError> {
+        let (machine, position) = match completion_position(prefix) {
+            Some(split) => split,
+            None => return Ok(Vec::new()),
+        };
+        let starts_with =
+            |name: &str, partial: &str| name.to_lowercase().starts_with(&partial.to_lowercase());
+        let mut candidates = Vec::new();
+        match position {
+            CompletionPosition::Instance { object, partial } => {
+                let (_, instances) = self.enumerate_items_string(object)?;
+                for i in instances.iter().filter(|i| starts_with(i, partial)) {
+                    candidates.push(format!("{}\\{}({})", machine, object, i));
+                }
+            }
+            CompletionPosition::Counter {
+                object,
+                object_and_instance,
+                partial,
+            } => {
+                for c in self
+                    .completion_counters(object)?
+                    .iter()
+                    .filter(|c| starts_with(c, partial))
+                {
+                    candidates.push(format!("{}\\{}\\{}", machine, object_and_instance, c));
+                }
+            }
+            CompletionPosition::Object { partial } => {
+                for o in self
+                    .completion_objects()?
+                    .iter()
+                    .filter(|o| starts_with(o, partial))
+                {
+                    candidates.push(format!("{}\\{}", machine, o));
+                }
+            }
+        }
+        candidates.sort();
+        candidates.dedup();
+        return Ok(candidates);
     }
 
-    /// Adds a performance counter for the given path in utf16 format.
-    pub fn add_counter_utf16(&self, wide_path: Vec<u16>) -> Result<PdhCounter, PDHStatus> {
-        let mut status = unsafe { PdhValidatePathW(wide_path.as_ptr()) } as u32;
-        if status != ERROR_SUCCESS {
-            return Err(status);
+    /// The object names for path completion from the cache if we have them.
+    fn completion_objects(&mut self) -> Result<&Vec<String>, PdhError> {
+        if self.completion_objects.is_none() {
+            self.completion_objects = Some(self.enumerate_objects_string()?);
         }
-        let mut counter_handle: HCounter = null_mut();
-        status =
-            unsafe { PdhAddCounterW(self.0, wide_path.as_ptr(), 0, &mut counter_handle) } as u32;
-        if status != ERROR_SUCCESS {
-            return Err(status);
+        return Ok(self.completion_objects.as_ref().unwrap());
+    }
+
+    /// The counter names of an object for path completion from the cache if
+    /// we have them.
+    fn completion_counters(&mut self, object: &str) -> Result<&Vec<String>, PdhError> {
+        let key = object.to_lowercase();
+        if !self.completion_counters.contains_key(&key) {
+            let (counters, _) = self.enumerate_items_string(object)?;
+            self.completion_counters.insert(key.clone(), counters);
         }
-        return Ok(PdhCounter(counter_handle));
+        return Ok(&self.completion_counters[&key]);
     }
 
-    /// Adds a performance counter for the given path.
-    pub fn add_counter_string<S: Into<String>>(&self, path: S) -> Result<PdhCounter, PDHStatus> {
-        self.add_counter_utf16(str_to_utf16(&path.into()))
+    /// Looks up the explain text for each of the paths. All of the paths are
+    /// added to one temporary query so the query is only set up once but it
+    /// still costs a `PdhGetCounterInfoW` call per counter. Results are
+    /// returned per path in the same order so one bad path doesn't fail the
+    /// rest.
+    pub fn counter_help_bulk(&mut self, paths: &[&str]) -> Vec<(String, Result<String, PdhError>)> {
+        let query = match self.open_query() {
+            Ok(query) => query,
+            Err(s) => return paths.iter().map(|p| (p.to_string(), Err(s))).collect(),
+        };
+        let path_prefix = self.path_prefix();
+        paths
+            .iter()
+            .map(|path| {
+                let full_path = if path.starts_with("\\\\") {
+                    path.to_string()
+                } else {
+                    format!("{}{}", path_prefix, path)
+                };
+                let explain = query
+                    .add_counter_string(full_path)
+                    .and_then(|counter| query.counter_explain(&counter));
+                (path.to_string(), explain)
+            })
+            .collect()
     }
 
-    /// Removes a counter from the query consuming it in the process.
-    #[allow(unused_variables)]
-    pub fn remove_counter(&self, counter_handle: PdhCounter) {
-        // when the counter is dropped it will be removed from the query.
-        // We consume the counter in the process so it can't be reused.
-        // As such this function has no body. It exists only to consume the counter.
+    /// Detects the PDH features available on this version of windows so
+    /// callers can fall back to legacy apis when a newer one is absent.
+    pub fn capabilities() -> PdhCapabilities {
+        capabilities::detect()
     }
 
-    fn collect_data(
+    /// Exercises each major capability of this library against well known
+    /// counters that are always present on the local machine. Returns the
+    /// name and result of each step. Steps that depend on an earlier failed
+    /// step report that step's error.
+    pub fn self_test() -> Vec<(String, Result<(), PdhError>)> {
+        let mut results = Vec::new();
+        let mut pdh = PDH::new();
+        results.push((
+            "enumerate objects".to_owned(),
+            pdh.enumerate_objects_utf16().map(|_| ()),
+        ));
+        results.push((
+            "enumerate items for Memory".to_owned(),
+            pdh.enumerate_items_string("Memory").map(|_| ()),
+        ));
+        let query = pdh.open_query();
+        results.push((
+            "open query".to_owned(),
+            query.as_ref().map(|_| ()).map_err(|s| *s),
+        ));
+        let counter = query
+            .as_ref()
+            .map_err(|s| *s)
+            .and_then(|q| q.add_counter_string(r"\Memory\Available Bytes"));
+        results.push((
+            r"add \Memory\Available Bytes".to_owned(),
+            counter.as_ref().map(|_| ()).map_err(|s| *s),
+        ));
+        let primed = query.as_ref().map_err(|s| *s).and_then(|q| q.collect());
+        results.push(("prime query".to_owned(), primed));
+        let value = match (&query, &counter) {
+            (Ok(q), Ok(c)) => q.collect_large_data(c).map(|_| ()),
+            (Err(s), _) | (_, Err(s)) => Err(*s),
+        };
+        results.push(("collect a value".to_owned(), value));
+        let processes = query.as_ref().map_err(|s| *s).and_then(|q| {
+            let counter = q.add_counter_string(r"\System\Processes")?;
+            q.collect_large_data(&counter).map(|_| ())
+        });
+        results.push((r"read \System\Processes".to_owned(), processes));
+        results.push((
+            r"expand \Processor(*)\% Processor Time".to_owned(),
+            pdh.expand_counter_path_string(r"\Processor(*)\% Processor Time")
+                .map(|_| ()),
+        ));
+        results
+    }
+}
+
+impl Drop for PDH {
+    fn drop(&mut self) {
+        let _ = self.disconnect();
+    }
+}
+
+/// Information about a counter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CounterInfo {
+    pub counter_type: CounterType,
+    /// The power of 10 the counter's values are scaled by.
+    pub scale: i32,
+    /// The power of 10 the counter's values are scaled by when not overridden.
+    pub default_scale: i32,
+    pub full_path: String,
+}
+
+/// The counters and instances of a performance object.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ObjectItems {
+    /// The `\\HOSTNAME` prefix of paths on a remote machine or empty for the
+    /// local machine.
+    pub path_prefix: String,
+    pub object: String,
+    pub counters: Vec<String>,
+    /// The instances as PDH lists them. Objects without instances have a
+    /// single empty instance and instances that share a name are listed
+    /// once each under that name.
+    pub instances: Vec<String>,
+}
+
+impl ObjectItems {
+    /// The full counter path of every counter for every instance in order
+    /// of instance and then counter. Instances that share a name are
+    /// numbered `name#1`, `name#2` and so on.
+    pub fn paths<'a>(&'a self) -> impl Iterator<Item = String> + 'a {
+        counter_path::number_instances(self.instances.iter().map(|i| i.as_str()))
+            .into_iter()
+            .flat_map(move |instance| {
+                let object = if instance.is_empty() {
+                    format!("{}\\{}", self.path_prefix, self.object)
+                } else {
+                    format!("{}\\{}({})", self.path_prefix, self.object, instance)
+                };
+                self.counters
+                    .iter()
+                    .map(move |counter| format!("{}\\{}", object, counter))
+            })
+    }
+}
+
+/// A single instance's entry from a formatted counter array. Values are
+/// f64 unless the array was formatted with `format_large_array_into`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArrayItem<V = f64> {
+    /// The instance name. PDH does not disambiguate instances that share a
+    /// name so the same name may appear more than once.
+    pub instance: String,
+    /// The status of this instance's data. One of the PDH_CSTATUS_*
+    /// constants.
+    pub status: u32,
+    /// The value. Only meaningful when status is valid.
+    pub value: V,
+}
+
+impl<V> ArrayItem<V> {
+    /// Returns true if this item's value can be used.
+    pub fn is_valid(&self) -> bool {
+        self.status == PDH_CSTATUS_VALID_DATA || self.status == PDH_CSTATUS_NEW_DATA
+    }
+}
+
+/// A handle for a PDH Query. Queries can have multiple associated PdhCounters.
+///
+/// Each query keeps its own collection state. The same counter path can be
+/// added to several queries and each will compute rate counters from its own
+/// collections, so queries collected at different intervals don't interfere.
+pub struct PdhQuery {
+    handle: HQuery,
+    /// The counters that have been added to this query.
+    counters: RefCell<Vec<Weak<CounterHandle>>>,
+    /// The interval PDH collects this query at in the background if any.
+    interval: Cell<Option<Duration>>,
+    /// Signaled by PDH each time a background collection completes.
+    data_event: Cell<HANDLE>,
+    /// Whether collect stores each counter's raw value for last_raw.
+    stash_raw: Cell<bool>,
+    /// The number of times collect has succeeded on this query.
+    collections: Cell<u64>,
+    /// The log file this query reads from. Held so the log outlives us.
+    data_source: Option<Rc<LogDataSource>>,
+}
+
+impl PdhQuery {
+    fn new() -> Self {
+        Self {
+            handle: null_mut(),
+            counters: RefCell::new(Vec::new()),
+            interval: Cell::new(None),
+            data_event: Cell::new(null_mut()),
+            stash_raw: Cell::new(false),
+            collections: Cell::new(0),
+            data_source: None,
+        }
+    }
+
+    /// Convenience query accessor
+    pub fn query(&mut self) -> &mut HQuery {
+        &mut self.handle
+    }
+
+    /// Adds a performance counter for the given path in utf16 format. The
+    /// path is validated first. Validating a path on a remote machine can
+    /// fail transiently when the RPC to it does so those failures are
+    /// retried a few times with a short backoff.
+    pub fn add_counter_utf16(&self, wide_path: Vec<u16>) -> Result<PdhCounter, PdhError> {
+        self.validate_path(&wide_path)
+            .map_err(|e| self.check_provider_disabled(&wide_path, e))?;
+        self.add_counter_unchecked_utf16(wide_path)
+    }
+
+    /// Reports a missing object as `PdhError::ProviderDisabled` if the path
+    /// is for a live counter on this machine and the object's provider is
+    /// disabled here.
+    fn check_provider_disabled(&self, wide_path: &[u16], err: PdhError) -> PdhError {
+        if err != PdhError::NoObject || self.data_source.is_some() {
+            return err;
+        }
+        let end = wide_path
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(wide_path.len());
+        let path = String::from_utf16_lossy(&wide_path[0..end]);
+        match counter_path::parse_counter_path_pdh_as_written(&path) {
+            Ok(elements) if elements.machine_name.is_none() => {
+                capabilities::check_provider_disabled(&elements.object_name, err)
+            }
+            _ => err,
+        }
+    }
+
+    /// Validates a counter path against the query's log if it has one or the
+    /// live data otherwise retrying transient remote failures.
+    fn validate_path(&self, wide_path: &Vec<u16>) -> Result<(), PdhError> {
+        let data_source = self
+            .data_source
+            .as_ref()
+            .map_or(null_mut(), |source| source.handle());
+        let mut backoff = VALIDATE_RETRY_BACKOFF;
+        let mut attempts = 1;
+        loop {
+            let status = unsafe { PdhValidatePathExW(data_source, wide_path.as_ptr()) } as u32;
+            if status == ERROR_SUCCESS {
+                return Ok(());
+            }
+            if !is_transient_remote_error(status) || attempts == MAX_VALIDATE_ATTEMPTS {
+                return Err(status.into());
+            }
+            std::thread::sleep(backoff);
+            backoff *= 2;
+            attempts += 1;
+        }
+    }
+
+    /// Adds a performance counter for the given path without validating it
+    /// first. Use this to skip the cost of validation when adding many
+    /// paths that are already known to be valid, e.g. ones that came from
+    /// enumeration. Invalid paths fail when added or when collected instead.
+    pub fn add_counter_unchecked(&self, path: &str) -> Result<PdhCounter, PdhError> {
+        self.add_counter_unchecked_utf16(str_to_utf16(path))
+    }
+
+    /// Adds a performance counter for the given path in utf16 format without
+    /// validating it first.
+    pub fn add_counter_unchecked_utf16(&self, wide_path: Vec<u16>) -> Result<PdhCounter, PdhError> {
+        let mut counter_handle: HCounter = null_mut();
+        let status = timed!(AddCounter, unsafe {
+            PdhAddCounterW(self.handle, wide_path.as_ptr(), 0, &mut counter_handle)
+        }) as u32;
+        if status != ERROR_SUCCESS {
+            return Err(self.check_provider_disabled(&wide_path, status.into()));
+        }
+        // Leave off the null terminator.
+        let path = String::from_utf16_lossy(&wide_path[..wide_path.len() - 1]);
+        return Ok(self.track_counter(counter_handle, CounterDefinition::new(path)));
+    }
+
+    /// Wraps a newly added counter handle and tracks it for clear_counters
+    /// and export_definition.
+    fn track_counter(&self, counter_handle: HCounter, definition: CounterDefinition) -> PdhCounter {
+        let counter = PdhCounter(Rc::new(CounterHandle {
+            handle: Cell::new(counter_handle),
+            definition: RefCell::new(definition),
+            last_raw: Cell::new(None),
+            format_flags: Cell::new(0),
+            formatted_collection: Cell::new(0),
+            stale_warned: Cell::new(false),
+            counter_type: Cell::new(None),
+        }));
+        // A handle's type never changes so we look it up once here rather
+        // than every time the counter is read.
+        if let Ok(info) = self.counter_info(&counter) {
+            counter.0.counter_type.set(Some(info.counter_type));
+        }
+        let mut counters = self.counters.borrow_mut();
+        // Forget about any counters that have since been dropped.
+        counters.retain(|c| c.strong_count() > 0);
+        counters.push(Rc::downgrade(&counter.0));
+        counter
+    }
+
+    /// Adds a performance counter for a path using the english object and
+    /// counter names regardless of the machine's language. Uses
+    /// `PdhAddEnglishCounterW` where available and otherwise translates the
+    /// names to their localized versions by index before adding the counter.
+    pub fn add_english_counter(&self, path: &str) -> Result<PdhCounter, PdhError> {
+        let add_english = match capabilities::add_english_counter_fn() {
+            Some(add_english) => add_english,
+            None => {
+                let counter =
+                    self.add_counter_string(capabilities::localize_counter_path(path)?)?;
+                // Remember the english path so the definition can be rebuilt
+                // on a machine with a different language.
+                *counter.0.definition.borrow_mut() = CounterDefinition {
+                    path: path.to_owned(),
+                    english: true,
+                    scale: None,
+                };
+                return Ok(counter);
+            }
+        };
+        let wide_path = str_to_utf16(path);
+        let mut counter_handle: HCounter = null_mut();
+        let status =
+            unsafe { add_english(self.handle, wide_path.as_ptr(), 0, &mut counter_handle) } as u32;
+        if status != ERROR_SUCCESS {
+            return Err(status.into());
+        }
+        Ok(self.track_counter(
+            counter_handle,
+            CounterDefinition {
+                path: path.to_owned(),
+                english: true,
+                scale: None,
+            },
+        ))
+    }
+
+    /// Overrides the power of 10 a counter's values are scaled by when they
+    /// are formatted. The PdhCounter must be associated with this query.
+    pub fn set_counter_scale(&self, counter: &PdhCounter, scale: i32) -> Result<(), PdhError> {
+        let status = unsafe { PdhSetCounterScaleFactor(counter.handle(), scale) } as u32;
+        if status != ERROR_SUCCESS {
+            return Err(status.into());
+        }
+        counter.0.definition.borrow_mut().scale = Some(scale);
+        Ok(())
+    }
+
+    /// Sets extra PDH_FMT_* flags, e.g. `PDH_FMT_NOSCALE`, that are used
+    /// every time the counter's values are formatted by this query. The
+    /// PdhCounter must be associated with this query.
+    pub fn set_counter_format_flags(&self, counter: &PdhCounter, flags: u32) {
+        counter.0.format_flags.set(flags);
+    }
+
+    /// The extra PDH_FMT_* flags set for a counter with
+    /// `set_counter_format_flags`.
+    pub fn counter_format_flags(&self, counter: &PdhCounter) -> u32 {
+        counter.0.format_flags.get()
+    }
+
+    /// Exports the counters currently in this query so an equivalent query
+    /// can be rebuilt later with `PDH::query_from_definition`.
+    pub fn export_definition(&self) -> QueryDefinition {
+        QueryDefinition {
+            counters: self
+                .counters
+                .borrow()
+                .iter()
+                .filter_map(|c| c.upgrade())
+                .filter(|c| !c.handle.get().is_null())
+                .map(|c| c.definition.borrow().clone())
+                .collect(),
+        }
+    }
+
+    /// Adds a performance counter for the given path.
+    pub fn add_counter_string<S: Into<String>>(&self, path: S) -> Result<PdhCounter, PdhError> {
+        self.add_counter_utf16(str_to_utf16(&path.into()))
+    }
+
+    /// Adds a performance counter for the given path if it exists on this
+    /// machine. Returns Ok(None) if the object, counter, or instance is
+    /// absent or the object's provider is disabled and Err only for real
+    /// failures.
+    pub fn try_add_counter(&self, path: &str) -> Result<Option<PdhCounter>, PdhError> {
+        match self.add_counter_string(path) {
+            Ok(counter) => Ok(Some(counter)),
+            Err(PdhError::NoObject)
+            | Err(PdhError::NoCounter)
+            | Err(PdhError::NoInstance)
+            | Err(PdhError::ProviderDisabled) => Ok(None),
+            Err(s) => Err(s),
+        }
+    }
+
+    /// Fetches the PDH_COUNTER_INFO_W for a counter optionally including its
+    /// explain text. The returned buffer holds the struct followed by the
+    /// strings it points to.
+    fn counter_info_buffer(
         &self,
         counter: &PdhCounter,
-        format: u32,
-    ) -> Result<PDH_FMT_COUNTERVALUE, PDHStatus> {
-        let mut status = unsafe { PdhCollectQueryData(self.0) } as u32;
+        explain: bool,
+    ) -> Result<Vec<u64>, PdhError> {
+        let explain = if explain { TRUE } else { FALSE } as u8;
+        let mut buffer_size: DWORD = 0;
+        // The first time we call this to find out what the required buffer
+        // size is.
+        let mut status =
+            unsafe { PdhGetCounterInfoW(counter.handle(), explain, &mut buffer_size, null_mut()) }
+                as u32;
+        if status != PDH_MORE_DATA {
+            return Err(status.into());
+        }
+        // The info struct is followed by the strings it points to. We use a
+        // u64 buffer to get the alignment the struct requires.
+        let mut buffer = vec![0u64; (buffer_size as usize + 7) / 8];
+        status = unsafe {
+            PdhGetCounterInfoW(
+                counter.handle(),
+                explain,
+                &mut buffer_size,
+                buffer.as_mut_ptr() as *mut PDH_COUNTER_INFO_W,
+            )
+        } as u32;
         if status != ERROR_SUCCESS {
-            return Err(status);
+            return Err(status.into());
         }
+        Ok(buffer)
+    }
+
+    /// Looks up the type, scale and full path of a counter.
+    /// The PdhCounter must be associated with this query.
+    pub fn counter_info(&self, counter: &PdhCounter) -> Result<CounterInfo, PdhError> {
+        let buffer = self.counter_info_buffer(counter, false)?;
+        let info = unsafe { &*(buffer.as_ptr() as *const PDH_COUNTER_INFO_W) };
+        Ok(CounterInfo {
+            counter_type: CounterType(info.dwType),
+            scale: info.lScale,
+            default_scale: info.lDefaultScale,
+            full_path: unsafe { wide_ptr_to_string(info.szFullPath) },
+        })
+    }
+
+    /// The counter's type from when it was added or looked up now if PDH
+    /// couldn't report it then.
+    fn type_of(&self, counter: &PdhCounter) -> Result<CounterType, PdhError> {
+        match counter.counter_type() {
+            Some(counter_type) => Ok(counter_type),
+            None => Ok(self.counter_info(counter)?.counter_type),
+        }
+    }
+
+    /// Whether the counter's values are percentages such as `% Processor
+    /// Time` so a UI can display them with a `%`.
+    /// The PdhCounter must be associated with this query.
+    pub fn is_percentage(&self, counter: &PdhCounter) -> Result<bool, PdhError> {
+        Ok(self.type_of(counter)?.is_percent())
+    }
+
+    /// Whether the counter's values are computed from the change between
+    /// two collections.
+    /// The PdhCounter must be associated with this query.
+    pub fn is_rate(&self, counter: &PdhCounter) -> Result<bool, PdhError> {
+        Ok(self.type_of(counter)?.is_rate())
+    }
+
+    /// Whether the counter's values are a length of time in seconds.
+    /// The PdhCounter must be associated with this query.
+    pub fn is_duration(&self, counter: &PdhCounter) -> Result<bool, PdhError> {
+        Ok(self.type_of(counter)?.is_duration())
+    }
+
+    /// Looks up the explain text describing a counter. Counters without any
+    /// have an empty explain text.
+    /// The PdhCounter must be associated with this query.
+    pub fn counter_explain(&self, counter: &PdhCounter) -> Result<String, PdhError> {
+        let buffer = self.counter_info_buffer(counter, true)?;
+        let info = unsafe { &*(buffer.as_ptr() as *const PDH_COUNTER_INFO_W) };
+        if info.szExplainText.is_null() {
+            return Ok(String::new());
+        }
+        Ok(unsafe { wide_ptr_to_string(info.szExplainText) })
+    }
+
+    /// Removes a counter from the query consuming it in the process.
+    #[allow(unused_variables)]
+    pub fn remove_counter(&self, counter_handle: PdhCounter) {
+        // when the counter is dropped it will be removed from the query.
+        // We consume the counter in the process so it can't be reused.
+        // As such this function has no body. It exists only to consume the counter.
+    }
+
+    /// Removes the counter that was added with the given path from the query.
+    /// Any PdhCounter still held for it will return errors if used
+    /// afterwards. Returns false if no counter in the query has that path.
+    pub fn remove_counter_by_path(&self, path: &str) -> Result<bool, PdhError> {
+        let mut counters = self.counters.borrow_mut();
+        counters.retain(|c| c.strong_count() > 0);
+        let idx = counters.iter().position(|c| match c.upgrade() {
+            Some(c) => !c.handle.get().is_null() && c.definition.borrow().path == path,
+            None => false,
+        });
+        match idx {
+            Some(idx) => {
+                let counter = counters.remove(idx);
+                if let Some(counter) = counter.upgrade() {
+                    counter.remove()?;
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Removes every counter from the query while leaving the query open for
+    /// new counters. Any PdhCounter still held for this query will return
+    /// errors if used afterwards. Every counter is removed even if one fails
+    /// and the first error encountered is returned.
+    pub fn clear_counters(&mut self) -> Result<(), PdhError> {
+        let mut result = Ok(());
+        for counter in self.counters.borrow_mut().drain(0..) {
+            if let Some(counter) = counter.upgrade() {
+                if let Err(s) = counter.remove() {
+                    if result.is_ok() {
+                        result = Err(s);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Collects the current raw values for every counter associated with this query
+    /// in a single call. Use the format_*_data methods afterwards to read each
+    /// counter's value from this collection.
+    #[inline]
+    pub fn collect(&self) -> Result<(), PdhError> {
+        let status = timed!(CollectQueryData, unsafe {
+            PdhCollectQueryData(self.handle)
+        }) as u32;
+        if status != ERROR_SUCCESS {
+            return Err(status.into());
+        }
+        self.collections.set(self.collections.get() + 1);
+        if self.stash_raw.get() {
+            self.stash_raw_values();
+        }
+        Ok(())
+    }
+
+    /// Collects, waits interval and collects again so rate counters have the
+    /// two samples they need for a value.
+    pub(crate) fn collect_sampled(&self, interval: Duration) -> Result<(), PdhError> {
+        self.collect()?;
+        std::thread::sleep(interval);
+        self.collect()
+    }
+
+    /// Primes the query so the counters' values are valid. Collects
+    /// skip_count samples to throw away and then keeps sampling until none
+    /// of the counters report warmup errors or `MAX_PRIME_SAMPLES` more
+    /// samples have been taken. Waits interval between samples. Some timer
+    /// counters report invalid data for the first two samples after a cold
+    /// start so one discarded sample isn't always enough.
+    pub fn prime(
+        &self,
+        counters: &[&PdhCounter],
+        skip_count: usize,
+        interval: Duration,
+    ) -> Result<(), PdhError> {
+        for i in 0..skip_count {
+            if i > 0 {
+                std::thread::sleep(interval);
+            }
+            self.collect()?;
+        }
+        sample_until_warm(MAX_PRIME_SAMPLES, interval, || {
+            self.collect()?;
+            Ok(counters
+                .iter()
+                .any(|c| match self.format_data(c, PDH_FMT_DOUBLE) {
+                    Err(s) => is_warmup_error(s),
+                    Ok(_) => false,
+                }))
+        })?;
+        Ok(())
+    }
+
+    /// Sets whether collect stores the raw value of every counter so it can
+    /// be read later with last_raw. This costs a call per counter on each
+    /// collection so it is off by default.
+    pub fn set_stash_raw(&self, stash_raw: bool) {
+        self.stash_raw.set(stash_raw);
+    }
+
+    fn stash_raw_values(&self) {
+        for counter in self.counters.borrow().iter().filter_map(|c| c.upgrade()) {
+            let handle = counter.handle.get();
+            if handle.is_null() {
+                continue;
+            }
+            let mut raw: PDH_RAW_COUNTER = unsafe { std::mem::zeroed() };
+            let status = unsafe { PdhGetRawCounterValue(handle, null_mut(), &mut raw) } as u32;
+            counter.last_raw.set(if status == ERROR_SUCCESS {
+                Some(RawCounterValue::from_pdh(&raw))
+            } else {
+                None
+            });
+        }
+    }
+
+    /// The raw value of a counter from the last collect if stashing raw
+    /// values is enabled with set_stash_raw. Consecutive raw values can be
+    /// passed to calculate_from_raw to compute rates across collections.
+    /// The PdhCounter must be associated with this query.
+    pub fn last_raw(&self, counter: &PdhCounter) -> Option<RawCounterValue> {
+        counter.0.last_raw.get()
+    }
+
+    /// Has PDH collect this query in the background every interval using
+    /// `PdhCollectQueryDataEx`. The interval is rounded up to whole seconds.
+    /// Use wait_for_data to wait for each collection and the format_*_data
+    /// methods to read the values. Background collection continues until the
+    /// query is dropped and can only be started once.
+    pub fn collect_in_background(&self, interval: Duration) -> Result<(), PdhError> {
+        if self.interval.get().is_some() {
+            return Err(PdhError::InvalidArgument);
+        }
+        let secs = std::cmp::max(1, interval.as_secs() + (interval.subsec_nanos() > 0) as u64);
+        let event = unsafe { CreateEventW(null_mut(), FALSE, FALSE, null_mut()) };
+        if event.is_null() {
+            return Err(unsafe { GetLastError() }.into());
+        }
+        let status = unsafe { PdhCollectQueryDataEx(self.handle, secs as DWORD, event) } as u32;
+        if status != ERROR_SUCCESS {
+            unsafe { CloseHandle(event) };
+            return Err(status.into());
+        }
+        self.data_event.set(event);
+        self.interval.set(Some(Duration::from_secs(secs)));
+        Ok(())
+    }
+
+    /// Returns the interval PDH is collecting this query at in the background
+    /// or None if the query is collected manually.
+    pub fn collection_interval(&self) -> Option<Duration> {
+        self.interval.get()
+    }
+
+    /// Waits up to timeout for the next background collection to complete.
+    /// Returns false if the timeout elapsed first.
+    pub fn wait_for_data(&self, timeout: Duration) -> Result<bool, PdhError> {
+        let event = self.data_event.get();
+        if event.is_null() {
+            return Err(PdhError::InvalidHandle);
+        }
+        match unsafe { WaitForSingleObject(event, timeout.as_millis() as DWORD) } {
+            WAIT_OBJECT_0 => Ok(true),
+            WAIT_TIMEOUT => Ok(false),
+            _ => Err(unsafe { GetLastError() }.into()),
+        }
+    }
+
+    fn collect_data(
+        &self,
+        counter: &PdhCounter,
+        format: u32,
+    ) -> Result<PDH_FMT_COUNTERVALUE, PdhError> {
+        self.collect()?;
+        self.format_data(counter, format)
+    }
+
+    #[inline]
+    fn format_data(
+        &self,
+        counter: &PdhCounter,
+        format: u32,
+    ) -> Result<PDH_FMT_COUNTERVALUE, PdhError> {
         let mut fmt_counter_value = unsafe {
             PDH_FMT_COUNTERVALUE {
                 CStatus: 0,
                 u: std::mem::zeroed::<PDH_FMT_COUNTERVALUE_u>(),
             }
         };
+        self.format_data_into(counter, format, &mut fmt_counter_value)?;
+        return Ok(fmt_counter_value);
+    }
+
+    /// Warns in debug builds when a rate counter is read again without its
+    /// query being collected since the last read. PDH computes a rate from
+    /// the two most recent collections of the counter's query so every
+    /// counter whose rates are compared should live in one query that is
+    /// collected once per cycle. A rate read twice without a collection
+    /// in between usually means it was put in a query that was left out of
+    /// the cycle. Queries collected in the background aren't checked.
+    fn check_rate_collected(&self, counter: &PdhCounter) {
+        if !cfg!(debug_assertions) || self.interval.get().is_some() {
+            return;
+        }
+        let collections = self.collections.get();
+        let last = counter.0.formatted_collection.replace(collections);
+        if last != collections || counter.0.stale_warned.get() {
+            return;
+        }
+        if counter.counter_type().map_or(false, |t| t.is_rate()) {
+            counter.0.stale_warned.set(true);
+            log::warn!(
+                "Rate counter {} was read again without collecting its query. The value is stale.",
+                counter.path()
+            );
+        }
+    }
+
+    /// Formats a counter's most recently collected value into a caller
+    /// provided value struct so it can be reused across calls.
+    #[inline]
+    fn format_data_into(
+        &self,
+        counter: &PdhCounter,
+        format: u32,
+        fmt_counter_value: &mut PDH_FMT_COUNTERVALUE,
+    ) -> Result<(), PdhError> {
         let mut counter_type: u32 = 0;
-        status = unsafe {
+        let status = unsafe {
             PdhGetFormattedCounterValue(
-                counter.0,
-                format,
+                counter.handle(),
+                format | counter.0.format_flags.get(),
                 &mut counter_type,
-                &mut fmt_counter_value,
+                fmt_counter_value,
             )
         } as u32;
         if status != ERROR_SUCCESS {
-            return Err(status);
+            return Err(status.into());
         }
-        return Ok(fmt_counter_value);
+        self.check_rate_collected(counter);
+        // The call can succeed while the value itself is not valid so we
+        // check the counter's own status as well.
+        if fmt_counter_value.CStatus != PDH_CSTATUS_VALID_DATA
+            && fmt_counter_value.CStatus != PDH_CSTATUS_NEW_DATA
+        {
+            return Err(fmt_counter_value.CStatus.into());
+        }
+        Ok(())
+    }
+
+    /// Collects the query once and formats each of the counters as an f64 into
+    /// values. Values is cleared first and then lines up index for index with
+    /// counters. Reusing the same values Vec across calls avoids reallocating
+    /// it each collection which makes this the cheapest way to read a set of
+    /// counters in a hot loop.
+    pub fn collect_all_double_into(
+        &self,
+        counters: &[&PdhCounter],
+        values: &mut Vec<Result<f64, PdhError>>,
+    ) -> Result<(), PdhError> {
+        self.collect()?;
+        values.clear();
+        let mut fmt_counter_value = unsafe {
+            PDH_FMT_COUNTERVALUE {
+                CStatus: 0,
+                u: std::mem::zeroed::<PDH_FMT_COUNTERVALUE_u>(),
+            }
+        };
+        for counter in counters {
+            values.push(
+                self.format_data_into(counter, PDH_FMT_DOUBLE, &mut fmt_counter_value)
+                    .map(|_| unsafe { *fmt_counter_value.u.doubleValue() }),
+            );
+        }
+        Ok(())
+    }
+
+    /// Collects the query once and formats each of the counters as an f64 so
+    /// every value comes from the same snapshot. The results line up index
+    /// for index with counters and a counter that fails to format doesn't
+    /// affect the others. Use collect_all_double_into to reuse the Vec.
+    pub fn collect_all_double(
+        &self,
+        counters: &[&PdhCounter],
+    ) -> Result<Vec<Result<f64, PdhError>>, PdhError> {
+        let mut values = Vec::with_capacity(counters.len());
+        self.collect_all_double_into(counters, &mut values)?;
+        Ok(values)
+    }
+
+    /// Formats the most recently collected values of a wildcard counter as an
+    /// array of ArrayItems. There is one entry per instance the counter
+    /// currently matches including instances whose own data was not valid.
+    /// The query must have been collected with `collect` first.
+    pub fn format_double_array(&self, counter: &PdhCounter) -> Result<Vec<ArrayItem>, PdhError> {
+        let mut buffer = Vec::new();
+        let mut values = Vec::new();
+        self.format_double_array_into(counter, &mut buffer, &mut values)?;
+        Ok(values)
+    }
+
+    /// Formats the most recently collected values of a wildcard counter into
+    /// values. The buffer PDH writes the array into is only grown when it is
+    /// too small so reusing the same buffer and values across calls avoids
+    /// reallocating them each collection.
+    pub fn format_double_array_into(
+        &self,
+        counter: &PdhCounter,
+        buffer: &mut Vec<u64>,
+        values: &mut Vec<ArrayItem>,
+    ) -> Result<(), PdhError> {
+        self.format_array_into(counter, PDH_FMT_DOUBLE, buffer, values, |v| unsafe {
+            *v.u.doubleValue()
+        })
+    }
+
+    /// Formats the most recently collected values of a wildcard counter into
+    /// values as i64s. Use this for integer counters like byte counts whose
+    /// values may be too big for an f64 to hold exactly.
+    pub fn format_large_array_into(
+        &self,
+        counter: &PdhCounter,
+        buffer: &mut Vec<u64>,
+        values: &mut Vec<ArrayItem<i64>>,
+    ) -> Result<(), PdhError> {
+        self.format_array_into(counter, PDH_FMT_LARGE, buffer, values, |v| unsafe {
+            *v.u.largeValue()
+        })
+    }
+
+    fn format_array_into<V, F>(
+        &self,
+        counter: &PdhCounter,
+        format: u32,
+        buffer: &mut Vec<u64>,
+        values: &mut Vec<ArrayItem<V>>,
+        value_of: F,
+    ) -> Result<(), PdhError>
+    where
+        F: Fn(&PDH_FMT_COUNTERVALUE) -> V,
+    {
+        values.clear();
+        let format = format | counter.0.format_flags.get();
+        // The buffer holds the items followed by the instance names they point
+        // to. We use a u64 buffer to get the alignment the items require.
+        let mut item_count: DWORD = 0;
+        let mut attempts = 0;
+        let status = loop {
+            let mut buffer_size = (buffer.len() * 8) as DWORD;
+            let buffer_ptr = if buffer.is_empty() {
+                null_mut()
+            } else {
+                buffer.as_mut_ptr() as *mut PDH_FMT_COUNTERVALUE_ITEM_W
+            };
+            let status = unsafe {
+                PdhGetFormattedCounterArrayW(
+                    counter.handle(),
+                    format,
+                    &mut buffer_size,
+                    &mut item_count,
+                    buffer_ptr,
+                )
+            } as u32;
+            // Instances can appear between sizing the buffer and filling it
+            // so we resize and try again a bounded number of times. A
+            // PDH_MORE_DATA after that is returned as an error rather than
+            // a partial array.
+            if status != PDH_MORE_DATA || attempts == MAX_BUFFER_ATTEMPTS {
+                break status;
+            }
+            attempts += 1;
+            // buffer_size has been set to the required size in bytes.
+            buffer.resize((buffer_size as usize + 7) / 8, 0);
+        };
+        if status != ERROR_SUCCESS {
+            return Err(status.into());
+        }
+        self.check_rate_collected(counter);
+        let items = unsafe {
+            std::slice::from_raw_parts(
+                buffer.as_ptr() as *const PDH_FMT_COUNTERVALUE_ITEM_W,
+                item_count as usize,
+            )
+        };
+        for item in items {
+            values.push(ArrayItem {
+                instance: unsafe { wide_ptr_to_string(item.szName) },
+                status: item.FmtValue.CStatus,
+                value: value_of(&item.FmtValue),
+            });
+        }
+        Ok(())
+    }
+
+    /// Collects the query and returns the values of a wildcard counter as an
+    /// array of ArrayItems.
+    pub fn collect_formatted_array(
+        &self,
+        counter: &PdhCounter,
+    ) -> Result<Vec<ArrayItem>, PdhError> {
+        self.collect()?;
+        self.format_double_array(counter)
     }
 
     /// Returns a ValueStream for a given path that will iterate over
@@ -359,7 +1965,7 @@ impl PdhQuery {
     pub fn get_value_stream_from_path<S: Into<String>, ValueType>(
         &self,
         counter_path: S,
-    ) -> Result<CounterStream<ValueType>, PDHStatus> {
+    ) -> Result<CounterStream<ValueType>, PdhError> {
         let counter_handle = self.add_counter_string(counter_path)?;
         Ok(self.get_value_stream_from_handle(counter_handle))
     }
@@ -377,45 +1983,132 @@ impl PdhQuery {
 
     /// Collect data from a counter in i32 format.
     /// The PdhCounter must be associated with this query.
-    pub fn collect_long_data(&self, counter: &PdhCounter) -> Result<i32, PDHStatus> {
+    #[inline]
+    pub fn collect_long_data(&self, counter: &PdhCounter) -> Result<i32, PdhError> {
         let fmt_counter_value = self.collect_data(counter, PDH_FMT_LONG)?;
         return Ok(unsafe { *fmt_counter_value.u.longValue() });
     }
 
     /// Collect data from a counter in i64 format.
     /// The PdhCounter must be associated with this query.
-    pub fn collect_large_data(&self, counter: &PdhCounter) -> Result<i64, PDHStatus> {
+    #[inline]
+    pub fn collect_large_data(&self, counter: &PdhCounter) -> Result<i64, PdhError> {
         let fmt_counter_value = self.collect_data(counter, PDH_FMT_LARGE)?;
         return Ok(unsafe { *fmt_counter_value.u.largeValue() });
     }
 
     /// Collect data from a counter in f64 format.
     /// The PdhCounter must be associated with this query.
-    pub fn collect_double_data(&self, counter: &PdhCounter) -> Result<f64, PDHStatus> {
+    #[inline]
+    pub fn collect_double_data(&self, counter: &PdhCounter) -> Result<f64, PdhError> {
         let fmt_counter_value = self.collect_data(counter, PDH_FMT_DOUBLE)?;
         return Ok(unsafe { *fmt_counter_value.u.doubleValue() });
     }
+
+    /// Collect a counter and return the first and second raw values and the
+    /// multi count of the sample behind it. For rate counters these are the
+    /// unprocessed numerator and denominator.
+    ///
+    /// base_format must be one of PDH_FMT_LONG, PDH_FMT_LARGE or
+    /// PDH_FMT_DOUBLE. The sample is formatted with `PDH_FMT_RAW |
+    /// base_format` first so this fails with the same status the matching
+    /// collect_*_data method would, e.g. when a rate counter only has one
+    /// sample. The formatted value struct has no room for the raw fields so
+    /// they are then read from the same sample.
+    ///
+    /// Use this when the raw values should only be used if the counter
+    /// formats cleanly. Use last_raw, which reads `PdhGetRawCounterValue`
+    /// without formatting at all, to keep every sample including invalid ones
+    /// e.g. to compute rates yourself with calculate_from_raw.
+    /// The PdhCounter must be associated with this query.
+    pub fn collect_raw_formatted(
+        &self,
+        counter: &PdhCounter,
+        base_format: u32,
+    ) -> Result<(i64, i64, u32), PdhError> {
+        if base_format != PDH_FMT_LONG
+            && base_format != PDH_FMT_LARGE
+            && base_format != PDH_FMT_DOUBLE
+        {
+            return Err(PdhError::InvalidArgument);
+        }
+        self.collect_data(counter, PDH_FMT_RAW | base_format)?;
+        let mut raw: PDH_RAW_COUNTER = unsafe { std::mem::zeroed() };
+        let status =
+            unsafe { PdhGetRawCounterValue(counter.handle(), null_mut(), &mut raw) } as u32;
+        if status != ERROR_SUCCESS {
+            return Err(status.into());
+        }
+        return Ok((raw.FirstValue, raw.SecondValue, raw.MultiCount));
+    }
+
+    /// Format the most recently collected value of a counter in i32 format.
+    /// The PdhCounter must be associated with this query and the query must
+    /// have been collected with `collect` first.
+    #[inline]
+    pub fn format_long_data(&self, counter: &PdhCounter) -> Result<i32, PdhError> {
+        let fmt_counter_value = self.format_data(counter, PDH_FMT_LONG)?;
+        return Ok(unsafe { *fmt_counter_value.u.longValue() });
+    }
+
+    /// Format the most recently collected value of a counter in i64 format.
+    /// The PdhCounter must be associated with this query and the query must
+    /// have been collected with `collect` first.
+    #[inline]
+    pub fn format_large_data(&self, counter: &PdhCounter) -> Result<i64, PdhError> {
+        let fmt_counter_value = self.format_data(counter, PDH_FMT_LARGE)?;
+        return Ok(unsafe { *fmt_counter_value.u.largeValue() });
+    }
+
+    /// Format the most recently collected value of a counter in f64 format.
+    /// The PdhCounter must be associated with this query and the query must
+    /// have been collected with `collect` first.
+    #[inline]
+    pub fn format_double_data(&self, counter: &PdhCounter) -> Result<f64, PdhError> {
+        let fmt_counter_value = self.format_data(counter, PDH_FMT_DOUBLE)?;
+        return Ok(unsafe { *fmt_counter_value.u.doubleValue() });
+    }
 }
 
 /// Represents a stream of Values or Errors for a given ValueType.
 /// (i.e. i32, i64, or f64). Calling next will return the next value
-/// for the counter or a Err(PDHStatus).
+/// for the counter or a Err(PdhError).
 ///
 /// Note that an Err return from next does not imply that the stream
 /// has ended. Subsequent calls may succeed.
 pub trait ValueStream<ValueType> {
-    fn next(&self) -> Result<ValueType, PDHStatus>;
+    fn next(&self) -> Result<ValueType, PdhError>;
 }
 
 /// An iterator for a given ValueType over a PdhCounter.
 ///
 /// Note that sometimes the first value returned from a windows performance
 /// counter query is invalid but that subsequent values will then be okay.
+///
+/// A stream can be paused with `pause`. While paused next doesn't collect
+/// the query and returns the last value collected instead, or
+/// `PDH_NO_DATA` if there isn't one. Rate counters are calculated from the
+/// previous collection so the first value after `resume` covers the whole
+/// pause. Use `resume_and_reprime` to collect once when resuming so the
+/// first value only covers the time since.
+///
+/// With `with_circuit_breaker` a stream that keeps failing stops collecting
+/// and returns `CIRCUIT_OPEN` until `reset` is called.
+///
+/// The first call to next primes the query with `PdhQuery::prime` before
+/// collecting so the stream doesn't return the garbage first samples. By
+/// default one sample is skipped. Change that with `with_skip_count`.
 pub struct CounterStream<'a, ValueType> {
     query_handle: &'a PdhQuery,
     counter_handle: PdhCounter,
     collect_delay: Option<Duration>,
-    phantom: std::marker::PhantomData<ValueType>,
+    format_flags: u32,
+    paused: Cell<bool>,
+    last_value: Cell<Option<ValueType>>,
+    max_consecutive_errors: Option<usize>,
+    consecutive_errors: Cell<usize>,
+    skip_count: usize,
+    primed: Cell<bool>,
 }
 
 impl<'a, ValueType> CounterStream<'a, ValueType> {
@@ -424,11 +2117,24 @@ impl<'a, ValueType> CounterStream<'a, ValueType> {
         Self {
             query_handle: query_handle,
             counter_handle: counter_handle,
-            phantom: std::marker::PhantomData,
             collect_delay: None,
+            format_flags: 0,
+            paused: Cell::new(false),
+            last_value: Cell::new(None),
+            max_consecutive_errors: None,
+            consecutive_errors: Cell::new(0),
+            skip_count: 1,
+            primed: Cell::new(false),
         }
     }
 
+    /// Sets how many samples priming throws away before it starts checking
+    /// for valid data. Zero skips priming altogether.
+    pub fn with_skip_count(mut self, skip_count: usize) -> Self {
+        self.skip_count = skip_count;
+        return self;
+    }
+
     /// Add an optional delay to the iterator. This is useful for when
     /// you want to ensure that you don't spam the counter collection.
     /// Collecting too quickly will yield garbage data from your counter.
@@ -436,51 +2142,553 @@ impl<'a, ValueType> CounterStream<'a, ValueType> {
         self.collect_delay = Some(delay.into());
         return self;
     }
+
+    /// Add format flags to use when formatting each value. e.g.
+    /// `PDH_FMT_NOSCALE`, `PDH_FMT_NOCAP100` or `PDH_FMT_1000`. These are
+    /// combined with the format for the stream's ValueType.
+    pub fn with_format_flags(mut self, flags: u32) -> Self {
+        self.format_flags = flags;
+        return self;
+    }
+
+    /// Opens the circuit after max_consecutive_errors errors in a row. Once
+    /// open next returns `CIRCUIT_OPEN` without collecting until `reset` is
+    /// called. The collect delay still applies so a consumer that ignores it
+    /// doesn't spin.
+    ///
+    /// Rate counters report invalid data until they've been collected twice.
+    /// Those warmup errors don't count toward the breaker until the stream
+    /// has returned its first value.
+    pub fn with_circuit_breaker(mut self, max_consecutive_errors: usize) -> Self {
+        self.max_consecutive_errors = Some(max_consecutive_errors);
+        return self;
+    }
+
+    /// Whether the circuit breaker is open.
+    pub fn is_circuit_open(&self) -> bool {
+        match self.max_consecutive_errors {
+            Some(max) => self.consecutive_errors.get() >= max,
+            None => false,
+        }
+    }
+
+    /// Closes the circuit breaker so next collects again.
+    pub fn reset(&self) {
+        self.consecutive_errors.set(0);
+    }
+
+    /// Stops collecting the query until resumed. The query and counter are
+    /// kept so resuming doesn't have to warm them up again.
+    pub fn pause(&self) {
+        self.paused.set(true);
+    }
+
+    /// Starts collecting the query again. The first rate counter value will
+    /// be averaged over the whole pause.
+    pub fn resume(&self) {
+        self.paused.set(false);
+    }
+
+    /// Starts collecting the query again and collects it once right away so
+    /// the first rate counter value only covers the time since resuming.
+    pub fn resume_and_reprime(&self) -> Result<(), PdhError> {
+        self.paused.set(false);
+        self.query_handle.collect()
+    }
+
+    /// Whether the stream is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused.get()
+    }
 }
 
-impl<'a> ValueStream<i32> for CounterStream<'a, i32> {
-    fn next(&self) -> Result<i32, PDHStatus> {
+impl<'a, ValueType: Copy> CounterStream<'a, ValueType> {
+    /// Waits out the collect delay. Returns the result to report while
+    /// paused or with the circuit open or None if we should collect.
+    fn wait(&self) -> Option<Result<ValueType, PdhError>> {
+        if self.skip_count > 0 && !self.primed.get() && !self.paused.get() {
+            self.primed.set(true);
+            let interval = self.collect_delay.unwrap_or(DEFAULT_PRIME_INTERVAL);
+            if let Err(s) =
+                self.query_handle
+                    .prime(&[&self.counter_handle], self.skip_count, interval)
+            {
+                return Some(Err(s));
+            }
+        }
         if let Some(d) = self.collect_delay {
             std::thread::sleep(d);
         }
-        self.query_handle.collect_long_data(&self.counter_handle)
+        if self.is_circuit_open() {
+            return Some(Err(PdhError::CircuitOpen));
+        }
+        if !self.paused.get() {
+            return None;
+        }
+        Some(self.last_value.get().ok_or(PdhError::NoData))
+    }
+
+    /// Remembers a collected value so we can report it while paused and
+    /// counts errors toward the circuit breaker.
+    fn record(&self, result: Result<ValueType, PdhError>) -> Result<ValueType, PdhError> {
+        match result {
+            Ok(value) => {
+                self.last_value.set(Some(value));
+                self.consecutive_errors.set(0);
+            }
+            Err(s) if self.last_value.get().is_none() && is_warmup_error(s) => {}
+            Err(_) => self
+                .consecutive_errors
+                .set(self.consecutive_errors.get() + 1),
+        }
+        return result;
+    }
+}
+
+impl<'a> ValueStream<i32> for CounterStream<'a, i32> {
+    fn next(&self) -> Result<i32, PdhError> {
+        if let Some(paused_value) = self.wait() {
+            return paused_value;
+        }
+        let result = self
+            .query_handle
+            .collect_data(&self.counter_handle, PDH_FMT_LONG | self.format_flags)
+            .map(|fmt_counter_value| unsafe { *fmt_counter_value.u.longValue() });
+        return self.record(result);
     }
 }
 
 impl<'a> ValueStream<i64> for CounterStream<'a, i64> {
-    fn next(&self) -> Result<i64, PDHStatus> {
-        if let Some(d) = self.collect_delay {
-            std::thread::sleep(d);
+    fn next(&self) -> Result<i64, PdhError> {
+        if let Some(paused_value) = self.wait() {
+            return paused_value;
         }
-        self.query_handle.collect_large_data(&self.counter_handle)
+        let result = self
+            .query_handle
+            .collect_data(&self.counter_handle, PDH_FMT_LARGE | self.format_flags)
+            .map(|fmt_counter_value| unsafe { *fmt_counter_value.u.largeValue() });
+        return self.record(result);
     }
 }
 
 impl<'a> ValueStream<f64> for CounterStream<'a, f64> {
-    fn next(&self) -> Result<f64, PDHStatus> {
-        if let Some(d) = self.collect_delay {
-            std::thread::sleep(d);
+    fn next(&self) -> Result<f64, PdhError> {
+        if let Some(paused_value) = self.wait() {
+            return paused_value;
         }
-        self.query_handle.collect_double_data(&self.counter_handle)
+        let result = self
+            .query_handle
+            .collect_data(&self.counter_handle, PDH_FMT_DOUBLE | self.format_flags)
+            .map(|fmt_counter_value| unsafe { *fmt_counter_value.u.doubleValue() });
+        return self.record(result);
     }
 }
 
 impl Drop for PdhQuery {
     fn drop(&mut self) {
+        // Remove any counters that outlive us now so they don't try to
+        // remove themselves from a closed query later.
+        let _ = self.clear_counters();
         unsafe {
-            PdhCloseQuery(self.0);
+            // Closing the query also stops any background collection so it is
+            // safe to close the event afterwards.
+            PdhCloseQuery(self.handle);
+            let event = self.data_event.get();
+            if !event.is_null() {
+                CloseHandle(event);
+            }
+        }
+    }
+}
+
+/// The counter handle shared between a PdhCounter and the query that tracks it.
+/// The handle is nulled out once the counter has been removed from the query.
+struct CounterHandle {
+    handle: Cell<HCounter>,
+    /// How the counter was added so the query can be rebuilt.
+    definition: RefCell<CounterDefinition>,
+    /// The raw value from the last collection if the query stashes them.
+    last_raw: Cell<Option<RawCounterValue>>,
+    /// Extra PDH_FMT_* flags used every time the counter is formatted.
+    format_flags: Cell<u32>,
+    /// The query's collection count the last time the counter was read.
+    formatted_collection: Cell<u64>,
+    /// Whether we have warned about reading this counter without a
+    /// collection.
+    stale_warned: Cell<bool>,
+    /// The counter's type looked up when it was added.
+    counter_type: Cell<Option<CounterType>>,
+}
+
+impl CounterHandle {
+    fn remove(&self) -> Result<(), PdhError> {
+        let handle = self.handle.replace(null_mut());
+        if handle.is_null() {
+            return Ok(());
+        }
+        let status = unsafe { PdhRemoveCounter(handle) } as u32;
+        if status != ERROR_SUCCESS {
+            return Err(status.into());
         }
+        Ok(())
+    }
+}
+
+impl Drop for CounterHandle {
+    fn drop(&mut self) {
+        let _ = self.remove();
     }
 }
 
 /// A wrapper for the PDH counter handle provided by a query object when
 /// you add a counter.
-pub struct PdhCounter(HCounter);
+pub struct PdhCounter(Rc<CounterHandle>);
 
-impl Drop for PdhCounter {
-    fn drop(&mut self) {
-        unsafe {
-            PdhRemoveCounter(self.0);
+impl PdhCounter {
+    #[inline]
+    fn handle(&self) -> HCounter {
+        self.0.handle.get()
+    }
+
+    /// The path this counter was added with.
+    pub fn path(&self) -> String {
+        self.0.definition.borrow().path.clone()
+    }
+
+    /// Whether the counter was added with `add_english_counter` so its path
+    /// uses the english object and counter names.
+    pub fn is_english(&self) -> bool {
+        self.0.definition.borrow().english
+    }
+
+    /// The counter's type as looked up when it was added. None if PDH
+    /// couldn't report it.
+    pub fn counter_type(&self) -> Option<CounterType> {
+        self.0.counter_type.get()
+    }
+}
+
+/// Counters are equal if they are the same counter or share the same live
+/// PDH handle.
+///
+/// PDH may reuse a handle value once a counter has been removed so a
+/// counter compared against one added after it was removed can look equal
+/// even though they are different counters. Prefer
+/// `PdhQuery::remove_counter_by_path` when finding a counter to remove.
+impl PartialEq for PdhCounter {
+    fn eq(&self, other: &Self) -> bool {
+        if Rc::ptr_eq(&self.0, &other.0) {
+            return true;
+        }
+        let handle = self.handle();
+        !handle.is_null() && handle == other.handle()
+    }
+}
+
+impl Eq for PdhCounter {}
+
+/// Where a partial counter path ends in the path grammar.
+#[derive(Debug, PartialEq)]
+enum CompletionPosition<'a> {
+    /// Part of an object name. `\Mem`
+    Object { partial: &'a str },
+    /// Part of an instance name. `\Process(chr`
+    Instance { object: &'a str, partial: &'a str },
+    /// Part of a counter name of an object or one of its instances.
+    /// `\Memory\Av`, `\Process(chrome)\` or just `\Process(chrome)`.
+    Counter {
+        object: &'a str,
+        object_and_instance: &'a str,
+        partial: &'a str,
+    },
+}
+
+/// Splits a partial counter path into its `\\HOSTNAME` prefix, which is
+/// empty for a local path, and where the rest of it ends. None if the
+/// prefix is only a machine name.
+fn completion_position(prefix: &str) -> Option<(&str, CompletionPosition)> {
+    let (machine, rest) = if prefix.starts_with("\\\\") {
+        match prefix[2..].find('\\') {
+            Some(idx) => prefix.split_at(idx + 2),
+            None => return None,
+        }
+    } else {
+        ("", prefix)
+    };
+    let rest = rest.trim_start_matches('\\');
+    let position = match (rest.find('('), rest.find('\\')) {
+        (Some(paren), None) if !rest.ends_with(')') => CompletionPosition::Instance {
+            object: &rest[0..paren],
+            partial: &rest[paren + 1..],
+        },
+        // A complete instance is completed with all of its counters.
+        (Some(paren), None) => CompletionPosition::Counter {
+            object: &rest[0..paren],
+            object_and_instance: rest,
+            partial: "",
+        },
+        (_, Some(slash)) => {
+            let object_and_instance = &rest[0..slash];
+            CompletionPosition::Counter {
+                object: match object_and_instance.find('(') {
+                    Some(paren) => &object_and_instance[0..paren],
+                    None => object_and_instance,
+                },
+                object_and_instance: object_and_instance,
+                partial: &rest[slash + 1..],
+            }
+        }
+        (None, None) => CompletionPosition::Object { partial: rest },
+    };
+    return Some((machine, position));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::multi_sz::vec_to_null_separated;
+
+    fn wide_list(items: &[&str]) -> Vec<u16> {
+        vec_to_null_separated(
+            &items
+                .iter()
+                .map(|s| s.encode_utf16().collect())
+                .collect::<Vec<Vec<u16>>>(),
+        )
+    }
+
+    fn object_items(counters: &[&str], instances: &[&str]) -> ObjectItems {
+        let to_strings = |v: Vec<Vec<u16>>| {
+            v.iter()
+                .map(|w| String::from_utf16_lossy(w))
+                .collect::<Vec<String>>()
+        };
+        let (counters, instances) = split_object_items(&wide_list(counters), &wide_list(instances));
+        ObjectItems {
+            path_prefix: String::new(),
+            object: "Process".to_owned(),
+            counters: to_strings(counters),
+            instances: to_strings(instances),
+        }
+    }
+
+    fn normalized(name: &str) -> Option<String> {
+        let wide = name.encode_utf16().collect::<Vec<u16>>();
+        normalize_machine_name(&wide).map(|n| String::from_utf16_lossy(&n))
+    }
+
+    #[test]
+    fn empty_machine_name_is_the_local_machine() {
+        for name in &["", "\\\\"] {
+            assert_eq!(check_machine_name(name), Ok(()));
+            assert_eq!(normalized(name), None);
+        }
+    }
+
+    #[test]
+    fn whitespace_machine_name_is_rejected() {
+        for name in &["   ", "\t", "\\\\  "] {
+            assert_eq!(check_machine_name(name), Err(PdhError::InvalidMachineName));
+        }
+    }
+
+    #[test]
+    fn machine_name_gets_one_leading_double_backslash() {
+        for name in &["host", "\\\\host", "host.example.com"] {
+            assert_eq!(check_machine_name(name), Ok(()));
+            assert_eq!(
+                normalized(name),
+                Some(format!("\\\\{}\0", name.trim_start_matches('\\')))
+            );
+        }
+    }
+
+    #[test]
+    fn wide_secrets_are_built_without_reallocating() {
+        for secret in &["", "hunter2", "pässwörd", "🔑 key"] {
+            let wide = WideSecret::new(secret);
+            assert_eq!(wide.0, str_to_utf16(secret));
+            assert_eq!(wide.0.capacity(), secret.len() + 1);
+        }
+    }
+
+    #[test]
+    fn path_prefix_has_one_double_backslash() {
+        let names = &[
+            ("HOST", r"\\HOST"),
+            (r"\\HOST", r"\\HOST"),
+            ("HOST\0", r"\\HOST"),
+            (
+                "sqlcluster.corp.example.com",
+                r"\\sqlcluster.corp.example.com",
+            ),
+            (
+                r"\\sqlcluster.corp.example.com",
+                r"\\sqlcluster.corp.example.com",
+            ),
+        ];
+        for (name, prefix) in names {
+            let wide = name.encode_utf16().collect::<Vec<u16>>();
+            assert_eq!(path_prefix(&normalize_machine_name(&wide)), *prefix);
         }
+        assert_eq!(path_prefix(&normalize_machine_name(&[])), "");
+    }
+
+    /// A sample closure whose counter reports the statuses in order, the
+    /// last one repeating, and that counts how many times it was called.
+    fn sampler<'a>(
+        statuses: &'a [Result<(), PdhError>],
+        calls: &'a Cell<usize>,
+    ) -> impl FnMut() -> Result<bool, PdhError> + 'a {
+        move || {
+            let status = statuses[calls.get().min(statuses.len() - 1)];
+            calls.set(calls.get() + 1);
+            Ok(match status {
+                Err(s) => is_warmup_error(s),
+                Ok(_) => false,
+            })
+        }
+    }
+
+    #[test]
+    fn priming_stops_at_the_first_valid_sample() {
+        let calls = Cell::new(0);
+        let statuses = [
+            Err(PdhError::InvalidData),
+            Err(PdhError::CalcNegativeDenominator),
+            Ok(()),
+        ];
+        let taken = sample_until_warm(
+            MAX_PRIME_SAMPLES,
+            Duration::from_millis(0),
+            sampler(&statuses, &calls),
+        )
+        .unwrap();
+        assert_eq!(taken, 3);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn priming_gives_up_after_the_cap() {
+        let calls = Cell::new(0);
+        let statuses = [Err(PdhError::InvalidCounterData)];
+        let taken = sample_until_warm(
+            MAX_PRIME_SAMPLES,
+            Duration::from_millis(0),
+            sampler(&statuses, &calls),
+        )
+        .unwrap();
+        assert_eq!(taken, MAX_PRIME_SAMPLES);
+        assert_eq!(calls.get(), MAX_PRIME_SAMPLES);
+    }
+
+    #[test]
+    fn priming_stops_on_a_collection_error() {
+        let calls = Cell::new(0);
+        let result = sample_until_warm(MAX_PRIME_SAMPLES, Duration::from_millis(0), || {
+            calls.set(calls.get() + 1);
+            Err(PdhError::InvalidHandle)
+        });
+        assert_eq!(result, Err(PdhError::InvalidHandle));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn duplicate_counters_are_dropped_and_repeated_instances_numbered() {
+        let items = object_items(
+            &["ID Process", "Handle Count", "ID Process"],
+            &["svchost", "svchost", "init"],
+        );
+        assert_eq!(items.counters, vec!["ID Process", "Handle Count"]);
+        let paths = items.paths().collect::<Vec<String>>();
+        let unique = paths.iter().collect::<HashSet<&String>>();
+        assert_eq!(unique.len(), paths.len());
+        assert_eq!(
+            paths,
+            vec![
+                r"\Process(svchost)\ID Process",
+                r"\Process(svchost)\Handle Count",
+                r"\Process(svchost#1)\ID Process",
+                r"\Process(svchost#1)\Handle Count",
+                r"\Process(init)\ID Process",
+                r"\Process(init)\Handle Count",
+            ]
+        );
+    }
+
+    #[test]
+    fn paths_of_objects_without_instances_have_none() {
+        let mut items = object_items(&["Available Bytes", "Cache Bytes"], &[""]);
+        items.object = "Memory".to_owned();
+        assert_eq!(
+            items.paths().collect::<Vec<String>>(),
+            vec![r"\Memory\Available Bytes", r"\Memory\Cache Bytes"]
+        );
+    }
+
+    #[test]
+    fn paths_on_a_remote_machine_have_its_prefix() {
+        let mut items = object_items(&["ID Process"], &["init"]);
+        items.path_prefix = r"\\HOST".to_owned();
+        assert_eq!(
+            items.paths().collect::<Vec<String>>(),
+            vec![r"\\HOST\Process(init)\ID Process"]
+        );
+        // An object without counters has no paths however many instances.
+        let mut items = object_items(&["ID Process"], &["init", "svchost"]);
+        items.counters.clear();
+        assert_eq!(items.paths().count(), 0);
+    }
+
+    #[test]
+    fn completion_positions() {
+        assert_eq!(
+            completion_position(r"\Mem"),
+            Some(("", CompletionPosition::Object { partial: "Mem" }))
+        );
+        assert_eq!(
+            completion_position(r"\\HOST\Process(chr"),
+            Some((
+                r"\\HOST",
+                CompletionPosition::Instance {
+                    object: "Process",
+                    partial: "chr"
+                }
+            ))
+        );
+        assert_eq!(
+            completion_position(r"\Memory\Av"),
+            Some((
+                "",
+                CompletionPosition::Counter {
+                    object: "Memory",
+                    object_and_instance: "Memory",
+                    partial: "Av"
+                }
+            ))
+        );
+        assert_eq!(completion_position(r"\\HOST"), None);
+    }
+
+    #[test]
+    fn completion_of_a_complete_instance_lists_its_counters() {
+        let counters = CompletionPosition::Counter {
+            object: "Process",
+            object_and_instance: "Process(chrome)",
+            partial: "",
+        };
+        assert_eq!(
+            completion_position(r"\Process(chrome)"),
+            Some(("", counters))
+        );
+        let counters = CompletionPosition::Counter {
+            object: "Process",
+            object_and_instance: "Process(chrome)",
+            partial: "",
+        };
+        assert_eq!(
+            completion_position(r"\Process(chrome)\"),
+            Some(("", counters))
+        );
     }
 }
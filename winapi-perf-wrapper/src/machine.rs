@@ -0,0 +1,399 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Higher level typed helpers for reading common information about a machine.
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use crate::constants::*;
+use crate::counter_path::number_instances;
+use crate::{ArrayItem, PDH, RATE_SAMPLE_INTERVAL};
+
+/// Temperature of each thermal zone in tenths of a degree Kelvin.
+pub const THERMAL_ZONE_HIGH_PRECISION_TEMPERATURE: &'static str =
+    "\\Thermal Zone Information(*)\\High Precision Temperature";
+/// Temperature of each thermal zone in degrees Kelvin.
+pub const THERMAL_ZONE_TEMPERATURE: &'static str = "\\Thermal Zone Information(*)\\Temperature";
+/// Remaining capacity of each battery in mWh.
+pub const BATTERY_REMAINING_CAPACITY: &'static str = "\\Battery Status(*)\\Remaining Capacity";
+/// Full charge capacity of each battery in mWh.
+pub const BATTERY_FULL_CHARGED_CAPACITY: &'static str =
+    "\\Battery Status(*)\\Full Charged Capacity";
+
+/// Utilization of each GPU engine by each process as a percentage.
+pub const GPU_ENGINE_UTILIZATION: &'static str = "\\GPU Engine(*)\\Utilization Percentage";
+/// Dedicated GPU memory used by each process on each adapter in bytes.
+pub const GPU_PROCESS_DEDICATED_USAGE: &'static str = "\\GPU Process Memory(*)\\Dedicated Usage";
+/// The id of each process.
+pub const PROCESS_ID: &'static str = "\\Process(*)\\ID Process";
+/// The processor time of each process as a percentage of one processor.
+pub const PROCESS_PROCESSOR_TIME: &'static str = "\\Process(*)\\% Processor Time";
+/// The working set of each process in bytes.
+pub const PROCESS_WORKING_SET: &'static str = "\\Process(*)\\Working Set";
+/// The private memory committed by each process in bytes.
+pub const PROCESS_PRIVATE_BYTES: &'static str = "\\Process(*)\\Private Bytes";
+/// The time all processors spent idle as a percentage of the sample interval.
+pub const PROCESSOR_IDLE_TIME: &'static str = "\\Processor Information(_Total)\\% Idle Time";
+/// The time since the machine last started.
+pub const SYSTEM_UP_TIME: &'static str = "\\System\\System Up Time";
+/// The object whose instances are the machine's physical disks.
+pub const PHYSICAL_DISK_OBJECT: &'static str = "PhysicalDisk";
+/// The object with an instance per logical processor named `node,processor`
+/// plus a `node,_Total` for each NUMA node and an overall `_Total`.
+pub const PROCESSOR_INFORMATION_OBJECT: &'static str = "Processor Information";
+
+/// Every counter path above. Most depend on the hardware present so they
+/// may not exist on every machine.
+pub const COUNTER_PATHS: &'static [&'static str] = &[
+    THERMAL_ZONE_HIGH_PRECISION_TEMPERATURE,
+    THERMAL_ZONE_TEMPERATURE,
+    BATTERY_REMAINING_CAPACITY,
+    BATTERY_FULL_CHARGED_CAPACITY,
+    GPU_ENGINE_UTILIZATION,
+    GPU_PROCESS_DEDICATED_USAGE,
+    PROCESS_ID,
+    PROCESS_PROCESSOR_TIME,
+    PROCESS_WORKING_SET,
+    PROCESS_PRIVATE_BYTES,
+    PROCESSOR_IDLE_TIME,
+    SYSTEM_UP_TIME,
+];
+
+const KELVIN_OFFSET: f64 = 273.15;
+
+/// The number of 100ns FILETIME units in a second.
+const HUNDRED_NS_PER_SECOND: f64 = 10_000_000.0;
+
+/// Strips the `pid_1234_` prefix from a GPU counter instance leaving the
+/// adapter and engine it refers to, e.g.
+/// `luid_0x00000000_0x0000C3A2_phys_0_eng_0_engtype_3D`.
+fn gpu_instance_without_pid(instance: &str) -> &str {
+    if instance.starts_with("pid_") {
+        if let Some(luid) = instance.find("_luid_") {
+            return &instance[luid + 1..];
+        }
+    }
+    instance
+}
+
+/// Sums the values of GPU counter instances across processes.
+fn sum_gpu_instances(items: Vec<ArrayItem>) -> Vec<(String, f64)> {
+    let mut totals = BTreeMap::new();
+    for item in items {
+        *totals
+            .entry(gpu_instance_without_pid(&item.instance).to_owned())
+            .or_insert(0.0) += item.value;
+    }
+    totals.into_iter().collect()
+}
+
+/// The Process instance name for a process name. Process instances are
+/// named for the executable without its `.exe`.
+fn process_instance_name(name: &str) -> &str {
+    let ext_start = name.len().saturating_sub(4);
+    match name.get(ext_start..) {
+        Some(ext) if ext.eq_ignore_ascii_case(".exe") => &name[0..ext_start],
+        _ => name,
+    }
+}
+
+/// The CPU and memory use of a single process.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessStats {
+    /// The Process instance, numbered `name#1`, `name#2` and so on when
+    /// several processes share a name.
+    pub instance: String,
+    pub pid: u32,
+    /// The processor time used as a percentage of one processor. Busy
+    /// processes on machines with several processors can exceed 100.
+    pub cpu_pct: f64,
+    pub working_set_bytes: u64,
+    pub private_bytes: u64,
+}
+
+/// The logical volumes named in a PhysicalDisk instance. PhysicalDisk
+/// instances are the disk number followed by the drive letters or volume
+/// names on that disk, e.g. `0 C: D:`. The `_Total` instance has none.
+pub fn physical_disk_volumes(instance: &str) -> Vec<String> {
+    if instance == "_Total" {
+        return Vec::new();
+    }
+    instance
+        .split_whitespace()
+        .skip(1)
+        .map(|v| v.to_owned())
+        .collect()
+}
+
+/// A local or remote machine.
+pub struct Machine {
+    pdh: PDH,
+}
+
+/// Whether a Processor Information instance is a logical processor rather
+/// than one of the totals.
+fn is_logical_processor(instance: &str) -> bool {
+    let processor = match instance.rfind(',') {
+        Some(idx) => &instance[idx + 1..],
+        None => instance,
+    };
+    !processor.is_empty() && processor != "_Total"
+}
+
+impl Machine {
+    /// The local machine.
+    pub fn local() -> Self {
+        Self { pdh: PDH::new() }
+    }
+
+    /// A remote machine by hostname, cluster name or FQDN.
+    pub fn remote<S: Into<String>>(machine_name: S) -> Self {
+        Self {
+            pdh: PDH::new().with_machine_name_str(machine_name),
+        }
+    }
+
+    /// The underlying PDH for anything the typed helpers don't cover.
+    pub fn pdh(&mut self) -> &mut PDH {
+        &mut self.pdh
+    }
+
+    /// Reads the current values of a wildcard path on this machine. Returns
+    /// None if the object or counter doesn't exist here.
+    fn read_array(&self, path: &str) -> Result<Option<Vec<ArrayItem>>, PdhError> {
+        self.read_array_sampled(path, None)
+    }
+
+    /// Reads the values of a wildcard path like read_array. Rate counters
+    /// need two collections so with a sample interval we collect, wait that
+    /// long and collect again.
+    fn read_array_sampled(
+        &self,
+        path: &str,
+        sample_interval: Option<Duration>,
+    ) -> Result<Option<Vec<ArrayItem>>, PdhError> {
+        let query = self.pdh.open_query()?;
+        let path = format!("{}{}", self.pdh.path_prefix(), path);
+        let counter = match query.try_add_counter(&path)? {
+            Some(counter) => counter,
+            None => return Ok(None),
+        };
+        match sample_interval {
+            Some(interval) => query.collect_sampled(interval)?,
+            None => query.collect()?,
+        }
+        match query.format_double_array(&counter) {
+            Ok(items) => Ok(Some(items.into_iter().filter(|i| i.is_valid()).collect())),
+            // An object with no instances right now.
+            Err(PdhError::NoData) | Err(PdhError::NoInstance) => Ok(Some(Vec::new())),
+            Err(s) => Err(s),
+        }
+    }
+
+    /// The temperature of each thermal zone in degrees Celsius. Returns an
+    /// empty list if the machine doesn't report thermal zones.
+    pub fn thermal_zones(&self) -> Result<Vec<(String, f64)>, PdhError> {
+        // Prefer the more precise counter where this version of windows has it.
+        if let Some(items) = self.read_array(THERMAL_ZONE_HIGH_PRECISION_TEMPERATURE)? {
+            return Ok(items
+                .into_iter()
+                .map(|i| (i.instance, i.value / 10.0 - KELVIN_OFFSET))
+                .collect());
+        }
+        if let Some(items) = self.read_array(THERMAL_ZONE_TEMPERATURE)? {
+            return Ok(items
+                .into_iter()
+                .map(|i| (i.instance, i.value - KELVIN_OFFSET))
+                .collect());
+        }
+        Ok(Vec::new())
+    }
+
+    /// The combined charge of all batteries as a percentage of their full
+    /// capacity. Returns None if the machine has no batteries.
+    pub fn battery_percent(&self) -> Result<Option<f64>, PdhError> {
+        let remaining = match self.read_array(BATTERY_REMAINING_CAPACITY)? {
+            Some(items) => items,
+            None => return Ok(None),
+        };
+        let full = match self.read_array(BATTERY_FULL_CHARGED_CAPACITY)? {
+            Some(items) => items,
+            None => return Ok(None),
+        };
+        let full: f64 = full.iter().map(|i| i.value).sum();
+        if full <= 0.0 {
+            return Ok(None);
+        }
+        let remaining: f64 = remaining.iter().map(|i| i.value).sum();
+        Ok(Some(remaining / full * 100.0))
+    }
+
+    /// The utilization of each GPU engine as a percentage summed across the
+    /// processes using it. Engines are named for their adapter and engine,
+    /// e.g. `luid_0x00000000_0x0000C3A2_phys_0_eng_0_engtype_3D`. This is a
+    /// rate counter so it takes a second to sample. Returns an empty list
+    /// if the machine doesn't report GPU counters.
+    pub fn gpu_utilization(&self) -> Result<Vec<(String, f64)>, PdhError> {
+        match self.read_array_sampled(GPU_ENGINE_UTILIZATION, Some(RATE_SAMPLE_INTERVAL))? {
+            Some(items) => Ok(sum_gpu_instances(items)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The dedicated memory in use on each GPU adapter in bytes summed across
+    /// processes. Adapters are named like `luid_0x00000000_0x0000C3A2_phys_0`.
+    /// Returns an empty list if the machine doesn't report GPU counters.
+    pub fn gpu_memory_bytes(&self) -> Result<Vec<(String, f64)>, PdhError> {
+        match self.read_array(GPU_PROCESS_DEDICATED_USAGE)? {
+            Some(items) => Ok(sum_gpu_instances(items)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The utilization of all processors as a percentage computed as 100
+    /// less the idle time. `% Processor Time` is itself derived from the
+    /// idle thread's run time but on processors that change frequency or
+    /// park cores it can disagree with the idle time over the same interval.
+    /// `% Idle Time` is the figure the monitoring guides recommend so prefer
+    /// this when comparing machines or alerting on saturation. This is a
+    /// rate counter so it takes a second to sample.
+    pub fn cpu_utilization_from_idle(&self) -> Result<f64, PdhError> {
+        let query = self.pdh.open_query()?;
+        let path = format!("{}{}", self.pdh.path_prefix(), PROCESSOR_IDLE_TIME);
+        let counter = query.add_counter_string(&path)?;
+        query.collect_sampled(RATE_SAMPLE_INTERVAL)?;
+        let idle = query.format_double_data(&counter)?;
+        // Idle time can read slightly over 100 from rounding in the counter.
+        Ok((100.0 - idle).max(0.0).min(100.0))
+    }
+
+    /// The time since the machine last started. Up time is an elapsed time
+    /// counter which PDH formats in seconds. A provider that reports it as
+    /// a plain count is assumed to count the 100ns units of a FILETIME.
+    pub fn uptime(&self) -> Result<Duration, PdhError> {
+        let query = self.pdh.open_query()?;
+        let path = format!("{}{}", self.pdh.path_prefix(), SYSTEM_UP_TIME);
+        let counter = query.add_counter_string(&path)?;
+        query.collect()?;
+        let info = query.counter_info(&counter)?;
+        let value = query.format_double_data(&counter)?;
+        let seconds = if info.counter_type.is_elapsed() {
+            value
+        } else {
+            value / HUNDRED_NS_PER_SECOND
+        };
+        Ok(Duration::from_secs_f64(seconds.max(0.0)))
+    }
+
+    /// The CPU and memory use of every process with the given name, with or
+    /// without its `.exe`. Processor time is a rate counter so this takes a
+    /// second to sample. Returns an empty list if no process has the name.
+    pub fn process_stats(&self, name: &str) -> Result<Vec<ProcessStats>, PdhError> {
+        let name = process_instance_name(name);
+        let query = self.pdh.open_query()?;
+        let prefix = self.pdh.path_prefix();
+        let mut counters = Vec::new();
+        for path in &[
+            PROCESS_ID,
+            PROCESS_PROCESSOR_TIME,
+            PROCESS_WORKING_SET,
+            PROCESS_PRIVATE_BYTES,
+        ] {
+            counters.push(query.add_counter_string(format!("{}{}", prefix, path))?);
+        }
+        query.collect_sampled(RATE_SAMPLE_INTERVAL)?;
+        // The value of each counter by numbered instance for the processes
+        // with our name. Every counter's array lists the instances in the
+        // same order so the numbering lines up across them.
+        let mut values = Vec::with_capacity(counters.len());
+        for counter in counters.iter() {
+            let items = match query.format_double_array(counter) {
+                Ok(items) => items,
+                Err(PdhError::NoData) | Err(PdhError::NoInstance) => Vec::new(),
+                Err(s) => return Err(s),
+            };
+            let mut by_instance = HashMap::new();
+            for (item, instance) in items
+                .iter()
+                .zip(number_instances(items.iter().map(|i| i.instance.as_str())))
+            {
+                if item.is_valid() && item.instance.eq_ignore_ascii_case(name) {
+                    by_instance.insert(instance, item.value);
+                }
+            }
+            values.push(by_instance);
+        }
+        let mut stats = Vec::new();
+        for (instance, pid) in values[0].iter() {
+            // A process may have exited between reading the counters.
+            let (cpu_pct, working_set, private_bytes) = match (
+                values[1].get(instance),
+                values[2].get(instance),
+                values[3].get(instance),
+            ) {
+                (Some(c), Some(w), Some(p)) => (*c, *w, *p),
+                _ => continue,
+            };
+            stats.push(ProcessStats {
+                instance: instance.clone(),
+                pid: *pid as u32,
+                cpu_pct,
+                working_set_bytes: working_set as u64,
+                private_bytes: private_bytes as u64,
+            });
+        }
+        stats.sort_by(|a, b| a.instance.cmp(&b.instance));
+        Ok(stats)
+    }
+
+    /// The number of logical processors as PDH sees them across every NUMA
+    /// node. This is the count the per processor counters report on.
+    pub fn logical_processor_count(&self) -> Result<usize, PdhError> {
+        let (_, mut instances) = self
+            .pdh
+            .enumerate_items_string(PROCESSOR_INFORMATION_OBJECT)?;
+        instances.retain(|i| is_logical_processor(i));
+        instances.sort();
+        instances.dedup();
+        Ok(instances.len())
+    }
+
+    /// Maps each LogicalDisk instance to the PhysicalDisk instances it lives
+    /// on as `(logical, physical)` pairs. A volume spanning several disks
+    /// appears once for each of them. The pairs can be used to roll the
+    /// LogicalDisk counters up to the PhysicalDisk they are stored on.
+    pub fn logical_disk_mapping(&self) -> Result<Vec<(String, String)>, PdhError> {
+        let (_, instances) = self.pdh.enumerate_items_string(PHYSICAL_DISK_OBJECT)?;
+        let mut mapping = Vec::new();
+        for physical in instances {
+            for logical in physical_disk_volumes(&physical) {
+                mapping.push((logical, physical.clone()));
+            }
+        }
+        mapping.sort();
+        mapping.dedup();
+        Ok(mapping)
+    }
+
+    /// The PhysicalDisk instances a LogicalDisk instance such as `C:` lives
+    /// on. Returns an empty list if the volume isn't on any physical disk
+    /// PDH reports.
+    pub fn physical_disks_for(&self, logical: &str) -> Result<Vec<String>, PdhError> {
+        Ok(self
+            .logical_disk_mapping()?
+            .into_iter()
+            .filter(|(l, _)| l.eq_ignore_ascii_case(logical))
+            .map(|(_, p)| p)
+            .collect())
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Splitting and building the null separated utf16 lists PDH returns. Each
+//! string in the list is null terminated and the list ends with an extra
+//! null, e.g. `a\0bc\0\0`.
+
+/// Splits a null separated list into its strings. PDH buffers may be larger
+/// than the list they hold so everything after the double null terminator is
+/// ignored. A buffer missing its terminator is read to the end. An empty list
+/// comes back as a single empty string.
+pub fn null_separated_to_vec(buf: &[u16]) -> Vec<Vec<u16>> {
+    let list = match buf.windows(2).position(|w| w == [0, 0]) {
+        Some(end) => &buf[0..end],
+        None => buf.strip_suffix(&[0]).unwrap_or(buf),
+    };
+    let mut v = Vec::new();
+    for item in list.split(|el| *el == 0) {
+        v.push(item.to_owned());
+    }
+    return v;
+}
+
+/// Builds a null separated list from strings. The strings must not contain
+/// nulls and only non-empty strings survive a round trip through
+/// null_separated_to_vec since an empty string reads as the terminator.
+pub fn vec_to_null_separated<S: AsRef<[u16]>>(items: &[S]) -> Vec<u16> {
+    let mut buf = Vec::new();
+    for item in items {
+        buf.extend_from_slice(item.as_ref());
+        buf.push(0);
+    }
+    buf.push(0);
+    if items.is_empty() {
+        buf.push(0);
+    }
+    return buf;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Non-empty strings without nulls including characters outside the
+    /// basic multilingual plane that encode as surrogate pairs.
+    fn item() -> impl Strategy<Value = String> {
+        "[^\u{0}]{1,12}"
+    }
+
+    fn to_utf16(items: &[String]) -> Vec<Vec<u16>> {
+        items.iter().map(|s| s.encode_utf16().collect()).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips(items in prop::collection::vec(item(), 1..8)) {
+            let wide = to_utf16(&items);
+            prop_assert_eq!(null_separated_to_vec(&vec_to_null_separated(&wide)), wide);
+        }
+
+        #[test]
+        fn keeps_surrogate_pairs(items in prop::collection::vec("[\u{10000}-\u{10FFFF}a]{1,6}", 1..6)) {
+            let split = null_separated_to_vec(&vec_to_null_separated(&to_utf16(&items)));
+            let decoded = split
+                .iter()
+                .map(|w| String::from_utf16(w).unwrap())
+                .collect::<Vec<String>>();
+            prop_assert_eq!(decoded, items);
+        }
+
+        #[test]
+        fn reads_to_the_end_without_terminators(
+            items in prop::collection::vec(item(), 1..8),
+            terminators in 0usize..2,
+        ) {
+            let wide = to_utf16(&items);
+            let mut buf = vec_to_null_separated(&wide);
+            // Drop both nulls then put back the single terminator if asked.
+            buf.truncate(buf.len() - 2);
+            buf.resize(buf.len() + terminators, 0);
+            prop_assert_eq!(null_separated_to_vec(&buf), wide);
+        }
+
+        #[test]
+        fn ignores_everything_after_the_terminator(
+            items in prop::collection::vec(item(), 1..8),
+            trailing in prop::collection::vec(any::<u16>(), 0..16),
+        ) {
+            let wide = to_utf16(&items);
+            let mut buf = vec_to_null_separated(&wide);
+            buf.extend(trailing);
+            prop_assert_eq!(null_separated_to_vec(&buf), wide);
+        }
+
+        #[test]
+        fn splits_buffers_of_any_length_into_null_free_items(
+            buf in prop::collection::vec(prop_oneof![Just(0u16), any::<u16>()], 0..33),
+        ) {
+            let split = null_separated_to_vec(&buf);
+            prop_assert!(!split.is_empty());
+            prop_assert!(split.iter().all(|item| !item.contains(&0)));
+            prop_assert!(split.iter().map(|item| item.len()).sum::<usize>() <= buf.len());
+        }
+    }
+
+    #[test]
+    fn empty_list_is_a_single_empty_string() {
+        let empty: &[Vec<u16>] = &[];
+        assert_eq!(
+            null_separated_to_vec(&vec_to_null_separated(empty)),
+            vec![vec![]]
+        );
+        assert_eq!(null_separated_to_vec(&[]), vec![Vec::<u16>::new()]);
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! A query that owns its counters.
+//!
+//! A PdhCounter must be removed before the query it was added to is closed.
+//! Keeping a query and its counters in separate fields of a struct means
+//! relying on field order for that. OwnedQuery keeps both together, refers
+//! to the counters by index and removes them before closing the query when
+//! it is dropped so it can be stored anywhere a PdhQuery can.
+use crate::{ArrayItem, PdhCounter, PdhError, PdhQuery, PDH};
+
+/// Owns a query and every counter added through it.
+pub struct OwnedQuery {
+    counters: Vec<PdhCounter>,
+    query: PdhQuery,
+}
+
+impl OwnedQuery {
+    /// Takes ownership of an open query. Counters already added to the query
+    /// are not owned by the OwnedQuery.
+    pub fn new(query: PdhQuery) -> Self {
+        Self {
+            counters: Vec::new(),
+            query,
+        }
+    }
+
+    /// Adds a counter for the path and returns its index.
+    pub fn add_counter(&mut self, path: &str) -> Result<usize, PdhError> {
+        let counter = self.query.add_counter_string(path)?;
+        self.counters.push(counter);
+        Ok(self.counters.len() - 1)
+    }
+
+    /// Adds a counter for the path using the english object and counter
+    /// names and returns its index.
+    pub fn add_english_counter(&mut self, path: &str) -> Result<usize, PdhError> {
+        let counter = self.query.add_english_counter(path)?;
+        self.counters.push(counter);
+        Ok(self.counters.len() - 1)
+    }
+
+    /// Removes the counter at index from the query. The counters after it
+    /// move down an index.
+    pub fn remove_counter(&mut self, index: usize) {
+        self.counters.remove(index);
+    }
+
+    /// Removes every counter while leaving the query open for new ones.
+    pub fn clear(&mut self) {
+        self.counters.clear();
+    }
+
+    /// The counter at index if there is one.
+    pub fn counter(&self, index: usize) -> Option<&PdhCounter> {
+        self.counters.get(index)
+    }
+
+    /// The counters in the order they were added.
+    pub fn counters(&self) -> &[PdhCounter] {
+        &self.counters
+    }
+
+    /// The number of counters.
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    /// Whether there are no counters.
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    /// The underlying query for anything not wrapped here.
+    pub fn query(&self) -> &PdhQuery {
+        &self.query
+    }
+
+    /// Collects the current raw values for every counter.
+    pub fn collect(&self) -> Result<(), PdhError> {
+        self.query.collect()
+    }
+
+    /// Collects once and formats every counter as an f64 into values in
+    /// index order. See `PdhQuery::collect_all_double_into`.
+    pub fn collect_all_double_into(
+        &self,
+        values: &mut Vec<Result<f64, PdhError>>,
+    ) -> Result<(), PdhError> {
+        self.query.collect()?;
+        values.clear();
+        for counter in self.counters.iter() {
+            values.push(self.query.format_double_data(counter));
+        }
+        Ok(())
+    }
+
+    /// Formats the most recently collected value of the counter at index as
+    /// an f64.
+    pub fn format_double_data(&self, index: usize) -> Result<f64, PdhError> {
+        self.query.format_double_data(self.get(index)?)
+    }
+
+    /// Formats the most recently collected value of the counter at index as
+    /// an i64.
+    pub fn format_large_data(&self, index: usize) -> Result<i64, PdhError> {
+        self.query.format_large_data(self.get(index)?)
+    }
+
+    /// Formats the most recently collected values of the wildcard counter at
+    /// index into values. See `PdhQuery::format_double_array_into`.
+    pub fn format_double_array_into(
+        &self,
+        index: usize,
+        buffer: &mut Vec<u64>,
+        values: &mut Vec<ArrayItem>,
+    ) -> Result<(), PdhError> {
+        self.query
+            .format_double_array_into(self.get(index)?, buffer, values)
+    }
+
+    fn get(&self, index: usize) -> Result<&PdhCounter, PdhError> {
+        self.counters.get(index).ok_or(PdhError::InvalidHandle)
+    }
+}
+
+impl From<PdhQuery> for OwnedQuery {
+    fn from(query: PdhQuery) -> Self {
+        Self::new(query)
+    }
+}
+
+impl Drop for OwnedQuery {
+    fn drop(&mut self) {
+        // The counters have to be removed while the query is still open.
+        self.counters.clear();
+    }
+}
+
+impl PDH {
+    /// Opens a query that owns the counters added to it.
+    pub fn open_owned_query(&self) -> Result<OwnedQuery, PdhError> {
+        Ok(OwnedQuery::new(self.open_query()?))
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Sampling counters on a fixed schedule timed with the high resolution
+//! performance counter for profiling short lived workloads.
+//!
+//! Sampling faster than a counter is updated doesn't make it more precise.
+//! Most kernel counters such as `% Processor Time` are only updated on the
+//! system timer tick so samples taken closer together than that repeat the
+//! same value or compute rates from a change of zero or one tick. Rates from
+//! such short intervals swing between 0 and 100% rather than converging on
+//! the true value.
+use std::time::Duration;
+
+use winapi::shared::ntdef::LARGE_INTEGER;
+use winapi::um::profileapi::{QueryPerformanceCounter, QueryPerformanceFrequency};
+
+use crate::constants::*;
+use crate::{PdhCounter, PdhQuery};
+
+/// The default system timer tick. Rate counters sampled more often than
+/// this are mostly noise.
+pub const MIN_MEANINGFUL_INTERVAL: Duration = Duration::from_micros(15_625);
+
+/// How long before a sample is due we stop sleeping and spin. Sleeps are
+/// only accurate to the timer tick.
+const SPIN_THRESHOLD: Duration = Duration::from_millis(2);
+
+fn performance_counter() -> i64 {
+    let mut ticks: LARGE_INTEGER = unsafe { std::mem::zeroed() };
+    unsafe {
+        QueryPerformanceCounter(&mut ticks);
+        *ticks.QuadPart()
+    }
+}
+
+fn performance_frequency() -> i64 {
+    let mut frequency: LARGE_INTEGER = unsafe { std::mem::zeroed() };
+    unsafe {
+        QueryPerformanceFrequency(&mut frequency);
+        *frequency.QuadPart()
+    }
+}
+
+/// One collection of the sampled counters.
+#[derive(Debug, Clone)]
+pub struct PreciseSample {
+    /// When the sample was scheduled relative to the start of sampling.
+    pub requested: Duration,
+    /// When the collection actually started relative to the start of
+    /// sampling.
+    pub actual: Duration,
+    /// The value of each counter in the order they were given.
+    pub values: Vec<Result<f64, PdhError>>,
+}
+
+impl PreciseSample {
+    /// How late the sample was taken. Samples are never taken early.
+    pub fn jitter(&self) -> Duration {
+        self.actual - self.requested
+    }
+}
+
+/// Collects a set of counters at exact multiples of an interval from when
+/// sampling started. The schedule is kept with `QueryPerformanceCounter`
+/// and the time each collection actually happened is recorded with its
+/// values so the jitter can be accounted for. A sample that is late doesn't
+/// push back the ones after it.
+///
+/// ```ignore
+/// let mut sampler = PreciseSampler::new(&query, vec![&counter], Duration::from_millis(20))?;
+/// for sample in sampler.samples(500)? {
+///     println!("{:?} {:?} {:?}", sample.actual, sample.jitter(), sample.values);
+/// }
+/// ```
+pub struct PreciseSampler<'a> {
+    query: &'a PdhQuery,
+    counters: Vec<&'a PdhCounter>,
+    interval_ticks: i64,
+    frequency: i64,
+    /// The performance counter when sampling started.
+    start: i64,
+    /// The number of samples taken so far.
+    taken: i64,
+    values: Vec<Result<f64, PdhError>>,
+}
+
+impl<'a> PreciseSampler<'a> {
+    /// Constructs a sampler and takes the baseline collection rate counters
+    /// need. Warns if any of the counters is a rate and the interval is
+    /// shorter than `MIN_MEANINGFUL_INTERVAL`.
+    pub fn new(
+        query: &'a PdhQuery,
+        counters: Vec<&'a PdhCounter>,
+        interval: Duration,
+    ) -> Result<Self, PdhError> {
+        if interval < MIN_MEANINGFUL_INTERVAL {
+            for counter in counters.iter() {
+                if counter.counter_type().map_or(false, |t| t.is_rate()) {
+                    log::warn!(
+                        "Sampling rate counter {} every {:?} is below its {:?} resolution. Expect repeated or noisy values.",
+                        counter.path(),
+                        interval,
+                        MIN_MEANINGFUL_INTERVAL
+                    );
+                }
+            }
+        }
+        let frequency = performance_frequency();
+        let interval_ticks = (interval.as_nanos() * frequency as u128 / 1_000_000_000) as i64;
+        query.collect()?;
+        Ok(Self {
+            query: query,
+            counters: counters,
+            interval_ticks: std::cmp::max(1, interval_ticks),
+            frequency: frequency,
+            start: performance_counter(),
+            taken: 0,
+            values: Vec::new(),
+        })
+    }
+
+    fn ticks_to_duration(&self, ticks: i64) -> Duration {
+        Duration::from_nanos((ticks as u128 * 1_000_000_000 / self.frequency as u128) as u64)
+    }
+
+    /// Waits until the next sample is due and collects it.
+    pub fn sample(&mut self) -> Result<PreciseSample, PdhError> {
+        self.taken += 1;
+        let target = self.start + self.taken * self.interval_ticks;
+        loop {
+            let remaining = target - performance_counter();
+            if remaining <= 0 {
+                break;
+            }
+            let remaining = self.ticks_to_duration(remaining);
+            if remaining > SPIN_THRESHOLD {
+                std::thread::sleep(remaining - SPIN_THRESHOLD);
+            } else {
+                std::hint::spin_loop();
+            }
+        }
+        let actual = performance_counter();
+        self.query
+            .collect_all_double_into(&self.counters, &mut self.values)?;
+        Ok(PreciseSample {
+            requested: self.ticks_to_duration(target - self.start),
+            actual: self.ticks_to_duration(actual - self.start),
+            values: self.values.clone(),
+        })
+    }
+
+    /// Takes count samples on the schedule.
+    pub fn samples(&mut self, count: usize) -> Result<Vec<PreciseSample>, PdhError> {
+        let mut samples = Vec::with_capacity(count);
+        for _ in 0..count {
+            samples.push(self.sample()?);
+        }
+        Ok(samples)
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Rendering counters in the prometheus text exposition format without the
+//! exporter. Enabled with the `prometheus` feature.
+//!
+//! ```ignore
+//! let body = render_paths_as_prometheus(&[
+//!     ("cpu_total_pct", "\\Processor(_Total)\\% Processor Time"),
+//!     ("disk_read_bytes_sec", "\\PhysicalDisk(*)\\Disk Read Bytes/sec"),
+//! ])?;
+//! ```
+use std::fmt::Write;
+
+use crate::constants::*;
+use crate::{PDH, RATE_SAMPLE_INTERVAL};
+
+fn escape_help(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn escape_label_value(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Reads each of the (metric name, counter path) pairs and renders them as
+/// gauges in the prometheus text exposition format. Paths with a `*` get a
+/// series per instance with an `instance` label. Values that can't be read
+/// are left out.
+///
+/// The query is collected twice a second apart so that rate counters have
+/// the two samples they need, which means this takes at least a second.
+pub fn render_paths_as_prometheus(paths: &[(&str, &str)]) -> Result<String, PdhError> {
+    let query = PDH::new().open_query()?;
+    let mut counters = Vec::with_capacity(paths.len());
+    for (name, path) in paths {
+        counters.push((*name, *path, query.add_counter_string(*path)?));
+    }
+    query.collect_sampled(RATE_SAMPLE_INTERVAL)?;
+    let mut out = String::new();
+    for (name, path, counter) in counters.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, escape_help(path));
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        if path.contains('*') {
+            let items = match query.format_double_array(counter) {
+                Ok(items) => items,
+                Err(_) => continue,
+            };
+            for item in items.iter().filter(|i| i.is_valid()) {
+                let _ = writeln!(
+                    out,
+                    "{}{{instance=\"{}\"}} {}",
+                    name,
+                    escape_label_value(&item.instance),
+                    item.value
+                );
+            }
+        } else if let Ok(v) = query.format_double_data(counter) {
+            let _ = writeln!(out, "{} {}", name, v);
+        }
+    }
+    Ok(out)
+}
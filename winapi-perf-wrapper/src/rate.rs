@@ -0,0 +1,171 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Rates derived on the client from the change in a total counter.
+use std::cell::Cell;
+use std::time::Instant;
+
+use crate::constants::PdhError;
+use crate::counter_type::CounterType;
+use crate::ValueStream;
+
+/// The change from previous to current for a counter that is width_bits
+/// wide. If current is smaller and the counter is narrower than 64 bits we
+/// assume it wrapped once. Returns None if it went backwards any other way
+/// which means the counter was reset.
+pub fn wrapping_delta(previous: i64, current: i64, width_bits: u32) -> Option<i64> {
+    if current >= previous {
+        return Some(current - previous);
+    }
+    if width_bits >= 64 {
+        return None;
+    }
+    let wrapped = current + (1i64 << width_bits) - previous;
+    if wrapped < 0 {
+        return None;
+    }
+    Some(wrapped)
+}
+
+/// Computes the per second rate of a stream of total values such as a count
+/// of bytes sent. Each call yields the rate since the previous value or None
+/// for the first value and after the counter is reset.
+///
+/// Totals narrower than 64 bits wrap to zero when they overflow. We assume
+/// a counter that went backwards wrapped once and add `2^width` to the
+/// delta rather than report a huge negative rate. Set the width with
+/// `with_counter_type` from the counter's info or `with_counter_width` if
+/// the type isn't known. The default is 64 bits which never wraps.
+///
+/// ```ignore
+/// let info = query.counter_info(&counter)?;
+/// let rates = DerivedRateStream::new(query.get_value_stream_from_handle(counter))
+///     .with_counter_type(info.counter_type);
+/// ```
+pub struct DerivedRateStream<S> {
+    stream: S,
+    width_bits: u32,
+    /// The last value read and when we read it.
+    last: Cell<Option<(i64, Instant)>>,
+}
+
+impl<S> DerivedRateStream<S>
+where
+    S: ValueStream<i64>,
+{
+    /// Constructs a new DerivedRateStream over a stream of totals.
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: stream,
+            width_bits: 64,
+            last: Cell::new(None),
+        }
+    }
+
+    /// Sets the width of the counter in bits.
+    pub fn with_counter_width(mut self, width_bits: u32) -> Self {
+        self.width_bits = width_bits;
+        return self;
+    }
+
+    /// Sets the width of the counter from its type. Types without a fixed
+    /// width leave the width unchanged.
+    pub fn with_counter_type(mut self, counter_type: CounterType) -> Self {
+        if let Some(width_bits) = counter_type.width_bits() {
+            self.width_bits = width_bits;
+        }
+        return self;
+    }
+}
+
+impl<S> ValueStream<Option<f64>> for DerivedRateStream<S>
+where
+    S: ValueStream<i64>,
+{
+    fn next(&self) -> Result<Option<f64>, PdhError> {
+        let value = self.stream.next()?;
+        let now = Instant::now();
+        let (previous, then) = match self.last.replace(Some((value, now))) {
+            Some(last) => last,
+            None => return Ok(None),
+        };
+        let elapsed = now.duration_since(then).as_secs_f64();
+        if elapsed <= 0.0 {
+            return Ok(None);
+        }
+        Ok(wrapping_delta(previous, value, self.width_bits).map(|delta| delta as f64 / elapsed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counter_type::{PERF_COUNTER_BULK_COUNT, PERF_COUNTER_COUNTER};
+    use std::cell::RefCell;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    /// Yields the values in order and then NoMoreData.
+    struct Totals(RefCell<Vec<i64>>);
+
+    impl ValueStream<i64> for Totals {
+        fn next(&self) -> Result<i64, PdhError> {
+            let mut values = self.0.borrow_mut();
+            if values.is_empty() {
+                return Err(PdhError::NoMoreData);
+            }
+            Ok(values.remove(0))
+        }
+    }
+
+    #[test]
+    fn deltas_of_increasing_totals() {
+        assert_eq!(wrapping_delta(10, 25, 32), Some(15));
+        assert_eq!(wrapping_delta(10, 10, 64), Some(0));
+    }
+
+    #[test]
+    fn narrow_totals_wrap_once() {
+        let max = u32::MAX as i64;
+        assert_eq!(wrapping_delta(max - 5, 10, 32), Some(16));
+        assert_eq!(wrapping_delta(max, 0, 32), Some(1));
+        // A value wider than the counter can't have wrapped so it was reset.
+        assert_eq!(wrapping_delta(1 << 33, 5, 32), None);
+    }
+
+    #[test]
+    fn wide_totals_that_go_backwards_were_reset() {
+        assert_eq!(wrapping_delta(100, 50, 64), None);
+    }
+
+    #[test]
+    fn width_comes_from_the_counter_type() {
+        let stream = DerivedRateStream::new(Totals(RefCell::new(Vec::new())));
+        assert_eq!(stream.width_bits, 64);
+        let stream = stream.with_counter_type(CounterType(PERF_COUNTER_COUNTER));
+        assert_eq!(stream.width_bits, 32);
+        let stream = stream.with_counter_type(CounterType(PERF_COUNTER_BULK_COUNT));
+        assert_eq!(stream.width_bits, 64);
+    }
+
+    #[test]
+    fn rates_across_a_wrap() {
+        let totals = Totals(RefCell::new(vec![u32::MAX as i64 - 5, 10]));
+        let rates = DerivedRateStream::new(totals).with_counter_width(32);
+        assert_eq!(rates.next(), Ok(None));
+        sleep(Duration::from_millis(10));
+        let rate = rates.next().unwrap().unwrap();
+        assert!(rate > 0.0 && rate <= 16.0 / 0.01, "rate {}", rate);
+        assert_eq!(rates.next(), Err(PdhError::NoMoreData));
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Formatting of raw counter values captured outside of a live query.
+use std::ptr::null_mut;
+
+use winapi::shared::minwindef::FILETIME;
+use winapi::shared::winerror::ERROR_SUCCESS;
+use winapi::um::pdh::{
+    PDH_FMT_COUNTERVALUE_u, PdhFormatFromRawValue, PDH_FMT_COUNTERVALUE, PDH_RAW_COUNTER,
+};
+
+use crate::constants::*;
+
+/// The timebase of raw counter timestamps which are FILETIMEs in 100
+/// nanosecond intervals.
+const FILETIME_TIMEBASE: i64 = 10_000_000;
+
+/// A raw counter sample as PDH would record it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RawCounterValue {
+    /// The status of the sample. One of the PDH_CSTATUS_* constants.
+    pub status: u32,
+    /// When the sample was taken as a FILETIME in 100ns intervals.
+    pub timestamp: u64,
+    /// The first raw value. For rate counters this is the numerator.
+    pub first_value: i64,
+    /// The second raw value. For rate counters this is usually the
+    /// denominator or the time base.
+    pub second_value: i64,
+    /// The number of items that were summed for multi counters.
+    pub multi_count: u32,
+}
+
+impl RawCounterValue {
+    pub(crate) fn from_pdh(raw: &PDH_RAW_COUNTER) -> Self {
+        Self {
+            status: raw.CStatus,
+            timestamp: ((raw.TimeStamp.dwHighDateTime as u64) << 32)
+                | raw.TimeStamp.dwLowDateTime as u64,
+            first_value: raw.FirstValue,
+            second_value: raw.SecondValue,
+            multi_count: raw.MultiCount,
+        }
+    }
+
+    fn to_pdh(&self) -> PDH_RAW_COUNTER {
+        PDH_RAW_COUNTER {
+            CStatus: self.status,
+            TimeStamp: FILETIME {
+                dwLowDateTime: self.timestamp as u32,
+                dwHighDateTime: (self.timestamp >> 32) as u32,
+            },
+            FirstValue: self.first_value,
+            SecondValue: self.second_value,
+            MultiCount: self.multi_count,
+        }
+    }
+}
+
+/// A formatted counter value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CounterValue {
+    Long(i32),
+    Large(i64),
+    Double(f64),
+}
+
+/// Computes a formatted counter value from raw samples using PDH's own
+/// formula for the counter type. This needs no live query so raw samples
+/// captured elsewhere can be reprocessed. Rate counters need both raw1 the
+/// newer sample and raw2 the older one. The format must contain one of
+/// `PDH_FMT_LONG`, `PDH_FMT_LARGE` or `PDH_FMT_DOUBLE` and determines the
+/// returned CounterValue variant.
+pub fn calculate_from_raw(
+    counter_type: u32,
+    format: u32,
+    raw1: &RawCounterValue,
+    raw2: Option<&RawCounterValue>,
+) -> Result<CounterValue, PdhError> {
+    let mut raw1 = raw1.to_pdh();
+    let mut raw2 = raw2.map(|r| r.to_pdh());
+    let mut time_base = FILETIME_TIMEBASE;
+    let mut fmt_counter_value = unsafe {
+        PDH_FMT_COUNTERVALUE {
+            CStatus: 0,
+            u: std::mem::zeroed::<PDH_FMT_COUNTERVALUE_u>(),
+        }
+    };
+    let status = unsafe {
+        PdhFormatFromRawValue(
+            counter_type,
+            format,
+            &mut time_base,
+            &mut raw1,
+            raw2.as_mut()
+                .map_or(null_mut(), |r| r as *mut PDH_RAW_COUNTER),
+            &mut fmt_counter_value,
+        )
+    } as u32;
+    if status != ERROR_SUCCESS {
+        return Err(status.into());
+    }
+    if fmt_counter_value.CStatus != PDH_CSTATUS_VALID_DATA
+        && fmt_counter_value.CStatus != PDH_CSTATUS_NEW_DATA
+    {
+        return Err(fmt_counter_value.CStatus.into());
+    }
+    let value = unsafe {
+        if format & PDH_FMT_DOUBLE != 0 {
+            CounterValue::Double(*fmt_counter_value.u.doubleValue())
+        } else if format & PDH_FMT_LARGE != 0 {
+            CounterValue::Large(*fmt_counter_value.u.largeValue())
+        } else if format & PDH_FMT_LONG != 0 {
+            CounterValue::Long(*fmt_counter_value.u.longValue())
+        } else {
+            return Err(PdhError::InvalidArgument);
+        }
+    };
+    Ok(value)
+}
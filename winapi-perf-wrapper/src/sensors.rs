@@ -0,0 +1,113 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Interactive selection of data sources and counters using the standard PDH
+//! dialogs.
+use std::path::PathBuf;
+use std::ptr::null_mut;
+
+pub use crate::data_source::DataSource;
+
+use winapi::shared::minwindef::DWORD;
+use winapi::shared::winerror::ERROR_SUCCESS;
+use winapi::um::pdh::{PdhBrowseCountersW, PdhSelectDataSourceW, PDH_BROWSE_DLG_CONFIG_W};
+
+use crate::constants::*;
+use crate::counter_path::{parse_counter_path_pdh, CounterPathElements};
+
+/// How many counter paths the browse dialog can return at once.
+const BROWSE_MAX_COUNTERS: u32 = 64;
+
+/// A counter chosen in the browse dialog.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectedCounter {
+    /// The path as the dialog returned it.
+    pub path: String,
+    /// The path broken into its elements by `PdhParseCounterPathW`.
+    pub elements: CounterPathElements,
+}
+
+/// Shows the standard dialog for browsing the counters and choosing some.
+/// The dialog starts out showing the counters at detail_level, one of the
+/// `PERF_DETAIL_*` constants, and the user can change it. The dialog closes
+/// on the first Add. With multi_select every counter selected in the list
+/// when Add is clicked is returned, otherwise only a single one. Returns an
+/// empty list if the user cancelled the dialog.
+pub fn browse_perf_counters(
+    detail_level: u32,
+    multi_select: bool,
+) -> Result<Vec<SelectedCounter>, PdhError> {
+    let buffer_length = if multi_select {
+        PDH_MAX_COUNTER_PATH * BROWSE_MAX_COUNTERS
+    } else {
+        PDH_MAX_COUNTER_PATH
+    };
+    let mut buffer = crate::zeroed_buffer(buffer_length as usize + 1);
+    let mut config: PDH_BROWSE_DLG_CONFIG_W = unsafe { std::mem::zeroed() };
+    config.set_IncludeInstanceIndex(1);
+    config.set_SingleCounterPerAdd(if multi_select { 0 } else { 1 });
+    // Without a callback to collect each Add the buffer only ever holds the
+    // last one so the dialog has to close after it.
+    config.set_SingleCounterPerDialog(1);
+    config.szReturnPathBuffer = buffer.as_mut_ptr();
+    config.cchReturnPathLength = buffer_length;
+    config.dwDefaultDetailLevel = detail_level;
+    let status = unsafe { PdhBrowseCountersW(&mut config) } as u32;
+    if status == PDH_DIALOG_CANCELLED {
+        return Ok(Vec::new());
+    }
+    if status != ERROR_SUCCESS {
+        return Err(status.into());
+    }
+    let mut selected = Vec::new();
+    for path in crate::null_separated_to_vec(&buffer)
+        .into_iter()
+        .filter(|p| !p.is_empty())
+    {
+        let path = String::from_utf16_lossy(&path);
+        selected.push(SelectedCounter {
+            elements: parse_counter_path_pdh(&path)?,
+            path: path,
+        });
+    }
+    Ok(selected)
+}
+
+/// Shows the standard dialog for choosing where to read counters from. Pass
+/// `PDH_FLAGS_FILE_BROWSER_ONLY` to go straight to the file browser instead
+/// of offering current activity as a choice. Returns `DataSource::Live` for
+/// current activity or `DataSource::LogFiles` otherwise. Returns None if the
+/// user cancelled the dialog.
+pub fn select_data_source(flags: u32) -> Result<Option<DataSource>, PdhError> {
+    // The dialog may return several null separated log files.
+    let mut buffer_length: DWORD = PDH_MAX_DATASOURCE_PATH * 8;
+    let mut buffer = crate::zeroed_buffer(buffer_length as usize + 1);
+    let status =
+        unsafe { PdhSelectDataSourceW(null_mut(), flags, buffer.as_mut_ptr(), &mut buffer_length) }
+            as u32;
+    if status == PDH_DIALOG_CANCELLED {
+        return Ok(None);
+    }
+    if status != ERROR_SUCCESS {
+        return Err(status.into());
+    }
+    let logs = crate::null_separated_to_vec(&buffer)
+        .into_iter()
+        .filter(|p| !p.is_empty())
+        .map(|p| PathBuf::from(String::from_utf16_lossy(&p)))
+        .collect::<Vec<PathBuf>>();
+    if logs.is_empty() {
+        return Ok(Some(DataSource::Live));
+    }
+    Ok(Some(DataSource::LogFiles(logs)))
+}
@@ -0,0 +1,119 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Edge triggered notifications for a value stream crossing a threshold.
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::constants::PdhError;
+use crate::ValueStream;
+
+/// A change in whether a watched value satisfies its predicate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThresholdEvent {
+    /// The predicate became true and stayed true for the debounce period.
+    /// Holds the value that triggered the event.
+    Crossed(f64),
+    /// The predicate became false again after a Crossed event.
+    Cleared(f64),
+}
+
+/// Watches a stream of values and only yields when a predicate first becomes
+/// true and when it becomes false again. With a debounce the predicate must
+/// hold for at least that long before it counts as crossed.
+///
+/// ```ignore
+/// let watcher = ThresholdWatcher::new(stream, |v| v > 90.0)
+///     .with_debounce(Duration::from_secs(30));
+/// loop {
+///     match watcher.next() {
+///         Ok(ThresholdEvent::Crossed(v)) => println!("CPU is at {}", v),
+///         Ok(ThresholdEvent::Cleared(_)) => println!("CPU recovered"),
+///         Err(_) => continue,
+///     }
+/// }
+/// ```
+pub struct ThresholdWatcher<S, P> {
+    stream: S,
+    predicate: P,
+    debounce: Option<Duration>,
+    /// When the predicate started holding if it currently does.
+    since: Cell<Option<Instant>>,
+    /// Whether we've yielded a Crossed event without a Cleared event yet.
+    crossed: Cell<bool>,
+}
+
+impl<S, P> ThresholdWatcher<S, P>
+where
+    S: ValueStream<f64>,
+    P: Fn(f64) -> bool,
+{
+    /// Constructs a new ThresholdWatcher over a stream of values.
+    pub fn new(stream: S, predicate: P) -> Self {
+        Self {
+            stream: stream,
+            predicate: predicate,
+            debounce: None,
+            since: Cell::new(None),
+            crossed: Cell::new(false),
+        }
+    }
+
+    /// How long the predicate must hold before we yield a Crossed event.
+    pub fn with_debounce<D: Into<Duration>>(mut self, debounce: D) -> Self {
+        self.debounce = Some(debounce.into());
+        return self;
+    }
+
+    /// Whether the predicate is currently considered crossed.
+    pub fn is_crossed(&self) -> bool {
+        self.crossed.get()
+    }
+}
+
+impl<S, P> ValueStream<ThresholdEvent> for ThresholdWatcher<S, P>
+where
+    S: ValueStream<f64>,
+    P: Fn(f64) -> bool,
+{
+    /// Reads values from the underlying stream until the next event. Errors
+    /// from the underlying stream are returned as they happen.
+    fn next(&self) -> Result<ThresholdEvent, PdhError> {
+        loop {
+            let value = self.stream.next()?;
+            if !(self.predicate)(value) {
+                self.since.set(None);
+                if self.crossed.replace(false) {
+                    return Ok(ThresholdEvent::Cleared(value));
+                }
+                continue;
+            }
+            if self.crossed.get() {
+                continue;
+            }
+            let since = match self.since.get() {
+                Some(since) => since,
+                None => {
+                    let now = Instant::now();
+                    self.since.set(Some(now));
+                    now
+                }
+            };
+            if since.elapsed() < self.debounce.unwrap_or_default() {
+                continue;
+            }
+            self.crossed.set(true);
+            return Ok(ThresholdEvent::Crossed(value));
+        }
+    }
+}
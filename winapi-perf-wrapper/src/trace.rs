@@ -0,0 +1,140 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Timing of the PDH calls that are usually the bottleneck when collection
+//! is slow, e.g. against a remote machine.
+//!
+//! Only built with the `trace` feature. Without it the calls aren't timed
+//! at all. The timings are kept in process wide histograms that every
+//! query records into and can be read with `pdh_metrics`.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The upper bounds in seconds of the histogram buckets.
+pub const BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// The PDH calls we time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PdhCall {
+    /// `PdhCollectQueryData`
+    CollectQueryData,
+    /// `PdhAddCounterW`
+    AddCounter,
+}
+
+struct CallTimes {
+    buckets: [AtomicU64; 10],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl CallTimes {
+    const fn new() -> Self {
+        Self {
+            buckets: [
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+            ],
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|b| seconds <= *b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> CallHistogram {
+        let mut cumulative = 0;
+        let mut counts = Vec::with_capacity(BUCKETS.len());
+        for bucket in self.buckets.iter() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            counts.push(cumulative);
+        }
+        CallHistogram {
+            counts: counts,
+            count: self.count.load(Ordering::Relaxed),
+            sum_seconds: self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9,
+        }
+    }
+}
+
+static COLLECT_QUERY_DATA: CallTimes = CallTimes::new();
+static ADD_COUNTER: CallTimes = CallTimes::new();
+
+/// The durations of one PDH call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallHistogram {
+    /// The cumulative number of calls that took at most each of BUCKETS.
+    pub counts: Vec<u64>,
+    /// The total number of calls.
+    pub count: u64,
+    /// The total time spent in the calls.
+    pub sum_seconds: f64,
+}
+
+/// The durations of the PDH calls made so far by this process.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PdhMetrics {
+    pub collect_query_data: CallHistogram,
+    pub add_counter: CallHistogram,
+}
+
+impl PdhMetrics {
+    /// The histograms with the calls they are for.
+    pub fn calls(&self) -> Vec<(PdhCall, &CallHistogram)> {
+        vec![
+            (PdhCall::CollectQueryData, &self.collect_query_data),
+            (PdhCall::AddCounter, &self.add_counter),
+        ]
+    }
+}
+
+impl PdhCall {
+    /// The name of the PDH function.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PdhCall::CollectQueryData => "PdhCollectQueryData",
+            PdhCall::AddCounter => "PdhAddCounterW",
+        }
+    }
+}
+
+pub(crate) fn record(call: PdhCall, elapsed: Duration) {
+    match call {
+        PdhCall::CollectQueryData => COLLECT_QUERY_DATA.record(elapsed),
+        PdhCall::AddCounter => ADD_COUNTER.record(elapsed),
+    }
+}
+
+/// Reads the durations of the PDH calls made so far.
+pub fn pdh_metrics() -> PdhMetrics {
+    PdhMetrics {
+        collect_query_data: COLLECT_QUERY_DATA.snapshot(),
+        add_counter: ADD_COUNTER.snapshot(),
+    }
+}
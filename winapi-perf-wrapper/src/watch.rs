@@ -0,0 +1,70 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Fan out the latest value of a counter to any number of async consumers.
+use std::thread;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::constants::*;
+use crate::PDH;
+
+/// Samples the counter at path every interval and sends each value until
+/// every receiver has been dropped.
+fn watch_loop(
+    path: String,
+    interval: Duration,
+    sender: &watch::Sender<Result<f64, PdhError>>,
+) -> Result<(), PdhError> {
+    let query = PDH::new().open_query()?;
+    let counter = query.add_counter_string(path)?;
+    query.prime(&[&counter], 1, interval)?;
+    loop {
+        // Rates are computed over the time since the last collection so we
+        // wait before each one, including the first after priming.
+        thread::sleep(interval);
+        let value = query
+            .collect()
+            .and_then(|_| query.format_double_data(&counter));
+        if sender.send(value).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+impl PDH {
+    /// Watches a counter from a single background thread and returns a
+    /// receiver for its latest value. Clone the receiver to share the
+    /// counter with more consumers instead of adding it to another query.
+    /// New receivers see the last value straight away. Until the first
+    /// sample is taken the value is `Err(PdhError::NoData)`.
+    ///
+    /// The thread reads live data from this PDH's machine and stops once
+    /// every receiver has been dropped. If the counter can't be added or
+    /// primed the error is sent and the thread stops.
+    pub fn watch_counter<S: Into<String>>(
+        &self,
+        path: S,
+        interval: Duration,
+    ) -> watch::Receiver<Result<f64, PdhError>> {
+        let path = format!("{}{}", self.path_prefix(), path.into());
+        let (sender, receiver) = watch::channel(Err(PdhError::NoData));
+        thread::spawn(move || {
+            if let Err(s) = watch_loop(path, interval, &sender) {
+                let _ = sender.send(Err(s));
+            }
+        });
+        receiver
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! These tests remove counters from live queries on the local machine.
+#![cfg(windows)]
+use winapi_perf_wrapper::PDH;
+
+const MEM_AVAILABLE_BYTES: &'static str = r"\Memory\Available Bytes";
+const PROCESSES: &'static str = r"\System\Processes";
+
+#[test]
+fn cleared_counters_error_and_the_query_stays_usable() {
+    let mut query = PDH::new().open_query().unwrap();
+    let memory = query.add_counter_string(MEM_AVAILABLE_BYTES).unwrap();
+    let processes = query.add_counter_string(PROCESSES).unwrap();
+    query.collect().unwrap();
+    assert!(query.format_double_data(&memory).is_ok());
+
+    query.clear_counters().unwrap();
+    assert!(query.format_double_data(&memory).is_err());
+    assert!(query.format_double_data(&processes).is_err());
+    // Clearing twice has nothing left to remove.
+    query.clear_counters().unwrap();
+    // Dropping a cleared counter doesn't remove anything twice.
+    drop(processes);
+
+    let memory_again = query.add_counter_string(MEM_AVAILABLE_BYTES).unwrap();
+    query.collect().unwrap();
+    assert!(query.format_double_data(&memory_again).unwrap() > 0.0);
+    assert!(query.format_double_data(&memory).is_err());
+}
+
+#[test]
+fn counters_can_be_dropped_after_their_query() {
+    let counter = {
+        let query = PDH::new().open_query().unwrap();
+        query.add_counter_string(PROCESSES).unwrap()
+    };
+    // The query removed the counter when it closed so this is a no-op.
+    drop(counter);
+}
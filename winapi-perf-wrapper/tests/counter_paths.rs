@@ -0,0 +1,41 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! These tests check our counter path parser against `PdhParseCounterPathW`.
+#![cfg(windows)]
+use winapi_perf_wrapper::counter_path::{parse_counter_path, parse_counter_path_pdh_as_written};
+
+const PATHS: &'static [&'static str] = &[
+    r"\Processor(_Total)\% Processor Time",
+    r"\Process(foo (x86))\% Processor Time",
+    r"\Process(a(b(c))#2)\ID Process",
+    r"\Thread(svc(1)/3)\Context Switches/sec",
+];
+
+#[test]
+fn parenthesized_instances_parse_like_pdh() {
+    for path in PATHS {
+        let pdh_elements = parse_counter_path_pdh_as_written(path).unwrap();
+        assert_eq!(parse_counter_path(path), Some(pdh_elements), "{}", path);
+    }
+}
+
+#[test]
+fn pdh_parse_keeps_only_written_machine_names() {
+    let elements =
+        parse_counter_path_pdh_as_written(r"\\localhost\Process(a(b))\ID Process").unwrap();
+    assert_eq!(elements.machine_name, Some("localhost".to_owned()));
+    assert_eq!(elements.instance_name, Some("a(b)".to_owned()));
+    let elements = parse_counter_path_pdh_as_written(r"\Process(a(b))\ID Process").unwrap();
+    assert_eq!(elements.machine_name, None);
+}
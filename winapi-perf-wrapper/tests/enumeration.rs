@@ -0,0 +1,81 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! These tests enumerate the objects and counters of the local machine.
+#![cfg(windows)]
+use winapi_perf_wrapper::constants::is_access_denied;
+use winapi_perf_wrapper::{PdhError, PDH};
+
+/// Returns whether an object lists instances but has none right now. These
+/// objects list a single empty instance and reject a path without one.
+fn has_no_instances(pdh: &PDH, object: &str) -> bool {
+    let (counters, instances) = match pdh.enumerate_items_string(object) {
+        Ok(items) => items,
+        Err(_) => return false,
+    };
+    if !instances.iter().all(|i| i.is_empty()) {
+        return false;
+    }
+    let counter = match counters.iter().find(|c| !c.is_empty()) {
+        Some(c) => c,
+        None => return false,
+    };
+    let query = pdh.open_query().unwrap();
+    let path = format!("\\{}\\{}", object, counter);
+    return query.add_counter_string(&path).is_err();
+}
+
+#[test]
+fn active_objects_are_a_subset_of_all_objects() {
+    let mut pdh = PDH::new();
+    let all = pdh.enumerate_objects_string().unwrap();
+    let active = pdh.enumerate_active_objects().unwrap();
+    assert!(!active.is_empty());
+    assert!(active.len() <= all.len());
+    for obj in active.iter() {
+        assert!(all.contains(obj), "{} is active but not registered", obj);
+    }
+    // Every machine has these and they always have data.
+    assert!(active.iter().any(|o| o == "Processor"));
+    assert!(active.iter().any(|o| o == "Memory"));
+}
+
+#[test]
+fn active_objects_exclude_empty_objects() {
+    let mut pdh = PDH::new();
+    let all = pdh.enumerate_objects_string().unwrap();
+    let active = pdh.enumerate_active_objects().unwrap();
+    let empty: Vec<&String> = all.iter().filter(|o| has_no_instances(&pdh, o)).collect();
+    for obj in empty {
+        assert!(
+            !active.contains(obj),
+            "{} has no instances but is active",
+            obj
+        );
+    }
+}
+
+#[test]
+fn enumeration_only_skips_missing_or_denied_objects() {
+    let mut pdh = PDH::new();
+    let (paths, skipped) = pdh.enumerate_counters_verbose().unwrap();
+    assert!(!paths.is_empty());
+    for (obj, reason) in skipped {
+        assert!(
+            reason == PdhError::NoObject || is_access_denied(reason),
+            "{} was skipped for {}",
+            obj,
+            reason
+        );
+    }
+}
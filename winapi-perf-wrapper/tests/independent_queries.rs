@@ -0,0 +1,65 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! These tests run against the live performance counters of the local machine.
+#![cfg(windows)]
+use std::thread::sleep;
+use std::time::Duration;
+
+use winapi_perf_wrapper::PDH;
+
+const SYSTEM_CALLS_SEC: &'static str = r"\System\System Calls/sec";
+
+#[test]
+fn same_counter_in_two_queries_collects_independently() {
+    let pdh = PDH::new();
+    let fast_query = pdh.open_query().unwrap();
+    let slow_query = pdh.open_query().unwrap();
+    let fast_counter = fast_query.add_counter_string(SYSTEM_CALLS_SEC).unwrap();
+    let slow_counter = slow_query.add_counter_string(SYSTEM_CALLS_SEC).unwrap();
+
+    // Prime both queries so the rate counters have a previous sample.
+    fast_query.collect().unwrap();
+    slow_query.collect().unwrap();
+
+    let mut fast_values = Vec::new();
+    for _ in 0..5 {
+        sleep(Duration::from_secs(1));
+        fast_query.collect().unwrap();
+        fast_values.push(fast_query.format_double_data(&fast_counter).unwrap());
+    }
+    // The slow query has only been collected once so its rate spans the full
+    // five seconds while the fast query's spans just the last second.
+    slow_query.collect().unwrap();
+    let slow_value = slow_query.format_double_data(&slow_counter).unwrap();
+
+    assert_eq!(fast_values.len(), 5);
+    for v in fast_values.iter() {
+        assert!(*v >= 0.0, "fast rate should not be negative: {}", v);
+    }
+    assert!(
+        slow_value > 0.0,
+        "slow rate should be positive: {}",
+        slow_value
+    );
+    // The five second rate is an average of the same system calls the one
+    // second samples saw so it should fall within the range they cover with
+    // some generous slack for the sampling skew between the two queries.
+    let max_fast = fast_values.iter().cloned().fold(0.0, f64::max);
+    assert!(
+        slow_value <= max_fast * 2.0,
+        "slow rate {} is out of range of fast rates {:?}",
+        slow_value,
+        fast_values
+    );
+}
@@ -0,0 +1,196 @@
+// Copyright 2020 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! These tests read counters from a small perfmon CSV log rather than the
+//! live machine.
+#![cfg(windows)]
+use std::fs;
+use std::path::PathBuf;
+
+use winapi_perf_wrapper::{DataSource, LogDataSource, LogReplay, PdhError, ValueStream, PDH};
+
+const HEADER: &'static str = concat!(
+    r#""(PDH-CSV 4.0) (Coordinated Universal Time)(0)","#,
+    r#""\\LOGHOST\Memory\Available Bytes","#,
+    r#""\\LOGHOST\Process(svchost)\Handle Count","#,
+    r#""\\LOGHOST\Process(init)\Handle Count""#
+);
+
+/// Three samples a second apart. The Available Bytes value is missing from
+/// the middle one.
+const SAMPLES: &'static [&'static str] = &[
+    r#""10/16/2026 12:00:00.000","1000","40","10""#,
+    r#""10/16/2026 12:00:01.000"," ","41","11""#,
+    r#""10/16/2026 12:00:02.000","3000","42","12""#,
+];
+
+/// Writes the log to a file of its own in the temp directory.
+fn write_log(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "winapi-perf-wrapper-{}-{}.csv",
+        name,
+        std::process::id()
+    ));
+    let mut contents = String::from(HEADER);
+    for sample in SAMPLES {
+        contents.push_str("\r\n");
+        contents.push_str(sample);
+    }
+    contents.push_str("\r\n");
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn log_counters_are_enumerated_with_their_machine() {
+    let path = write_log("enumerate");
+    let mut paths = PDH::new().enumerate_log_counters(&path).unwrap();
+    paths.sort();
+    assert_eq!(
+        paths,
+        vec![
+            r"\\LOGHOST\Memory\Available Bytes",
+            r"\\LOGHOST\Process(init)\Handle Count",
+            r"\\LOGHOST\Process(svchost)\Handle Count",
+        ]
+    );
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+fn log_machine_names_with_or_without_backslashes() {
+    let path = write_log("machine-names");
+    let log = LogDataSource::open(&path).unwrap();
+    assert_eq!(log.enumerate_machines().unwrap(), vec![r"\\LOGHOST"]);
+    for machine in &["LOGHOST", r"\\LOGHOST"] {
+        let mut objects = log.enumerate_objects(machine).unwrap();
+        objects.sort();
+        assert_eq!(objects, vec!["Memory", "Process"]);
+        let (counters, mut instances) = log.enumerate_items(machine, "Process").unwrap();
+        instances.sort();
+        assert_eq!(counters, vec!["Handle Count"]);
+        assert_eq!(instances, vec!["init", "svchost"]);
+    }
+    drop(log);
+    let _ = fs::remove_file(path);
+}
+
+/// Opens a PDH that reads from the log at path.
+fn log_pdh(path: &PathBuf) -> PDH {
+    PDH::new()
+        .with_data_source(DataSource::CsvFile(path.clone()))
+        .unwrap()
+}
+
+#[test]
+fn paused_streams_repeat_the_last_sample() {
+    let path = write_log("pause");
+    let pdh = log_pdh(&path);
+    let query = pdh.open_query().unwrap();
+    let stream = query
+        .get_value_stream_from_path::<_, f64>(r"\\LOGHOST\Process(svchost)\Handle Count")
+        .unwrap()
+        // Priming would read samples of its own.
+        .with_skip_count(0);
+    // Nothing has been collected yet so there's nothing to repeat.
+    stream.pause();
+    assert_eq!(stream.next(), Err(PdhError::NoData));
+    stream.resume();
+    assert_eq!(stream.next(), Ok(40.0));
+    stream.pause();
+    assert!(stream.is_paused());
+    assert_eq!(stream.next(), Ok(40.0));
+    assert_eq!(stream.next(), Ok(40.0));
+    // Resuming picks up with the next sample in the log.
+    stream.resume();
+    assert_eq!(stream.next(), Ok(41.0));
+    drop(stream);
+    drop(query);
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+fn reprimed_streams_skip_the_sample_collected_on_resume() {
+    let path = write_log("reprime");
+    let pdh = log_pdh(&path);
+    let query = pdh.open_query().unwrap();
+    let stream = query
+        .get_value_stream_from_path::<_, f64>(r"\\LOGHOST\Process(init)\Handle Count")
+        .unwrap()
+        .with_skip_count(0);
+    assert_eq!(stream.next(), Ok(10.0));
+    stream.pause();
+    stream.resume_and_reprime().unwrap();
+    assert!(!stream.is_paused());
+    assert_eq!(stream.next(), Ok(12.0));
+    drop(stream);
+    drop(query);
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+fn log_queries_read_each_sample_in_turn() {
+    let path = write_log("query");
+    let query = {
+        let pdh = PDH::new().with_log_data_source(LogDataSource::open(&path).unwrap());
+        pdh.open_query_h().unwrap()
+    };
+    // The query keeps the log open after the PDH is gone.
+    let counter = query
+        .add_counter_string(r"\\LOGHOST\Process(svchost)\Handle Count")
+        .unwrap();
+    // Only counters in the log can be added.
+    assert!(query
+        .add_counter_string(r"\\LOGHOST\System\Processes")
+        .is_err());
+    let mut values = Vec::new();
+    loop {
+        match query.collect() {
+            Ok(()) => values.push(query.format_double_data(&counter).unwrap()),
+            Err(PdhError::NoMoreData) => break,
+            Err(s) => panic!("collecting the log failed: {}", s),
+        }
+    }
+    assert_eq!(values, vec![40.0, 41.0, 42.0]);
+    drop(counter);
+    drop(query);
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+fn replays_fill_gaps_only_when_asked() {
+    let path = write_log("replay");
+    let paths = &[
+        r"\\LOGHOST\Memory\Available Bytes",
+        r"\\LOGHOST\Process(init)\Handle Count",
+    ];
+    let pdh = log_pdh(&path);
+    let series = LogReplay::new(&pdh, paths).unwrap().read_all().unwrap();
+    assert_eq!(
+        series,
+        vec![
+            vec![Some(1000.0), None, Some(3000.0)],
+            vec![Some(10.0), Some(11.0), Some(12.0)],
+        ]
+    );
+    drop(pdh);
+    let pdh = log_pdh(&path);
+    let series = LogReplay::new(&pdh, paths)
+        .unwrap()
+        .with_interpolation(true)
+        .read_all()
+        .unwrap();
+    assert_eq!(series[0], vec![Some(1000.0), Some(2000.0), Some(3000.0)]);
+    drop(pdh);
+    let _ = fs::remove_file(path);
+}